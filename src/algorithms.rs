@@ -8,6 +8,78 @@ pub mod iterative_deepening;
 pub mod iterative_deepening_table;
 pub mod iterative_deepening_order;
 
+use crate::error::ChessError;
+use crate::search::{SearchContext, SearchResult};
+use hashtable::HashTable;
+
+// the search algorithms, so that frontends can choose one by name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+  Random,
+  Negamax,
+  NegamaxAB,
+  NegamaxABTable,
+  NegamaxABQuiescent,
+  IterativeDeepening,
+  IterativeDeepeningTable,
+  IterativeDeepeningOrder,
+}
+impl Algorithm {
+  pub const ALL: [Algorithm; 8] = [
+    Algorithm::Random,
+    Algorithm::Negamax,
+    Algorithm::NegamaxAB,
+    Algorithm::NegamaxABTable,
+    Algorithm::NegamaxABQuiescent,
+    Algorithm::IterativeDeepening,
+    Algorithm::IterativeDeepeningTable,
+    Algorithm::IterativeDeepeningOrder,
+  ];
+
+  // same names as the functions exported to JS
+  pub fn name(&self) -> &'static str {
+    match self {
+      Algorithm::Random => "random",
+      Algorithm::Negamax => "negamax",
+      Algorithm::NegamaxAB => "negamax_a_b",
+      Algorithm::NegamaxABTable => "negamax_a_b_table",
+      Algorithm::NegamaxABQuiescent => "negamax_a_b_quiescent",
+      Algorithm::IterativeDeepening => "iterative_deepening",
+      Algorithm::IterativeDeepeningTable => "iterative_deepening_table",
+      Algorithm::IterativeDeepeningOrder => "iterative_deepening_order",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Algorithm> {
+    Algorithm::ALL.iter().copied().find(|algorithm| algorithm.name() == name)
+  }
+
+  // iterative deepening searches until max_time, the others search at a fixed depth
+  pub fn is_timed(&self) -> bool {
+    matches!(self, Algorithm::IterativeDeepening | Algorithm::IterativeDeepeningTable | Algorithm::IterativeDeepeningOrder)
+  }
+
+  // searches with the algorithm, depth is used by fixed depth searches and max_time by timed ones,
+  // table is only used by the algorithms that have one, the leaves are scored by the evaluator of ctx
+  pub fn search(&self, fen_str: &str, seed: u64, depth: u64, max_time: u64, table: &mut HashTable, ctx: &mut SearchContext) -> Result<SearchResult, ChessError> {
+    let result = match self {
+      Algorithm::Random => random::search(fen_str, seed, ctx)?,
+      Algorithm::Negamax => negamax::search(fen_str, seed, depth, ctx)?,
+      Algorithm::NegamaxAB => negamax_a_b::search(fen_str, seed, depth, ctx)?,
+      Algorithm::NegamaxABTable => negamax_a_b_table::search(fen_str, seed, depth, table, ctx)?,
+      Algorithm::NegamaxABQuiescent => negamax_a_b_quiescent::search(fen_str, seed, depth, ctx)?,
+      Algorithm::IterativeDeepening => return iterative_deepening::search(fen_str, seed, max_time, ctx),
+      Algorithm::IterativeDeepeningTable => return iterative_deepening_table::search(fen_str, seed, max_time, table, ctx),
+      Algorithm::IterativeDeepeningOrder => return iterative_deepening_order::search(fen_str, seed, max_time, ctx),
+    };
+    // fixed depth searches have a single iteration to report
+    if let Some(info) = ctx.info.as_mut() {
+      info(&result);
+    }
+    Ok(result)
+  }
+}
+
 /* pub fn test() {
     println!("Hello, world!");
 } */
\ No newline at end of file
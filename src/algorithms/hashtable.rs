@@ -1,66 +1,492 @@
-use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
+use shakmaty::{Move, CastlingMode, uci::Uci};
 
-use crate::zobrish;
+use crate::error::ChessError;
+use crate::search::MATE_BOUND;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// entries checked when looking for a position, the hash decides the bucket
+const BUCKET_SIZE: usize = 4;
+pub const DEFAULT_SIZE_MB: usize = 16;
+// larger sizes are clamped to this, it's also the maximum of the UCI Hash option
+pub const MAX_SIZE_MB: usize = 1024;
+// age is stored in 6 bits, so it wraps around after 64 searches
+const AGE_CYCLE: u8 = 64;
+
+// binary format: header, used entries and a checksum of everything before it, all little endian
+// header: magic, version (u16), age (u8), reserved (u8), buckets (u32), entry count (u32)
+// entry: index in the table (u32), check (u32), score (i32), move (u16), depth (u8), flags (u8)
+const MAGIC: &[u8; 4] = b"WCTT";
+pub const FORMAT_VERSION: u16 = 1;
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+const CHECKSUM_SIZE: usize = 4;
+
+// what the stored score says about the real score of the position
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bound {
+  // the score is exact, it was inside the alpha-beta window
+  Exact,
+  // fail-high, the real score is at least the stored one
+  Lower,
+  // fail-low, the real score is at most the stored one
+  Upper,
+}
+impl Bound {
+  // 0 is kept for empty entries
+  fn to_bits(self) -> u8 {
+    match self {
+      Bound::Exact => 1,
+      Bound::Lower => 2,
+      Bound::Upper => 3,
+    }
+  }
+  fn from_bits(bits: u8) -> Option<Bound> {
+    match bits {
+      1 => Some(Bound::Exact),
+      2 => Some(Bound::Lower),
+      3 => Some(Bound::Upper),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PositionInfo {
   pub depth: u64,
+  // score from the point of view of the side to move in the position
   pub score: i64,
-  pub mov: String,
+  pub bound: Bound,
+  // best move found in the position, packed with encode_move
+  pub mov: Option<u16>,
 }
 impl PositionInfo {
-  pub fn new(depth: u64, score: i64, mov: String) -> PositionInfo {
-    PositionInfo { depth, score, mov }
+  pub fn new(depth: u64, score: i64, bound: Bound, mov: Option<u16>) -> PositionInfo {
+    PositionInfo { depth, score, bound, mov }
+  }
+
+  // returns the stored score if it can be used by a search of the given depth and window
+  pub fn cutoff(&self, depth: u64, alpha: i64, beta: i64) -> Option<i64> {
+    if self.depth < depth {
+      return None;
+    }
+    match self.bound {
+      Bound::Exact => Some(self.score),
+      Bound::Lower if self.score >= beta => Some(self.score),
+      Bound::Upper if self.score <= alpha => Some(self.score),
+      _ => None,
+    }
+  }
+}
+
+// packs a move in 16 bits: from square, to square and promotion role, like UCI does
+// 0 is never a valid move, since from and to can't be the same square
+pub fn encode_move(mov: &Move) -> u16 {
+  match mov.to_uci(CastlingMode::Standard) {
+    Uci::Normal { from, to, promotion } => {
+      let promotion = promotion.map_or(0, |role| role as u16);
+      u16::from(from) | u16::from(to) << 6 | promotion << 12
+    },
+    // drops and null moves can't happen in standard chess
+    _ => 0,
+  }
+}
+
+// mate scores are stored as distance from the position instead of from the root,
+// so they stay correct when the position is reached again at another ply
+fn score_to_table(score: i64, ply: u64) -> i64 {
+  if score >= MATE_BOUND {
+    score + ply as i64
+  } else if score <= -MATE_BOUND {
+    score - ply as i64
+  } else {
+    score
+  }
+}
+
+fn score_from_table(score: i64, ply: u64) -> i64 {
+  if score >= MATE_BOUND {
+    score - ply as i64
+  } else if score <= -MATE_BOUND {
+    score + ply as i64
+  } else {
+    score
+  }
+}
+
+// one slot of the table, 12 bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Entry {
+  // upper 32 bits of the key, the lower ones are (mostly) implied by the bucket
+  check: u32,
+  score: i32,
+  mov: u16,
+  depth: u8,
+  // bound in the lower 2 bits (0 if the entry is empty), age of the search in the others
+  flags: u8,
+}
+impl Entry {
+  fn is_empty(&self) -> bool {
+    self.flags & 0b11 == 0
+  }
+  fn age(&self) -> u8 {
+    self.flags >> 2
   }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+// fixed size transposition table, entries are grouped in buckets and the one
+// to replace is chosen based on depth and on how old its search is
+#[derive(Debug, Clone)]
 pub struct HashTable {
-  pub table: HashMap<String, PositionInfo>,
+  entries: Vec<Entry>,
+  buckets: usize,
+  age: u8,
 }
 impl HashTable {
   pub fn new() -> HashTable {
+    HashTable::with_size_mb(DEFAULT_SIZE_MB)
+  }
+
+  // buckets that fit in size_mb megabytes (up to MAX_SIZE_MB), at least one
+  fn buckets_for(size_mb: usize) -> usize {
+    let bytes = size_mb.min(MAX_SIZE_MB).saturating_mul(1024 * 1024);
+    (bytes / (std::mem::size_of::<Entry>() * BUCKET_SIZE)).max(1)
+  }
+
+  // the table uses at most size_mb megabytes, larger sizes are clamped to MAX_SIZE_MB
+  pub fn with_size_mb(size_mb: usize) -> HashTable {
+    let buckets = HashTable::buckets_for(size_mb);
     HashTable {
-      table: HashMap::new(),
+      entries: vec![Entry::default(); buckets * BUCKET_SIZE],
+      buckets,
+      age: 0,
     }
   }
-  pub fn find(&mut self, fen_str: &str, depth: u64) -> Option<PositionInfo> {
-    let pos_info = self.table.get(&zobrish(fen_str).to_string());
-    if pos_info.is_some() {
-      let pos_info = pos_info.unwrap();
-      if pos_info.depth >= depth {
-        return Some(pos_info.clone());
-      }
+
+  // changing the size throws away the content of the table
+  pub fn resize(&mut self, size_mb: usize) {
+    *self = HashTable::with_size_mb(size_mb);
+  }
+
+  pub fn clear(&mut self) {
+    self.entries.fill(Entry::default());
+    self.age = 0;
+  }
+
+  // to be called when a new search starts, older entries get replaced first
+  pub fn new_search(&mut self) {
+    self.age = (self.age + 1) % AGE_CYCLE;
+  }
+
+  // rounded, since the entries don't fill the megabytes exactly
+  pub fn size_mb(&self) -> usize {
+    (self.entries.len() * std::mem::size_of::<Entry>() + 512 * 1024) / (1024 * 1024)
+  }
+
+  // how full the table is in per mille, counting only entries of the current search,
+  // like UCI hashfull it's estimated on the first 1000 entries
+  pub fn hashfull(&self) -> u64 {
+    let sample = &self.entries[..self.entries.len().min(1000)];
+    let used = sample.iter().filter(|entry| !entry.is_empty() && entry.age() == self.age).count();
+    (used * 1000 / sample.len()) as u64
+  }
+
+  fn bucket(&self, key: u64) -> std::ops::Range<usize> {
+    let start = (key % self.buckets as u64) as usize * BUCKET_SIZE;
+    start..start + BUCKET_SIZE
+  }
+
+  // key is the zobrist hash of the position, the entry is returned whatever its depth
+  // so that its best move can still be used for ordering,
+  // ply is the distance from the root, used to turn stored mate scores back into distances from the root
+  pub fn find(&self, key: u64, ply: u64) -> Option<PositionInfo> {
+    let check = (key >> 32) as u32;
+    let entry = self.entries[self.bucket(key)].iter().find(|entry| !entry.is_empty() && entry.check == check)?;
+    Some(PositionInfo {
+      depth: entry.depth as u64,
+      score: score_from_table(entry.score as i64, ply),
+      bound: Bound::from_bits(entry.flags & 0b11)?,
+      mov: if entry.mov == 0 { None } else { Some(entry.mov) },
+    })
+  }
+
+  pub fn insert(&mut self, key: u64, pos_info: PositionInfo, ply: u64) {
+    let check = (key >> 32) as u32;
+    let age = self.age;
+    let range = self.bucket(key);
+    let bucket = &mut self.entries[range];
+
+    let index = match bucket.iter().position(|entry| !entry.is_empty() && entry.check == check) {
+      Some(index) => {
+        // same position, a shallower result only replaces it if it's exact or the old one is stale
+        let old = bucket[index];
+        if old.age() == age && old.depth as u64 > pos_info.depth && pos_info.bound != Bound::Exact {
+          return;
+        }
+        index
+      },
+      // otherwise an empty slot, or the one with the least depth, where old searches count as shallower
+      None => (0..BUCKET_SIZE).min_by_key(|&index| {
+        let entry = bucket[index];
+        if entry.is_empty() {
+          i64::MIN
+        } else {
+          let age_distance = (AGE_CYCLE + age - entry.age()) % AGE_CYCLE;
+          entry.depth as i64 - 8 * age_distance as i64
+        }
+      }).unwrap(),
+    };
+
+    // keep the old best move if the new result doesn't have one
+    let mov = pos_info.mov.unwrap_or(if bucket[index].check == check { bucket[index].mov } else { 0 });
+    bucket[index] = Entry {
+      check,
+      score: score_to_table(pos_info.score, ply) as i32,
+      mov,
+      depth: pos_info.depth.min(u8::MAX as u64) as u8,
+      flags: pos_info.bound.to_bits() | age << 2,
+    };
+  }
+}
+
+impl HashTable {
+  // exports the table in the binary format, only the used entries are written
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let used: Vec<(usize, &Entry)> = self.entries.iter().enumerate().filter(|(_, entry)| !entry.is_empty()).collect();
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + used.len() * ENTRY_SIZE + CHECKSUM_SIZE);
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.push(self.age);
+    bytes.push(0);
+    bytes.extend_from_slice(&(self.buckets as u32).to_le_bytes());
+    bytes.extend_from_slice(&(used.len() as u32).to_le_bytes());
+    for (index, entry) in used {
+      bytes.extend_from_slice(&(index as u32).to_le_bytes());
+      bytes.extend_from_slice(&entry.check.to_le_bytes());
+      bytes.extend_from_slice(&entry.score.to_le_bytes());
+      bytes.extend_from_slice(&entry.mov.to_le_bytes());
+      bytes.push(entry.depth);
+      bytes.push(entry.flags);
+    }
+    let checksum = fnv1a(&bytes);
+    bytes.extend_from_slice(&checksum.to_le_bytes());
+    bytes
+  }
+
+  // imports a table exported by to_bytes, the table gets the size it had when exported
+  pub fn from_bytes(bytes: &[u8]) -> Result<HashTable, ChessError> {
+    let corrupt = |reason: &str| ChessError::CorruptTable(reason.to_string());
+    if bytes.len() < HEADER_SIZE + CHECKSUM_SIZE || &bytes[0..4] != MAGIC {
+      return Err(corrupt("not a transposition table"));
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != FORMAT_VERSION {
+      return Err(ChessError::CorruptTable(format!("unsupported version {}, expected {}", version, FORMAT_VERSION)));
     }
-    return None;
-  }
-  pub fn insert(&mut self, fen_str: &str, pos_info: PositionInfo) -> Option<PositionInfo> {
-    let previous = self.table.get(&zobrish(fen_str).to_string());
-    if previous.is_some() {
-      let previous = previous.unwrap();
-      if previous.depth > pos_info.depth {
-        // println!("Not inserting {:?}", pos_info);
-        return None;
+    let age = bytes[6];
+    let buckets = read_u32(bytes, 8) as usize;
+    let count = read_u32(bytes, 12) as usize;
+    // the sizes come from untrusted bytes, they must not overflow on 32 bit targets
+    let length = count.checked_mul(ENTRY_SIZE).and_then(|size| size.checked_add(HEADER_SIZE + CHECKSUM_SIZE));
+    if length != Some(bytes.len()) {
+      return Err(corrupt("wrong length"));
+    }
+    let (content, checksum) = bytes.split_at(bytes.len() - CHECKSUM_SIZE);
+    if fnv1a(content) != read_u32(checksum, 0) {
+      return Err(corrupt("checksum mismatch"));
+    }
+    if buckets == 0 || age >= AGE_CYCLE {
+      return Err(corrupt("invalid header"));
+    }
+    // a table larger than any we create would allocate gigabytes
+    let size = buckets.checked_mul(BUCKET_SIZE).filter(|_| buckets <= HashTable::buckets_for(MAX_SIZE_MB));
+    let size = size.ok_or_else(|| corrupt("table too large"))?;
+
+    let mut entries = vec![Entry::default(); size];
+    for chunk in content[HEADER_SIZE..].chunks_exact(ENTRY_SIZE) {
+      let entry = Entry {
+        check: read_u32(chunk, 4),
+        score: read_u32(chunk, 8) as i32,
+        mov: u16::from_le_bytes([chunk[12], chunk[13]]),
+        depth: chunk[14],
+        flags: chunk[15],
+      };
+      let slot = entries.get_mut(read_u32(chunk, 0) as usize).ok_or_else(|| corrupt("entry out of the table"))?;
+      if entry.is_empty() || entry.age() >= AGE_CYCLE {
+        return Err(corrupt("invalid entry"));
       }
+      *slot = entry;
     }
-    self.table.insert(zobrish(fen_str).to_string(), pos_info)
-    //self.table.insert(zobrish(fen_str).to_string(), pos_info)
+    Ok(HashTable { entries, buckets, age })
+  }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+  u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+// 32 bit FNV-1a, only used to detect damaged files
+fn fnv1a(bytes: &[u8]) -> u32 {
+  bytes.iter().fold(0x811c9dc5, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x01000193))
+}
+
+impl Default for HashTable {
+  fn default() -> Self {
+    Self::new()
   }
 }
 
 #[cfg(test)]
 mod test {
   use super::*;
-  use crate::utils::{zobrish};
+  use shakmaty::zobrist::ZobristHash;
+  use shakmaty::Position;
+  use crate::utils::parse_fen;
 
-  // cargo test --release -- --nocapture checkmate_black_wins_in_1 
+  // cargo test --release -- --nocapture checkmate_black_wins_in_1
   #[test]
   fn test_creation_input() {
     let mut hashtable = HashTable::new();
-    let position_info = PositionInfo::new(0, 0, "".to_string());
-    let zobrish_hash = zobrish("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
-    hashtable.table.insert(zobrish_hash.to_string(), position_info);
-    println!("{:?}", hashtable);
+    let pos = parse_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1").unwrap();
+    let mov = encode_move(&pos.legal_moves()[0]);
+    let position_info = PositionInfo::new(1, 50, Bound::Lower, Some(mov));
+    let zobrish_hash: u64 = pos.zobrist_hash();
+    hashtable.insert(zobrish_hash, position_info.clone(), 0);
+    assert_eq!(hashtable.find(zobrish_hash, 0), Some(position_info));
+    assert!(hashtable.find(zobrish_hash + 1, 0).is_none());
+  }
+
+  #[test]
+  fn cutoff_respects_bound() {
+    let exact = PositionInfo::new(3, 50, Bound::Exact, None);
+    assert_eq!(exact.cutoff(3, 0, 100), Some(50));
+    assert_eq!(exact.cutoff(4, 0, 100), None);
+
+    // a fail-high only proves the score is at least 50
+    let lower = PositionInfo::new(3, 50, Bound::Lower, None);
+    assert_eq!(lower.cutoff(2, 0, 40), Some(50));
+    assert_eq!(lower.cutoff(2, 0, 100), None);
+
+    // a fail-low only proves the score is at most 50
+    let upper = PositionInfo::new(3, 50, Bound::Upper, None);
+    assert_eq!(upper.cutoff(2, 60, 100), Some(50));
+    assert_eq!(upper.cutoff(2, 0, 100), None);
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn fixed_size_and_replacement() {
+    assert_eq!(std::mem::size_of::<Entry>(), 12);
+    let mut table = HashTable::with_size_mb(1);
+    assert_eq!(table.size_mb(), 1);
+    let buckets = table.buckets as u64;
+    // all these keys fall in the same bucket, so only BUCKET_SIZE of them fit
+    let keys: Vec<u64> = (1..=6).map(|i| (i << 32) * buckets).collect();
+    for (depth, key) in keys.iter().enumerate() {
+      table.insert(*key, PositionInfo::new(depth as u64 + 1, 0, Bound::Exact, None), 0);
+    }
+    assert_eq!(table.entries.len(), table.buckets * BUCKET_SIZE);
+    // the shallowest entries were replaced
+    assert!(table.find(keys[0], 0).is_none());
+    assert!(table.find(keys[1], 0).is_none());
+    assert!(table.find(keys[5], 0).is_some());
+
+    // entries of old searches are replaced even if they are deeper
+    table.new_search();
+    let new_keys = [(7 << 32) * buckets, (8 << 32) * buckets];
+    table.insert(new_keys[0], PositionInfo::new(1, 0, Bound::Exact, None), 0);
+    table.insert(new_keys[1], PositionInfo::new(2, 0, Bound::Exact, None), 0);
+    assert!(table.find(new_keys[0], 0).is_some());
+    assert!(table.find(keys[3], 0).is_none());
+    // and a shallower result for the same position replaces a stale one
+    table.insert(keys[5], PositionInfo::new(1, 0, Bound::Lower, None), 0);
+    assert_eq!(table.find(keys[5], 0).unwrap().depth, 1);
+
+    table.resize(2);
+    assert_eq!(table.size_mb(), 2);
+    assert!(table.find(keys[5], 0).is_none());
+  }
+
+  #[test]
+  fn size_is_clamped() {
+    // sizes that would overflow or allocate gigabytes get the maximum instead
+    let max_buckets = MAX_SIZE_MB * 1024 * 1024 / (std::mem::size_of::<Entry>() * BUCKET_SIZE);
+    assert_eq!(HashTable::buckets_for(MAX_SIZE_MB), max_buckets);
+    assert_eq!(HashTable::buckets_for(MAX_SIZE_MB + 1), max_buckets);
+    assert_eq!(HashTable::buckets_for(usize::MAX), max_buckets);
+    assert_eq!(HashTable::buckets_for(0), 1);
+  }
+
+  #[test]
+  fn mate_scores_relative_to_ply() {
+    use crate::search::MATE;
+    let mut table = HashTable::with_size_mb(1);
+    // mate in 3 plies found 2 plies from the root is mate in 1 ply from the stored position
+    table.insert(1, PositionInfo::new(3, MATE - 3, Bound::Exact, None), 2);
+    assert_eq!(table.find(1, 2).unwrap().score, MATE - 3);
+    assert_eq!(table.find(1, 4).unwrap().score, MATE - 5);
+    table.insert(2, PositionInfo::new(3, -(MATE - 3), Bound::Exact, None), 2);
+    assert_eq!(table.find(2, 0).unwrap().score, -(MATE - 1));
+    table.insert(3, PositionInfo::new(3, 42, Bound::Exact, None), 2);
+    assert_eq!(table.find(3, 5).unwrap().score, 42);
+  }
+
+  #[test]
+  fn hashfull_per_mille() {
+    let mut table = HashTable::with_size_mb(0);
+    assert_eq!(table.entries.len(), BUCKET_SIZE);
+    assert_eq!(table.hashfull(), 0);
+    table.insert(1, PositionInfo::new(1, 0, Bound::Exact, None), 0);
+    assert_eq!(table.hashfull(), 250);
+    // entries of an old search don't count
+    table.new_search();
+    assert_eq!(table.hashfull(), 0);
+  }
+
+  #[test]
+  fn binary_round_trip() {
+    let mut table = HashTable::with_size_mb(1);
+    table.new_search();
+    for key in [1u64, 2 << 40, u64::MAX] {
+      table.insert(key, PositionInfo::new(key % 7, -1234, Bound::Upper, Some(4321)), 0);
+    }
+    let bytes = table.to_bytes();
+    assert_eq!(bytes.len(), HEADER_SIZE + 3 * ENTRY_SIZE + CHECKSUM_SIZE);
+
+    let imported = HashTable::from_bytes(&bytes).unwrap();
+    assert_eq!(imported.entries, table.entries);
+    assert_eq!(imported.age, table.age);
+    assert_eq!(imported.find(u64::MAX, 0), table.find(u64::MAX, 0));
+  }
+
+  #[test]
+  fn binary_rejects_bad_input() {
+    let mut table = HashTable::with_size_mb(0);
+    table.insert(1, PositionInfo::new(3, 10, Bound::Exact, None), 0);
+    let bytes = table.to_bytes();
+    let reason = |bytes: &[u8]| match HashTable::from_bytes(bytes) {
+      Err(ChessError::CorruptTable(reason)) => reason,
+      other => panic!("{:?}", other),
+    };
+
+    assert_eq!(reason(b"WCTT"), "not a transposition table");
+    assert_eq!(reason(&bytes[..bytes.len() - 1]), "wrong length");
+    let mut damaged = bytes.clone();
+    damaged[HEADER_SIZE + 8] ^= 1;
+    assert_eq!(reason(&damaged), "checksum mismatch");
+    let mut other_version = bytes.clone();
+    other_version[4] = 2;
+    assert_eq!(reason(&other_version), "unsupported version 2, expected 1");
+
+    // headers with a valid checksum, but sizes no table can have
+    let with_checksum = |mut bytes: Vec<u8>| {
+      let length = bytes.len() - CHECKSUM_SIZE;
+      let checksum = fnv1a(&bytes[..length]);
+      bytes[length..].copy_from_slice(&checksum.to_le_bytes());
+      bytes
+    };
+    let mut huge = bytes.clone();
+    huge[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(reason(&with_checksum(huge)), "table too large");
+    let mut many_entries = bytes.clone();
+    many_entries[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(reason(&with_checksum(many_entries)), "wrong length");
+  }
+}
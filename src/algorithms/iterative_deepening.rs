@@ -3,106 +3,153 @@
 use std::vec;
 
 use shakmaty::*;
+use shakmaty::zobrist::ZobristHash;
 
 use rand::{Rng, SeedableRng};
 use rand::rngs::SmallRng;
-use instant::Instant;
 
-use crate::evaluate;
+use crate::error::ChessError;
+use crate::evaluate::Evaluator;
+use crate::utils::{parse_fen, zobrist_after};
+use crate::search::{leaf_score, terminal_score, SearchContext, SearchResult};
 
-pub fn root(fen_str: &str, seed: u64, max_time: u64) -> String {
-  let start = Instant::now();
-  let fen: fen::Fen = fen_str.parse().unwrap();
-  let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
+pub fn root(fen_str: &str, seed: u64, max_time: u64, evaluator: Box<dyn Evaluator>) -> Result<SearchResult, ChessError> {
+  search(fen_str, seed, max_time, &mut SearchContext::with_evaluator(evaluator))
+}
+
+// same as root, but the caller owns the context, to stop the search or get its progress
+pub fn search(fen_str: &str, seed: u64, max_time: u64, ctx: &mut SearchContext) -> Result<SearchResult, ChessError> {
+  let pos = parse_fen(fen_str)?;
+  let key = pos.zobrist_hash();
+  ctx.keys.push(key);
   let legals = pos.legal_moves();
-  let mut best_moves:Vec<Move> = vec![];
-  let mut new_best_moves:Vec<Move> = vec![];
-  let mut alpha;
+  if legals.is_empty() {
+    return Err(ChessError::NoLegalMoves(fen_str.to_string()));
+  }
+  // each best move is stored with the principal variation that follows it
+  let mut best_moves:Vec<Vec<Move>> = vec![];
+  let mut new_best_moves:Vec<Vec<Move>> = vec![];
+  let mut best_score = 0;
+  let mut alpha = -1_000_000;
   let mut beta;
   let mut depth = 0;
+  let mut completed_depth = 0;
 
-  while start.elapsed().as_millis() < max_time.into() || best_moves.len()==0 {
+  while !ctx.out_of_time(max_time) || best_moves.is_empty() {
+    // moves found by the last iteration, that has been completed since we got here
+    best_moves = new_best_moves.clone();
+    best_score = alpha;
+    completed_depth = depth;
+    if let Some(best_pv) = best_moves.first() {
+      ctx.report(&pos, best_pv, best_score, completed_depth);
+      if completed_depth >= ctx.max_depth {
+        break;
+      }
+    }
     alpha = -1_000_000;
     beta = 1_000_000;
-    best_moves = new_best_moves.clone();
     new_best_moves = vec![];
 
+    let mut aborted = false;
     for legal in &legals {
-      let new_pos = pos.clone().play(&legal);
-      if new_pos.is_ok() {
-        let new_fen = fen::Fen::from_position(new_pos.unwrap(), EnPassantMode::Legal).to_string();
-        let score_option = iterative_deepening(new_fen.as_str(), seed, depth, -beta, -alpha, max_time, start);
-        if score_option.is_none() {
-          break;
-        }
-        let score = -score_option.unwrap();
-        // println!("{:?} -> {:?}", san::San::from_move(&pos,&legal).to_string(), score);
-        if score >= beta {
-          new_best_moves.push(legal.clone());
-          break;
-          // return san::San::from_move(&pos,&legal).to_string();
-        }
-        if score > alpha {
-          alpha = score;
-          new_best_moves = vec![legal.clone()];
-        }
+      let mut new_pos = pos.clone();
+      new_pos.play_unchecked(legal);
+      ctx.evaluator.push(&new_pos);
+      let new_key = zobrist_after(&pos, key, legal, &new_pos);
+      let score_option = iterative_deepening(&new_pos, new_key, seed, depth, 1, -beta, -alpha, max_time, ctx);
+      ctx.evaluator.pop();
+      if score_option.is_none() {
+        aborted = true;
+        break;
+      }
+      let (mut score, mut pv) = score_option.unwrap();
+      score = -score;
+      pv.push(legal.clone());
+      pv.reverse();
+      // println!("{:?} -> {:?}", san::San::from_move(&pos,&legal).to_string(), score);
+      if score >= beta {
+        new_best_moves.push(pv);
+        break;
+        // return san::San::from_move(&pos,&legal).to_string();
       }
+      if score > alpha {
+        alpha = score;
+        new_best_moves = vec![pv];
+      }
+    }
+    // a partial iteration is never used, if none was completed yet the first legal move is played
+    if aborted {
+      if best_moves.is_empty() {
+        best_moves = vec![vec![legals[0].clone()]];
+        best_score = 0;
+      }
+      break;
     }
-    println!("Depth {:?} in {:?}ms", depth, start.elapsed().as_millis());
     depth += 1;
   }
   
   let mut rng = SmallRng::seed_from_u64(seed);
   let move_index = rng.gen_range(0..best_moves.len());
-  let san_move = san::San::from_move(&pos, &best_moves[move_index]);
-  return san_move.to_string();
-
+  Ok(SearchResult::new(&pos, &best_moves[move_index], best_score, completed_depth, ctx))
 }
 
-fn iterative_deepening(fen_str: &str, seed:u64, depth:u64, mut alpha:i64, beta:i64, max_time:u64, start:Instant) -> Option<i64> {
-  if start.elapsed().as_millis() > max_time.into() {
+// returns the score and the principal variation in reverse order, None if time is up
+#[allow(clippy::too_many_arguments)]
+fn iterative_deepening(pos: &Chess, key: u64, seed:u64, depth:u64, ply: u64, mut alpha:i64, beta:i64, max_time:u64, ctx: &mut SearchContext) -> Option<(i64, Vec<Move>)> {
+  if ctx.out_of_time(max_time) {
     // if we've reached the max time, return None
     return None;
   }
+  // repetitions and the fifty-move rule end the line with a draw
+  if ctx.is_draw(pos, key) {
+    return Some((0, vec![]));
+  }
   if depth == 0 {
     // instead of just returning the score, we call quiescent search
     // return Some(evaluate::evaluate(fen_str));
-    return Some(quiescent_search(fen_str, alpha, beta));
+    return Some((quiescent_search(pos, ply, alpha, beta, ctx), vec![]));
   }
-  let fen: fen::Fen = fen_str.parse().unwrap();
-  let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
+  ctx.nodes += 1;
   let legals = pos.legal_moves();
   let mut best_score = None;
+  let mut best_pv = vec![];
   let mut rng = SmallRng::seed_from_u64(seed);
   for legal in legals {
-    let new_pos = pos.clone().play(&legal);
-    if new_pos.is_ok() {
-      let new_fen = fen::Fen::from_position(new_pos.unwrap(), EnPassantMode::Legal).to_string();
-      let score_option = iterative_deepening(new_fen.as_str(), rng.gen::<u64>(),depth-1, -beta, -alpha, max_time, start);
-      if score_option.is_none() {
-        return None;
-      }
-      let score = -score_option.unwrap();
-      if best_score == None || score > best_score.unwrap() {
-        best_score = Some(score);
-      }
-      if score >= beta {
-        return Some(beta);
-      }
-      if score > alpha {
-        best_score = Some(score);
-        alpha = score;
-      }
+    let mut new_pos = pos.clone();
+    new_pos.play_unchecked(&legal);
+    ctx.evaluator.push(&new_pos);
+    let new_key = zobrist_after(pos, key, &legal, &new_pos);
+    ctx.keys.push(key);
+    let child = iterative_deepening(&new_pos, new_key, rng.gen::<u64>(),depth-1, ply + 1, -beta, -alpha, max_time, ctx);
+    ctx.evaluator.pop();
+    ctx.keys.pop();
+    let (mut score, mut pv) = child?;
+    score = -score;
+    if best_score.is_none() || score > best_score.unwrap() {
+      best_score = Some(score);
+      pv.push(legal);
+      best_pv = pv;
+    }
+    if score >= beta {
+      return Some((beta, best_pv));
+    }
+    if score > alpha {
+      best_score = Some(score);
+      alpha = score;
     }
   }
   // or allows us to return the evaluation of the position if no legal moves are available, adjusted with the depth so we aim for faster checkmate
-  Some(best_score.unwrap_or(evaluate::evaluate(fen_str)-depth as i64))
+  match best_score {
+    Some(best_score) => Some((best_score, best_pv)),
+    None => Some((terminal_score(pos, ply), best_pv)),
+  }
   // best_score.unwrap_or(evaluate::evaluate(fen_str))
 }
 
-pub fn quiescent_search(fen_str: &str, mut alpha:i64, beta:i64) -> i64 {
+pub fn quiescent_search(pos: &Chess, ply: u64, mut alpha:i64, beta:i64, ctx: &mut SearchContext) -> i64 {
   // https://www.chessprogramming.org/Quiescence_Search
-  let stand_pat = evaluate::evaluate(fen_str);
+  ctx.nodes += 1;
+  let stand_pat = leaf_score(pos, ply, ctx.evaluator.as_mut());
   if stand_pat >= beta {
     return beta;
   }
@@ -110,21 +157,19 @@ pub fn quiescent_search(fen_str: &str, mut alpha:i64, beta:i64) -> i64 {
     alpha = stand_pat;
   }
 
-  let fen: fen::Fen = fen_str.parse().unwrap();
-  let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
   let capture_moves = pos.capture_moves();
   
   for capture in capture_moves {
-    let new_pos = pos.clone().play(&capture);
-    if new_pos.is_ok() {
-      let new_fen = fen::Fen::from_position(new_pos.unwrap(), EnPassantMode::Legal).to_string();
-      let score = -quiescent_search(new_fen.as_str(), -beta, -alpha);
-      if score >= beta {
-        return beta;
-      }
-      if score > alpha {
-        alpha = score;
-      }
+    let mut new_pos = pos.clone();
+    new_pos.play_unchecked(&capture);
+    ctx.evaluator.push(&new_pos);
+    let score = -quiescent_search(&new_pos, ply + 1, -beta, -alpha, ctx);
+    ctx.evaluator.pop();
+    if score >= beta {
+      return beta;
+    }
+    if score > alpha {
+      alpha = score;
     }
   }
   alpha
@@ -134,6 +179,7 @@ pub fn quiescent_search(fen_str: &str, mut alpha:i64, beta:i64) -> i64 {
 #[cfg(test)]
 mod test {
   use super::*;
+  use crate::evaluate::Classic;
   const NAME: &str = "iterative deepening";
 
   // cargo test --release -- --nocapture checkmate_black_wins_in_1 
@@ -142,18 +188,18 @@ mod test {
   fn test_exec_time() {
     use std::time::Instant;
     let now = Instant::now();
-    println!("[{:?}] {:?}",NAME,root("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 0, 1000));
+    println!("[{:?}] {:?}",NAME,root("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 0, 1000, Box::new(Classic::default())).unwrap().san);
     let elapsed = now.elapsed();
     println!("[{:?}] Elapsed: {:.2?}",NAME, elapsed);
   }
 
   #[test]
   fn checkmate_black_wins_in_1() {
-    assert_eq!(root("4k3/8/8/8/8/2r5/1q6/5K2 b - - 3 2", 1, 1000), "Rc1");
+    assert_eq!(root("4k3/8/8/8/8/2r5/1q6/5K2 b - - 3 2", 1, 1000, Box::new(Classic::default())).unwrap().san, "Rc1");
   }
   #[test]
   fn checkmate_black_wins_in_2() {
-    let mov = root("4k3/8/8/8/1qr5/8/8/6K1 b - - 3 2", 1, 2000);
+    let mov = root("4k3/8/8/8/1qr5/8/8/6K1 b - - 3 2", 1, 2000, Box::new(Classic::default())).unwrap().san;
     if !(mov == "Qb2" || mov == "Qd2" || mov == "Rc2") {
       panic!("{}", mov);
     }
@@ -161,14 +207,23 @@ mod test {
 
   #[test]
   fn checkmate_white_wins_in_1() {
-    assert_eq!(root("5k2/2R5/1Q6/8/8/8/8/4K3 w - - 3 3", 1, 1000), "Qb8");
+    assert_eq!(root("5k2/2R5/1Q6/8/8/8/8/4K3 w - - 3 3", 1, 1000, Box::new(Classic::default())).unwrap().san, "Qb8");
   }
 
   #[test]
   fn checkmate_white_wins_in_2() {
-    let mov = root("5k2/8/1Q6/2R5/8/8/8/4K3 w - - 3 3", 1, 1000);
+    let mov = root("5k2/8/1Q6/2R5/8/8/8/4K3 w - - 3 3", 1, 1000, Box::new(Classic::default())).unwrap().san;
     if !(mov == "Qb7" || mov == "Qa7" || mov == "Rc7") {
       panic!("{}", mov);
     }
   }
+
+  #[test]
+  fn stopped_before_first_iteration() {
+    let mut ctx = SearchContext::new();
+    ctx.stop.store(true, std::sync::atomic::Ordering::Relaxed);
+    let result = search("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 1, u64::MAX, &mut ctx).unwrap();
+    assert_eq!(result.pv.len(), 1);
+    assert_eq!(result.depth, 0);
+  }
 }
@@ -3,41 +3,65 @@
 use std::vec;
 
 use shakmaty::*;
+use shakmaty::zobrist::ZobristHash;
 
 use rand::{Rng, SeedableRng};
 use rand::rngs::SmallRng;
-use instant::Instant;
-use crate::ordering;
 
-use crate::evaluate;
+use crate::error::ChessError;
+use crate::evaluate::Evaluator;
+use crate::utils::{parse_fen, zobrist_after};
+use crate::search::{leaf_score, terminal_score, SearchContext, SearchResult};
 
-pub fn root(fen_str: &str, seed: u64, max_time: u64) -> String {
-  let start = Instant::now();
-  let fen: fen::Fen = fen_str.parse().unwrap();
-  let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
+pub fn root(fen_str: &str, seed: u64, max_time: u64, evaluator: Box<dyn Evaluator>) -> Result<SearchResult, ChessError> {
+  search(fen_str, seed, max_time, &mut SearchContext::with_evaluator(evaluator))
+}
+
+// same as root, but the caller owns the context, to stop the search or get its progress
+pub fn search(fen_str: &str, seed: u64, max_time: u64, ctx: &mut SearchContext) -> Result<SearchResult, ChessError> {
+  let pos = parse_fen(fen_str)?;
+  let key = pos.zobrist_hash();
+  ctx.keys.push(key);
   let mut legals = pos.legal_moves();
-  let mut best_moves:Vec<Move> = vec![];
-  let mut new_best_moves:Vec<Move> = vec![];
-  let mut alpha;
+  if legals.is_empty() {
+    return Err(ChessError::NoLegalMoves(fen_str.to_string()));
+  }
+  // each best move is stored with the principal variation that follows it
+  let mut best_moves:Vec<Vec<Move>> = vec![];
+  let mut new_best_moves:Vec<Vec<Move>> = vec![];
+  let mut best_score = 0;
+  let mut alpha = -1_000_000;
   let mut beta;
   let mut depth = 0;
-  let mut best_prev_moves:Vec<Move> = vec![];
+  let mut completed_depth = 0;
 
-  while start.elapsed().as_millis() < max_time.into() || best_moves.len()==0 {
+  while !ctx.out_of_time(max_time) || best_moves.is_empty() {
+    // moves found by the last iteration, that has been completed since we got here
+    best_moves = new_best_moves.clone();
+    best_score = alpha;
+    completed_depth = depth;
+    if let Some(best_pv) = best_moves.first() {
+      ctx.report(&pos, best_pv, best_score, completed_depth);
+      if completed_depth >= ctx.max_depth {
+        break;
+      }
+    }
     alpha = -1_000_000;
     beta = 1_000_000;
-    best_moves = new_best_moves.clone();
     new_best_moves = vec![];
 
     // starting from last best move
-    if best_prev_moves.len() > 0 {
-      let last_best_move = best_prev_moves.last().unwrap().clone();
+    if let Some(best_prev_moves) = best_moves.first() {
+      let last_best_move = best_prev_moves[0].clone();
       // println!("{:?}", last_best_move);
+      let heuristics = &ctx.heuristics;
       legals.sort_by(|a, b| {
         if a==&last_best_move && b!=&last_best_move {
           std::cmp::Ordering::Less
-        }else {
-          ordering::move_ordering(a,b)
+        } else if b==&last_best_move && a!=&last_best_move {
+          std::cmp::Ordering::Greater
+        } else {
+          heuristics.ordering(a, b, pos.turn(), 0)
         }
       });
       /* println!("{:?}", legals[0]);
@@ -46,122 +70,120 @@ pub fn root(fen_str: &str, seed: u64, max_time: u64) -> String {
     }
     // legals.sort_by(|a, b| a == )
 
+    let mut aborted = false;
     for legal in &legals {
-      let new_pos = pos.clone().play(&legal);
-      if new_pos.is_ok() {
-        let new_fen = fen::Fen::from_position(new_pos.unwrap(), EnPassantMode::Legal).to_string();
-        let score_option = iterative_deepening(new_fen.as_str(), seed, depth, -beta, -alpha, max_time, start);
-        if score_option.is_none() {
-          break;
-        }
-        let (mut score, mut prev_moves) = score_option.unwrap();
-        score = -score;
-        // println!("{:?} -> {:?}", san::San::from_move(&pos,&legal).to_string(), score);
-        if score >= beta {
-          println!("found another move with same score, updating vector");
-          new_best_moves.push(legal.clone());
-          best_prev_moves = prev_moves.clone();
-
-          break;
-          // return san::San::from_move(&pos,&legal).to_string();
-        }
-        if score > alpha {
-          alpha = score;
-          new_best_moves = vec![legal.clone()];
-          prev_moves.push(legal.clone());
-          best_prev_moves = prev_moves.clone();
-        }
+      let mut new_pos = pos.clone();
+      new_pos.play_unchecked(legal);
+      ctx.evaluator.push(&new_pos);
+      let new_key = zobrist_after(&pos, key, legal, &new_pos);
+      let score_option = iterative_deepening(&new_pos, new_key, seed, depth, 1, -beta, -alpha, max_time, ctx);
+      ctx.evaluator.pop();
+      if score_option.is_none() {
+        aborted = true;
+        break;
+      }
+      let (mut score, mut prev_moves) = score_option.unwrap();
+      score = -score;
+      // moves are collected from the leaf up, so the variation has to be reversed
+      prev_moves.push(legal.clone());
+      prev_moves.reverse();
+      // println!("{:?} -> {:?}", san::San::from_move(&pos,&legal).to_string(), score);
+      if score >= beta {
+        new_best_moves.push(prev_moves);
+        break;
+        // return san::San::from_move(&pos,&legal).to_string();
+      }
+      if score > alpha {
+        alpha = score;
+        new_best_moves = vec![prev_moves];
       }
     }
-    println!("Depth {:?} in {:?}ms", depth, start.elapsed().as_millis());
     /* for move_ in &best_prev_moves {
       print!("{:?} ", move_.to_uci(CastlingMode::Standard).to_string());
     }
     println!(""); */
+    // a partial iteration is never used, if none was completed yet the first legal move is played
+    if aborted {
+      if best_moves.is_empty() {
+        best_moves = vec![vec![legals[0].clone()]];
+        best_score = 0;
+      }
+      break;
+    }
     depth += 1;
   }
   
   let mut rng = SmallRng::seed_from_u64(seed);
   let move_index = rng.gen_range(0..best_moves.len());
-  let san_move = san::San::from_move(&pos, &best_moves[move_index]);
-  for move_ in &best_prev_moves {
-    println!("{:?}", move_.to_uci(CastlingMode::Standard).to_string());
-  }
-
-  return san_move.to_string();
-
+  Ok(SearchResult::new(&pos, &best_moves[move_index], best_score, completed_depth, ctx))
 }
 
-fn iterative_deepening(fen_str: &str, seed:u64, depth:u64, mut alpha:i64, beta:i64, max_time:u64, start:Instant) -> Option<(i64,Vec<Move>)> {
-  if start.elapsed().as_millis() > max_time.into() {
+#[allow(clippy::too_many_arguments)]
+fn iterative_deepening(pos: &Chess, key: u64, seed:u64, depth:u64, ply: u64, mut alpha:i64, beta:i64, max_time:u64, ctx: &mut SearchContext) -> Option<(i64, Vec<Move>)> {
+  if ctx.out_of_time(max_time) {
     // if we've reached the max time, return None
     return None;
   }
+  // repetitions and the fifty-move rule end the line with a draw
+  if ctx.is_draw(pos, key) {
+    return Some((0, vec![]));
+  }
   if depth == 0 {
     // instead of just returning the score, we call quiescent search
     // return Some(evaluate::evaluate(fen_str));
-    return Some((quiescent_search(fen_str, alpha, beta, max_time*5, start), vec![]));
+    return Some((quiescent_search(pos, ply, alpha, beta, max_time.saturating_mul(5), ctx), vec![]));
   }
-  let fen: fen::Fen = fen_str.parse().unwrap();
-  let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
+  ctx.nodes += 1;
   let mut legals = pos.legal_moves();
   let mut best_score = None;
   let mut best_previous_moves:Option<Vec<Move>> = None;
   let mut rng = SmallRng::seed_from_u64(seed);
 
-  // starting from last best move
-  let best_prev_moves = best_previous_moves.clone().unwrap_or(vec![]);
-  if best_prev_moves.len() > 0 {
-    let last_best_move = best_prev_moves.last().unwrap();
-    // println!("{:?}", last_best_move);
-    legals.sort_by(|a, b| {
-      if a==last_best_move && b!=last_best_move {
-        std::cmp::Ordering::Less 
-      }else {
-        ordering::move_ordering(a,b)
-      }
-    });
-  }
+  // captures by MVV-LVA, then the quiet moves that caused cutoffs in other lines
+  let heuristics = &ctx.heuristics;
+  legals.sort_by(|a, b| heuristics.ordering(a, b, pos.turn(), ply));
   
   for legal in legals {
-    let new_pos = pos.clone().play(&legal);
-    if new_pos.is_ok() {
-      let new_fen = fen::Fen::from_position(new_pos.unwrap(), EnPassantMode::Legal).to_string();
-      let score_option = iterative_deepening(new_fen.as_str(), rng.gen::<u64>(),depth-1, -beta, -alpha, max_time, start);
-      if score_option.is_none() {
-        return None;
-      }
-      let (mut score, mut prev_moves) = score_option.unwrap();
-      score = -score;
-      // updating new best score
-      if best_score == None || score > best_score.unwrap() {
-        best_score = Some(score);
-        prev_moves.push(legal.clone());
-        best_previous_moves = Some(prev_moves);
-      }
-      // pruning
-      if score >= beta {
-        return Some((beta, best_previous_moves.unwrap()));
-      }
-      // updating alpha
-      if score > alpha {
-        best_score = Some(score);
-        alpha = score;
-      }
+    let mut new_pos = pos.clone();
+    new_pos.play_unchecked(&legal);
+    ctx.evaluator.push(&new_pos);
+    let new_key = zobrist_after(pos, key, &legal, &new_pos);
+    ctx.keys.push(key);
+    let child = iterative_deepening(&new_pos, new_key, rng.gen::<u64>(),depth-1, ply + 1, -beta, -alpha, max_time, ctx);
+    ctx.evaluator.pop();
+    ctx.keys.pop();
+    let (mut score, mut prev_moves) = child?;
+    score = -score;
+    // updating new best score
+    if best_score.is_none() || score > best_score.unwrap() {
+      best_score = Some(score);
+      prev_moves.push(legal.clone());
+      best_previous_moves = Some(prev_moves);
+    }
+    // pruning
+    if score >= beta {
+      ctx.heuristics.cutoff(pos.turn(), &legal, ply, depth);
+      return Some((beta, best_previous_moves.unwrap()));
+    }
+    // updating alpha
+    if score > alpha {
+      best_score = Some(score);
+      alpha = score;
     }
   }
   // or allows us to return the evaluation of the position if no legal moves are available, adjusted with the depth so we aim for faster checkmate
   if best_score.is_none() {
-    return Some((evaluate::evaluate(fen_str) - depth as i64, vec![]));
+    return Some((terminal_score(pos, ply), vec![]));
   }
   Some((best_score.unwrap(), best_previous_moves.unwrap()))
   // best_score.unwrap_or(evaluate::evaluate(fen_str))
 }
 
-pub fn quiescent_search(fen_str: &str, mut alpha:i64, beta:i64, max_time:u64, start:Instant) -> i64 {
+pub fn quiescent_search(pos: &Chess, ply: u64, mut alpha:i64, beta:i64, max_time:u64, ctx: &mut SearchContext) -> i64 {
   // https://www.chessprogramming.org/Quiescence_Search
-  let stand_pat = evaluate::evaluate(fen_str);
-  if start.elapsed().as_millis() > max_time.into() {
+  ctx.nodes += 1;
+  let stand_pat = leaf_score(pos, ply, ctx.evaluator.as_mut());
+  if ctx.out_of_time(max_time) {
     // if we've reached the max time, return beta
     return beta;
   }
@@ -172,21 +194,19 @@ pub fn quiescent_search(fen_str: &str, mut alpha:i64, beta:i64, max_time:u64, st
     alpha = stand_pat;
   }
 
-  let fen: fen::Fen = fen_str.parse().unwrap();
-  let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
   let capture_moves = pos.capture_moves();
   
   for capture in capture_moves {
-    let new_pos = pos.clone().play(&capture);
-    if new_pos.is_ok() {
-      let new_fen = fen::Fen::from_position(new_pos.unwrap(), EnPassantMode::Legal).to_string();
-      let score = -quiescent_search(new_fen.as_str(), -beta, -alpha, max_time, start);
-      if score >= beta {
-        return beta;
-      }
-      if score > alpha {
-        alpha = score;
-      }
+    let mut new_pos = pos.clone();
+    new_pos.play_unchecked(&capture);
+    ctx.evaluator.push(&new_pos);
+    let score = -quiescent_search(&new_pos, ply + 1, -beta, -alpha, max_time, ctx);
+    ctx.evaluator.pop();
+    if score >= beta {
+      return beta;
+    }
+    if score > alpha {
+      alpha = score;
     }
   }
   alpha
@@ -196,6 +216,7 @@ pub fn quiescent_search(fen_str: &str, mut alpha:i64, beta:i64, max_time:u64, st
 #[cfg(test)]
 mod test {
   use super::*;
+  use crate::evaluate::Classic;
   const NAME: &str = "iterative deepening";
 
   // cargo test --release -- --nocapture checkmate_black_wins_in_1 
@@ -204,18 +225,18 @@ mod test {
   fn test_exec_time() {
     use std::time::Instant;
     let now = Instant::now();
-    println!("[{:?}] {:?}",NAME,root("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 0, 1000));
+    println!("[{:?}] {:?}",NAME,root("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 0, 1000, Box::new(Classic::default())).unwrap().san);
     let elapsed = now.elapsed();
     println!("[{:?}] Elapsed: {:.2?}",NAME, elapsed);
   }
 
   #[test]
   fn checkmate_black_wins_in_1() {
-    assert_eq!(root("4k3/8/8/8/8/2r5/1q6/5K2 b - - 3 2", 1, 1000), "Rc1");
+    assert_eq!(root("4k3/8/8/8/8/2r5/1q6/5K2 b - - 3 2", 1, 1000, Box::new(Classic::default())).unwrap().san, "Rc1");
   }
   #[test]
   fn checkmate_black_wins_in_2() {
-    let mov = root("4k3/8/8/8/1qr5/8/8/6K1 b - - 3 2", 1, 2000);
+    let mov = root("4k3/8/8/8/1qr5/8/8/6K1 b - - 3 2", 1, 2000, Box::new(Classic::default())).unwrap().san;
     if !(mov == "Qb2" || mov == "Qd2" || mov == "Rc2") {
       panic!("{}", mov);
     }
@@ -223,14 +244,66 @@ mod test {
 
   #[test]
   fn checkmate_white_wins_in_1() {
-    assert_eq!(root("5k2/2R5/1Q6/8/8/8/8/4K3 w - - 3 3", 1, 1000), "Qb8");
+    assert_eq!(root("5k2/2R5/1Q6/8/8/8/8/4K3 w - - 3 3", 1, 1000, Box::new(Classic::default())).unwrap().san, "Qb8");
   }
 
   #[test]
   fn checkmate_white_wins_in_2() {
-    let mov = root("5k2/8/1Q6/2R5/8/8/8/4K3 w - - 3 3", 1, 1000);
+    let mov = root("5k2/8/1Q6/2R5/8/8/8/4K3 w - - 3 3", 1, 1000, Box::new(Classic::default())).unwrap().san;
     if !(mov == "Qb7" || mov == "Qa7" || mov == "Rc7") {
       panic!("{}", mov);
     }
   }
+
+  #[test]
+  fn result_has_pv() {
+    let result = root("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 0, 500, Box::new(Classic::default())).unwrap();
+    assert!(result.depth >= 2);
+    assert!(result.pv.len() >= 2);
+    assert_eq!(result.pv[0], result.uci);
+    assert!(result.nodes > 0);
+  }
+
+  // nodes searched to depth with and without killer moves and history
+  fn nodes(fen: &str, depth: u64, heuristics: bool) -> u64 {
+    let mut ctx = SearchContext::new();
+    ctx.max_depth = depth;
+    ctx.heuristics.enabled = heuristics;
+    search(fen, 1, u64::MAX, &mut ctx).unwrap().nodes
+  }
+
+  // cargo test --release -- --ignored --nocapture node_count_benchmark
+  #[test]
+  #[ignore]
+  fn node_count_benchmark() {
+    let fens = [
+      "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+      "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3",
+      "r1bq1rk1/pp2bppp/2n1pn2/3p4/2PP4/2N1PN2/PP3PPP/R2QKB1R w KQ - 0 8",
+      "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+    ];
+    let (mut total_without, mut total_with) = (0, 0);
+    for fen in fens {
+      let (without, with) = (nodes(fen, 5, false), nodes(fen, 5, true));
+      println!("[{:?}] {}: {} -> {} nodes ({:.0}%)", NAME, fen, without, with, 100.0 * with as f64 / without as f64);
+      total_without += without;
+      total_with += with;
+    }
+    println!("[{:?}] total: {} -> {} nodes ({:.0}%)", NAME, total_without, total_with, 100.0 * total_with as f64 / total_without as f64);
+  }
+
+  #[test]
+  fn killers_shrink_the_tree() {
+    let fen = "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3";
+    assert!(nodes(fen, 4, true) < nodes(fen, 4, false));
+  }
+
+  #[test]
+  fn stopped_before_first_iteration() {
+    let mut ctx = SearchContext::new();
+    ctx.stop.store(true, std::sync::atomic::Ordering::Relaxed);
+    let result = search("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 1, u64::MAX, &mut ctx).unwrap();
+    assert_eq!(result.pv.len(), 1);
+    assert_eq!(result.depth, 0);
+  }
 }
@@ -2,124 +2,202 @@
 use std::vec;
 
 use shakmaty::*;
+use shakmaty::zobrist::ZobristHash;
 
 use rand::{Rng, SeedableRng};
 use rand::rngs::SmallRng;
-use instant::Instant;
 
-use crate::algorithms::hashtable::PositionInfo;
-use crate::evaluate;
+use crate::algorithms::hashtable::{encode_move, Bound, PositionInfo};
+use crate::ordering;
+use crate::error::ChessError;
+use crate::evaluate::Evaluator;
+use crate::utils::{parse_fen, zobrist_after};
 use crate::HashTable;
 use crate::utils::MoveAndTable;
+use crate::search::{leaf_score, terminal_score, SearchContext, SearchResult};
 
-pub fn root(fen_str: &str, seed: u64, max_time: u64, table: Option<HashTable>) -> MoveAndTable {
-  let start = Instant::now();
-  let fen: fen::Fen = fen_str.parse().unwrap();
-  let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
-  let legals = pos.legal_moves();
-  let mut best_moves:Vec<String> = vec![];
-  let mut new_best_moves:Vec<String> = vec![];
-  let mut alpha;
+pub fn root(fen_str: &str, seed: u64, max_time: u64, table: Option<HashTable>, evaluator: Box<dyn Evaluator>) -> Result<MoveAndTable, ChessError> {
+  let mut table = table.unwrap_or_default();
+  let result = search(fen_str, seed, max_time, &mut table, &mut SearchContext::with_evaluator(evaluator))?;
+  Ok(MoveAndTable::new(result, table))
+}
+
+// same as root, but works on a table owned by the caller so it can be kept between moves,
+// the context can hold the history of the game, to detect repetitions
+pub fn search(fen_str: &str, seed: u64, max_time: u64, table: &mut HashTable, ctx: &mut SearchContext) -> Result<SearchResult, ChessError> {
+  let pos = parse_fen(fen_str)?;
+  let key = pos.zobrist_hash();
+  ctx.keys.push(key);
+  table.new_search();
+  let mut legals = pos.legal_moves();
+  if legals.is_empty() {
+    return Err(ChessError::NoLegalMoves(fen_str.to_string()));
+  }
+  // each best move is stored with the principal variation that follows it
+  let mut best_moves:Vec<Vec<Move>> = vec![];
+  let mut new_best_moves:Vec<Vec<Move>> = vec![];
+  let mut best_score = 0;
+  let mut alpha = -1_000_000;
   let mut beta;
   let mut depth = 0;
-  let mut table = table.unwrap_or_else(|| HashTable::new());
+  let mut completed_depth = 0;
+  // draws found by is_draw before the last iteration started
+  let mut draws = ctx.draws;
 
-  while start.elapsed().as_millis() < max_time.into() || best_moves.len()==0 {
+  while !ctx.out_of_time(max_time) || best_moves.is_empty() {
+    // moves found by the last iteration, that has been completed since we got here
+    best_moves = new_best_moves.clone();
+    best_score = alpha;
+    completed_depth = depth;
+    if let Some(best_pv) = best_moves.first() {
+      // unless its score depends on the path to the root, as the scores of the other nodes
+      if ctx.draws == draws {
+        table.insert(key, PositionInfo::new(depth, best_score, Bound::Exact, Some(encode_move(&best_pv[0]))), 0);
+      }
+      ctx.report(&pos, best_pv, best_score, completed_depth);
+      if completed_depth >= ctx.max_depth {
+        break;
+      }
+    }
+    // the best move of the last iteration (or of a previous search) is searched first,
+    // then the other moves by the cutoffs they caused so far
+    let heuristics = &ctx.heuristics;
+    legals.sort_by(|a, b| heuristics.ordering(a, b, pos.turn(), 0));
+    let best_move = best_moves.first().map(|best_pv| encode_move(&best_pv[0]));
+    if let Some(mov) = best_move.or_else(|| table.find(key, 0).and_then(|entry| entry.mov)) {
+      ordering::best_move_first(&mut legals, mov);
+    }
     alpha = -1_000_000;
     beta = 1_000_000;
-    best_moves = new_best_moves.clone();
     new_best_moves = vec![];
 
+    draws = ctx.draws;
+    let mut aborted = false;
     for legal in &legals {
-      let new_pos = pos.clone().play(&legal);
-      if new_pos.is_ok() {
-        let new_fen = fen::Fen::from_position(new_pos.unwrap(), EnPassantMode::Legal).to_string();
-        let score_option = iterative_deepening(new_fen.as_str(), seed, depth, -beta, -alpha, max_time, start, &mut table);
-        if score_option.is_none() {
-          break;
-        }
-        let score = -score_option.unwrap();
-        // println!("{:?} -> {:?}", san::San::from_move(&pos,&legal).to_string(), score);
-        // store score in table 
-        table.insert(&new_fen, PositionInfo {
-          mov: san::San::from_move(&pos,&legal).to_string(),
-          score,
-          depth,
-        });
-        if score >= beta {
-          let san_move = san::San::from_move(&pos,&legal).to_string();
-          new_best_moves.push(san_move);
-          break;
-          // return san::San::from_move(&pos,&legal).to_string();
-        }
-        if score > alpha {
-          alpha = score;
-          let san_move = san::San::from_move(&pos,&legal).to_string();
-          new_best_moves = vec![san_move];
-        }
+      let mut new_pos = pos.clone();
+      new_pos.play_unchecked(legal);
+      ctx.evaluator.push(&new_pos);
+      let new_key = zobrist_after(&pos, key, legal, &new_pos);
+      let score_option = iterative_deepening(&new_pos, new_key, seed, depth, 1, -beta, -alpha, max_time, ctx, table);
+      ctx.evaluator.pop();
+      if score_option.is_none() {
+        aborted = true;
+        break;
+      }
+      let (mut score, mut pv) = score_option.unwrap();
+      score = -score;
+      pv.push(legal.clone());
+      pv.reverse();
+      // println!("{:?} -> {:?}", san::San::from_move(&pos,&legal).to_string(), score);
+      if score >= beta {
+        new_best_moves.push(pv);
+        break;
+        // return san::San::from_move(&pos,&legal).to_string();
+      }
+      if score > alpha {
+        alpha = score;
+        new_best_moves = vec![pv];
       }
     }
-    println!("Depth {:?} in {:?}ms", depth, start.elapsed().as_millis());
+    // a partial iteration is never used, if none was completed yet the move searched first is played,
+    // the one from the table if there is one
+    if aborted {
+      if best_moves.is_empty() {
+        best_moves = vec![vec![legals[0].clone()]];
+        best_score = 0;
+      }
+      break;
+    }
     depth += 1;
   }
   
   let mut rng = SmallRng::seed_from_u64(seed);
   let move_index = rng.gen_range(0..best_moves.len());
-  let san_move = &best_moves[move_index];
   // println!("{:?}", &table);
-  MoveAndTable::new(san_move.to_string(), table)
+  Ok(SearchResult::new(&pos, &best_moves[move_index], best_score, completed_depth, ctx))
 }
 
-fn iterative_deepening(fen_str: &str, seed:u64, depth:u64, mut alpha:i64, beta:i64, max_time:u64, start:Instant, table: &mut HashTable) -> Option<i64> {
-  if start.elapsed().as_millis() > max_time.into() {
+// returns the score and the principal variation in reverse order, None if time is up
+// key is the zobrist hash of pos
+#[allow(clippy::too_many_arguments)]
+fn iterative_deepening(pos: &Chess, key: u64, seed:u64, depth:u64, ply: u64, mut alpha:i64, beta:i64, max_time:u64, ctx: &mut SearchContext, table: &mut HashTable) -> Option<(i64, Vec<Move>)> {
+  if ctx.out_of_time(max_time) {
     // if we've reached the max time, return None
     return None;
   }
-  // if there's an entry in the table with a depth greater than the current depth, return it
-  let table_entry = table.find(&fen_str, depth);
-  if let Some(entry) = table_entry {
-    return Some(entry.score /* - (entry.depth-depth) as i64 */);
+  // repetitions and the fifty-move rule end the line with a draw, checked before the table so
+  // a draw node is never probed or stored, its ancestors are not stored either
+  if ctx.is_draw(pos, key) {
+    ctx.draws += 1;
+    return Some((0, vec![]));
+  }
+  let draws = ctx.draws;
+  // if there's an entry in the table with enough depth and a score usable in this window, return it
+  let table_entry = table.find(key, ply);
+  if let Some(score) = table_entry.as_ref().and_then(|entry| entry.cutoff(depth, alpha, beta)) {
+    return Some((score /* - (entry.depth-depth) as i64 */, vec![]));
   }
   
   if depth == 0 {
     // instead of just returning the score, we call quiescent search
     // return Some(evaluate::evaluate(fen_str));
-    return Some(quiescent_search(fen_str, alpha, beta));
+    return Some((quiescent_search(pos, ply, alpha, beta, ctx), vec![]));
   }
-  let fen: fen::Fen = fen_str.parse().unwrap();
-  let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
-  let legals = pos.legal_moves();
+  ctx.nodes += 1;
+  // the stored best move first, then captures by MVV-LVA and the quiet moves that caused cutoffs in other lines
+  let mut legals = pos.legal_moves();
+  let heuristics = &ctx.heuristics;
+  legals.sort_by(|a, b| heuristics.ordering(a, b, pos.turn(), ply));
+  if let Some(mov) = table_entry.and_then(|entry| entry.mov) {
+    ordering::best_move_first(&mut legals, mov);
+  }
+  let original_alpha = alpha;
   let mut best_score = None;
+  let mut best_pv = vec![];
   let mut rng = SmallRng::seed_from_u64(seed);
   for legal in legals {
-    let new_pos = pos.clone().play(&legal);
-    if new_pos.is_ok() {
-      let new_fen = fen::Fen::from_position(new_pos.unwrap(), EnPassantMode::Legal).to_string();
-      let score_option = iterative_deepening(new_fen.as_str(), rng.gen::<u64>(),depth-1, -beta, -alpha, max_time, start, table);
-      if score_option.is_none() {
-        return None;
-      }
-      let score = -score_option.unwrap();
-      if best_score == None || score > best_score.unwrap() {
-        best_score = Some(score);
-      }
-      if score >= beta {
-        return Some(beta);
-      }
-      if score > alpha {
-        best_score = Some(score);
-        alpha = score;
+    let mut new_pos = pos.clone();
+    new_pos.play_unchecked(&legal);
+    ctx.evaluator.push(&new_pos);
+    let new_key = zobrist_after(pos, key, &legal, &new_pos);
+    ctx.keys.push(key);
+    let child = iterative_deepening(&new_pos, new_key, rng.gen::<u64>(),depth-1, ply + 1, -beta, -alpha, max_time, ctx, table);
+    ctx.evaluator.pop();
+    ctx.keys.pop();
+    let (mut score, mut pv) = child?;
+    score = -score;
+    if best_score.is_none() || score > best_score.unwrap() {
+      best_score = Some(score);
+      pv.push(legal.clone());
+      best_pv = pv;
+    }
+    if score >= beta {
+      ctx.heuristics.cutoff(pos.turn(), &legal, ply, depth);
+      if ctx.draws == draws {
+        table.insert(key, PositionInfo::new(depth, beta, Bound::Lower, best_pv.last().map(encode_move)), ply);
       }
+      return Some((beta, best_pv));
+    }
+    if score > alpha {
+      best_score = Some(score);
+      alpha = score;
     }
   }
   // or allows us to return the evaluation of the position if no legal moves are available, adjusted with the depth so we aim for faster checkmate
-  Some(best_score.unwrap_or(evaluate::evaluate(fen_str)-depth as i64))
+  let best_score = best_score.unwrap_or_else(|| terminal_score(pos, ply));
+  // the score is exact only if some move raised alpha, otherwise it's just an upper bound
+  let bound = if best_score > original_alpha { Bound::Exact } else { Bound::Upper };
+  if ctx.draws == draws {
+    table.insert(key, PositionInfo::new(depth, best_score, bound, best_pv.last().map(encode_move)), ply);
+  }
+  Some((best_score, best_pv))
   // best_score.unwrap_or(evaluate::evaluate(fen_str))
 }
 
-pub fn quiescent_search(fen_str: &str, mut alpha:i64, beta:i64) -> i64 {
+pub fn quiescent_search(pos: &Chess, ply: u64, mut alpha:i64, beta:i64, ctx: &mut SearchContext) -> i64 {
   // https://www.chessprogramming.org/Quiescence_Search
-  let stand_pat = evaluate::evaluate(fen_str);
+  ctx.nodes += 1;
+  let stand_pat = leaf_score(pos, ply, ctx.evaluator.as_mut());
   if stand_pat >= beta {
     return beta;
   }
@@ -127,21 +205,19 @@ pub fn quiescent_search(fen_str: &str, mut alpha:i64, beta:i64) -> i64 {
     alpha = stand_pat;
   }
 
-  let fen: fen::Fen = fen_str.parse().unwrap();
-  let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
   let capture_moves = pos.capture_moves();
   
   for capture in capture_moves {
-    let new_pos = pos.clone().play(&capture);
-    if new_pos.is_ok() {
-      let new_fen = fen::Fen::from_position(new_pos.unwrap(), EnPassantMode::Legal).to_string();
-      let score = -quiescent_search(new_fen.as_str(), -beta, -alpha);
-      if score >= beta {
-        return beta;
-      }
-      if score > alpha {
-        alpha = score;
-      }
+    let mut new_pos = pos.clone();
+    new_pos.play_unchecked(&capture);
+    ctx.evaluator.push(&new_pos);
+    let score = -quiescent_search(&new_pos, ply + 1, -beta, -alpha, ctx);
+    ctx.evaluator.pop();
+    if score >= beta {
+      return beta;
+    }
+    if score > alpha {
+      alpha = score;
     }
   }
   alpha
@@ -151,6 +227,7 @@ pub fn quiescent_search(fen_str: &str, mut alpha:i64, beta:i64) -> i64 {
 #[cfg(test)]
 mod test {
   use super::*;
+  use crate::evaluate::Classic;
   const NAME: &str = "iterative deepening table2";
 
   // cargo test --release -- --nocapture checkmate_black_wins_in_1 
@@ -159,31 +236,31 @@ mod test {
   fn test_exec_time() {
     use std::time::Instant;
     let now = Instant::now();
-    let res = root("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 0, 1000,None);
-    println!("[{:?}] {:?}",NAME,res.mov);
+    let res = root("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 0, 1000,None, Box::new(Classic::default())).unwrap();
+    println!("[{:?}] {:?}",NAME,res.result.san);
     let elapsed = now.elapsed();
     println!("[{:?}] Elapsed: {:.2?}",NAME, elapsed);
 
     let now = Instant::now();
-    let res = root("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 0, 1000,Some(res.table));
-    println!("[{:?}] {:?}",NAME,res.mov);
+    let res = root("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 0, 1000,Some(res.table), Box::new(Classic::default())).unwrap();
+    println!("[{:?}] {:?}",NAME,res.result.san);
     let elapsed = now.elapsed();
     println!("[{:?}] Elapsed: {:.2?}",NAME, elapsed);
 
     let now = Instant::now();
-    let res = root("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 0, 1000,Some(res.table));
-    println!("[{:?}] {:?}",NAME,res.mov);
+    let res = root("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 0, 1000,Some(res.table), Box::new(Classic::default())).unwrap();
+    println!("[{:?}] {:?}",NAME,res.result.san);
     let elapsed = now.elapsed();
     println!("[{:?}] Elapsed: {:.2?}",NAME, elapsed);
   }
 
   #[test]
   fn checkmate_black_wins_in_1() {
-    assert_eq!(root("4k3/8/8/8/8/2r5/1q6/5K2 b - - 3 2", 1, 1000, None).mov, "Rc1");
+    assert_eq!(root("4k3/8/8/8/8/2r5/1q6/5K2 b - - 3 2", 1, 1000, None, Box::new(Classic::default())).unwrap().result.san, "Rc1");
   }
   #[test]
   fn checkmate_black_wins_in_2() {
-    let mov = root("4k3/8/8/8/1qr5/8/8/6K1 b - - 3 2", 1, 1000, None).mov;
+    let mov = root("4k3/8/8/8/1qr5/8/8/6K1 b - - 3 2", 1, 1000, None, Box::new(Classic::default())).unwrap().result.san;
     if !(mov == "Qb2" || mov == "Qd2" || mov == "Rc2") {
       panic!("{}", mov);
     }
@@ -191,14 +268,72 @@ mod test {
 
   #[test]
   fn checkmate_white_wins_in_1() {
-    assert_eq!(root("5k2/2R5/1Q6/8/8/8/8/4K3 w - - 3 3", 1, 1000, None).mov, "Qb8");
+    assert_eq!(root("5k2/2R5/1Q6/8/8/8/8/4K3 w - - 3 3", 1, 1000, None, Box::new(Classic::default())).unwrap().result.san, "Qb8");
   }
 
   #[test]
   fn checkmate_white_wins_in_2() {
-    let mov = root("5k2/8/1Q6/2R5/8/8/8/4K3 w - - 3 3", 1, 1000, None).mov;
+    let mov = root("5k2/8/1Q6/2R5/8/8/8/4K3 w - - 3 3", 1, 1000, None, Box::new(Classic::default())).unwrap().result.san;
     if !(mov == "Qb7" || mov == "Qa7" || mov == "Rc7") {
       panic!("{}", mov);
     }
   }
+
+  #[test]
+  fn stopped_before_first_iteration() {
+    let fen = "rnbqkbnr/pppp1ppp/8/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2";
+    let key = parse_fen(fen).unwrap().zobrist_hash();
+    let stopped = || {
+      let ctx = SearchContext::new();
+      ctx.stop.store(true, std::sync::atomic::Ordering::Relaxed);
+      ctx
+    };
+    // nothing is stored for the root, the iteration never finished
+    let mut table = HashTable::new();
+    let result = search(fen, 1, u64::MAX, &mut table, &mut stopped()).unwrap();
+    assert_eq!(result.pv.len(), 1);
+    assert!(table.find(key, 0).is_none());
+    // the move of a previous search is played instead of the first legal one
+    let mut ctx = SearchContext::new();
+    ctx.max_depth = 2;
+    let previous = search(fen, 1, u64::MAX, &mut table, &mut ctx).unwrap();
+    let entry = table.find(key, 0).unwrap();
+    let result = search(fen, 1, u64::MAX, &mut table, &mut stopped()).unwrap();
+    assert_eq!(result.uci, previous.uci);
+    assert_eq!(table.find(key, 0).unwrap().depth, entry.depth);
+  }
+
+  // nodes searched to depth with and without killer moves and history, with an empty table
+  fn nodes(fen: &str, depth: u64, heuristics: bool) -> u64 {
+    let mut ctx = SearchContext::new();
+    ctx.max_depth = depth;
+    ctx.heuristics.enabled = heuristics;
+    search(fen, 1, u64::MAX, &mut HashTable::new(), &mut ctx).unwrap().nodes
+  }
+
+  // cargo test --release -- --ignored --nocapture node_count_benchmark
+  #[test]
+  #[ignore]
+  fn node_count_benchmark() {
+    let fens = [
+      "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+      "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3",
+      "r1bq1rk1/pp2bppp/2n1pn2/3p4/2PP4/2N1PN2/PP3PPP/R2QKB1R w KQ - 0 8",
+      "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+    ];
+    let (mut total_without, mut total_with) = (0, 0);
+    for fen in fens {
+      let (without, with) = (nodes(fen, 5, false), nodes(fen, 5, true));
+      println!("[{:?}] {}: {} -> {} nodes ({:.0}%)", NAME, fen, without, with, 100.0 * with as f64 / without as f64);
+      total_without += without;
+      total_with += with;
+    }
+    println!("[{:?}] total: {} -> {} nodes ({:.0}%)", NAME, total_without, total_with, 100.0 * total_with as f64 / total_without as f64);
+  }
+
+  #[test]
+  fn killers_shrink_the_tree() {
+    let fen = "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3";
+    assert!(nodes(fen, 4, true) < nodes(fen, 4, false));
+  }
 }
@@ -1,85 +1,127 @@
 use std::vec;
 
 use shakmaty::*;//{fen::Fen, Board,Piece,Square, CastlingMode, Chess, Position, san::San};
+use shakmaty::zobrist::ZobristHash;
 
 use rand::{Rng, SeedableRng};
 use rand::rngs::SmallRng;
 
-use crate::evaluate;
+use crate::error::ChessError;
+use crate::evaluate::Evaluator;
+use crate::utils::{parse_fen, zobrist_after};
+use crate::search::{leaf_score, terminal_score, SearchContext, SearchResult};
 
-pub fn negamax_root(fen_str: &str, seed: u64, depth: u64) -> String {
-  let fen: fen::Fen = fen_str.parse().unwrap();
-  let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
+pub fn negamax_root(fen_str: &str, seed: u64, depth: u64, evaluator: Box<dyn Evaluator>) -> Result<SearchResult, ChessError> {
+  search(fen_str, seed, depth, &mut SearchContext::with_evaluator(evaluator))
+}
+
+// same as negamax_root, but the caller owns the context, to stop the search or get its progress
+pub fn search(fen_str: &str, seed: u64, depth: u64, ctx: &mut SearchContext) -> Result<SearchResult, ChessError> {
+  if depth == 0 {
+    return Err(ChessError::InvalidDepth(depth));
+  }
+  let pos = parse_fen(fen_str)?;
+  let key = pos.zobrist_hash();
+  ctx.keys.push(key);
   let legals = pos.legal_moves();
-  let mut best_moves:Vec<Move> = vec![];
+  if legals.is_empty() {
+    return Err(ChessError::NoLegalMoves(fen_str.to_string()));
+  }
+  // each best move is stored with the principal variation that follows it
+  let mut best_moves:Vec<Vec<Move>> = vec![];
   let mut best_score = -1_000_000;
   for legal in legals {
-    let new_pos = pos.clone().play(&legal);
-    if new_pos.is_ok() {
-      let new_fen = fen::Fen::from_position(new_pos.unwrap(), EnPassantMode::Legal).to_string();
-      let score = -negamax(new_fen.as_str(), seed, depth - 1);
-      // println!("{:?} -> {:?}", san::San::from_move(&pos,&legal).to_string(), score);
-      if score > best_score {
-        best_score = score;
-        best_moves = vec![legal];
-      } else if score == best_score {
-        best_moves.push(legal);
-      }
+    let mut new_pos = pos.clone();
+    new_pos.play_unchecked(&legal);
+    ctx.evaluator.push(&new_pos);
+    let new_key = zobrist_after(&pos, key, &legal, &new_pos);
+    let (mut score, mut pv) = negamax(&new_pos, new_key, seed, depth - 1, 1, ctx);
+    ctx.evaluator.pop();
+    score = -score;
+    // println!("{:?} -> {:?}", san::San::from_move(&pos,&legal).to_string(), score);
+    pv.push(legal);
+    if score > best_score {
+      best_score = score;
+      best_moves = vec![pv];
+    } else if score == best_score {
+      best_moves.push(pv);
     }
     
   }
   let mut rng = SmallRng::seed_from_u64(seed);
   let move_index = rng.gen_range(0..best_moves.len());
-  let san_move = san::San::from_move(&pos, &best_moves[move_index]);
-  return san_move.to_string();
-
+  // moves are collected from the leaf up, so the variation has to be reversed
+  let mut pv = best_moves.swap_remove(move_index);
+  pv.reverse();
+  Ok(SearchResult::new(&pos, &pv, best_score, depth, ctx))
 }
 
-fn negamax(fen_str: &str, seed:u64, depth: u64) -> i64 {
+// returns the score and the principal variation in reverse order
+fn negamax(pos: &Chess, key: u64, seed:u64, depth: u64, ply: u64, ctx: &mut SearchContext) -> (i64, Vec<Move>) {
+  // repetitions and the fifty-move rule end the line with a draw
+  if ctx.is_draw(pos, key) {
+    return (0, vec![]);
+  }
+  ctx.nodes += 1;
   if depth == 0 {
-    return evaluate::evaluate(fen_str);
+    return (leaf_score(pos, ply, ctx.evaluator.as_mut()), vec![]);
   }
-  let fen: fen::Fen = fen_str.parse().unwrap();
-  let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
   let legals = pos.legal_moves();
   let mut best_score = None;
+  let mut best_pv = vec![];
   let mut rng = SmallRng::seed_from_u64(seed);
   for legal in legals {
-    let new_pos = pos.clone().play(&legal);
-    if new_pos.is_ok() {
-      let new_fen = fen::Fen::from_position(new_pos.unwrap(), EnPassantMode::Legal).to_string();
-      let score = -negamax(new_fen.as_str(), rng.gen::<u64>(), depth - 1);
-      if best_score == None || score > best_score.unwrap() {
-        best_score = Some(score);
-      }
+    let mut new_pos = pos.clone();
+    new_pos.play_unchecked(&legal);
+    ctx.evaluator.push(&new_pos);
+    let new_key = zobrist_after(pos, key, &legal, &new_pos);
+    ctx.keys.push(key);
+    let (mut score, mut pv) = negamax(&new_pos, new_key, rng.gen::<u64>(), depth - 1, ply + 1, ctx);
+    ctx.evaluator.pop();
+    ctx.keys.pop();
+    score = -score;
+    if best_score.is_none() || score > best_score.unwrap() {
+      best_score = Some(score);
+      pv.push(legal);
+      best_pv = pv;
     }
   }
   // or allows us to return the evaluation of the position if no legal moves are available, adjusted with the depth so we aim for faster checkmate
-  best_score.unwrap_or(evaluate::evaluate(fen_str)-depth as i64)
+  match best_score {
+    Some(best_score) => (best_score, best_pv),
+    None => (terminal_score(pos, ply), best_pv),
+  }
   // best_score.unwrap_or(evaluate::evaluate(fen_str))
 }
 
 #[cfg(test)]
 mod test {
   use super::*;
+  use crate::evaluate::Classic;
 
   #[test]
   #[ignore]
   fn test_exec_time() {
     use std::time::Instant;
     let now = Instant::now();
-    println!("[negamax] {:?}",negamax_root("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 0, 4));
+    println!("[negamax] {:?}",negamax_root("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 0, 4, Box::new(Classic::default())).unwrap().san);
     let elapsed = now.elapsed();
     println!("[negamax] Elapsed: {:.2?}", elapsed);
   }
 
   #[test]
   fn checkmate_black_wins_in_1() {
-    assert_eq!(negamax_root("4k3/8/8/8/8/2r5/1q6/5K2 b - - 3 2", 1, 3), "Rc1");
+    assert_eq!(negamax_root("4k3/8/8/8/8/2r5/1q6/5K2 b - - 3 2", 1, 3, Box::new(Classic::default())).unwrap().san, "Rc1");
   }
+
+  #[test]
+  fn depth_0_is_an_error() {
+    assert_eq!(negamax_root("4k3/8/8/8/8/2r5/1q6/5K2 b - - 3 2", 1, 0, Box::new(Classic::default())).err().map(|err| err.code()), Some("INVALID_DEPTH"));
+  }
+
   #[test]
   fn checkmate_black_wins_in_2() {
-    let mov = negamax_root("4k3/8/8/8/1qr5/8/8/6K1 b - - 3 2", 1, 4);
+    let mov = negamax_root("4k3/8/8/8/1qr5/8/8/6K1 b - - 3 2", 1, 4, Box::new(Classic::default())).unwrap().san;
     if !(mov == "Qb2" || mov == "Qd2" || mov == "Rc2") {
       panic!("{}", mov);
     }
@@ -87,14 +129,24 @@ mod test {
 
   #[test]
   fn checkmate_white_wins_in_1() {
-    assert_eq!(negamax_root("5k2/2R5/1Q6/8/8/8/8/4K3 w - - 3 3", 1, 4), "Qb8");
+    assert_eq!(negamax_root("5k2/2R5/1Q6/8/8/8/8/4K3 w - - 3 3", 1, 4, Box::new(Classic::default())).unwrap().san, "Qb8");
   }
 
   #[test]
   fn checkmate_white_wins_in_2() {
-    let mov = negamax_root("5k2/8/1Q6/2R5/8/8/8/4K3 w - - 3 3", 1, 4);
+    let mov = negamax_root("5k2/8/1Q6/2R5/8/8/8/4K3 w - - 3 3", 1, 4, Box::new(Classic::default())).unwrap().san;
     if !(mov == "Qb7" || mov == "Qa7" || mov == "Rc7") {
       panic!("{}", mov);
     }
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn result_has_pv_and_mate_score() {
+    let result = negamax_root("5k2/8/1Q6/2R5/8/8/8/4K3 w - - 3 3", 1, 4, Box::new(Classic::default())).unwrap();
+    assert_eq!(result.pv.len(), 3);
+    assert_eq!(result.pv_san[0], result.san);
+    assert_eq!(result.score, crate::search::Score::Mate(2));
+    assert_eq!(result.depth, 4);
+    assert!(result.nodes > 0);
+  }
+}
@@ -1,109 +1,152 @@
 use std::vec;
 
 use shakmaty::*;//{fen::Fen, Board,Piece,Square, CastlingMode, Chess, Position, san::San};
+use shakmaty::zobrist::ZobristHash;
 
 use rand::{Rng, SeedableRng};
 use rand::rngs::SmallRng;
 
-use crate::evaluate;
+use crate::error::ChessError;
+use crate::evaluate::Evaluator;
+use crate::utils::{parse_fen, zobrist_after};
+use crate::search::{leaf_score, terminal_score, SearchContext, SearchResult};
 
-pub fn root(fen_str: &str, seed: u64, depth: u64) -> String {
-  let fen: fen::Fen = fen_str.parse().unwrap();
-  let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
+pub fn root(fen_str: &str, seed: u64, depth: u64, evaluator: Box<dyn Evaluator>) -> Result<SearchResult, ChessError> {
+  search(fen_str, seed, depth, &mut SearchContext::with_evaluator(evaluator))
+}
+
+// same as root, but the caller owns the context, to stop the search or get its progress
+pub fn search(fen_str: &str, seed: u64, depth: u64, ctx: &mut SearchContext) -> Result<SearchResult, ChessError> {
+  if depth == 0 {
+    return Err(ChessError::InvalidDepth(depth));
+  }
+  let pos = parse_fen(fen_str)?;
+  let key = pos.zobrist_hash();
+  ctx.keys.push(key);
   let legals = pos.legal_moves();
-  let mut best_moves:Vec<Move> = vec![];
+  if legals.is_empty() {
+    return Err(ChessError::NoLegalMoves(fen_str.to_string()));
+  }
+  // each best move is stored with the principal variation that follows it
+  let mut best_moves:Vec<Vec<Move>> = vec![];
   let mut alpha = -1_000_000;
   let beta = 1_000_000;
   for legal in legals {
-    let new_pos = pos.clone().play(&legal);
-    if new_pos.is_ok() {
-      let new_fen = fen::Fen::from_position(new_pos.unwrap(), EnPassantMode::Legal).to_string();
-      let score = -negamax_a_b(new_fen.as_str(), seed, depth - 1, -beta, -alpha);
-      // println!("{:?} -> {:?}", san::San::from_move(&pos,&legal).to_string(), score);
-      if score > beta {
-        return san::San::from_move(&pos,&legal).to_string();
-      } else if score == beta {
-        best_moves.push(legal);
-      } else if score > alpha {
-        alpha = score;
-        best_moves = vec![legal];
-      }
+    let mut new_pos = pos.clone();
+    new_pos.play_unchecked(&legal);
+    ctx.evaluator.push(&new_pos);
+    let new_key = zobrist_after(&pos, key, &legal, &new_pos);
+    let (mut score, mut pv) = negamax_a_b(&new_pos, new_key, seed, depth - 1, 1, -beta, -alpha, ctx);
+    ctx.evaluator.pop();
+    score = -score;
+    pv.push(legal);
+    pv.reverse();
+    // println!("{:?} -> {:?}", san::San::from_move(&pos,&legal).to_string(), score);
+    if score > beta {
+      return Ok(SearchResult::new(&pos, &pv, score, depth, ctx));
+    } else if score == beta {
+      best_moves.push(pv);
+    } else if score > alpha {
+      alpha = score;
+      best_moves = vec![pv];
     }
   }
   let mut rng = SmallRng::seed_from_u64(seed);
   let move_index = rng.gen_range(0..best_moves.len());
-  let san_move = san::San::from_move(&pos, &best_moves[move_index]);
-  return san_move.to_string();
-
+  Ok(SearchResult::new(&pos, &best_moves[move_index], alpha, depth, ctx))
 }
 
-fn negamax_a_b(fen_str: &str, seed:u64, depth: u64, mut alpha:i64, beta:i64) -> i64 {
+// returns the score and the principal variation in reverse order
+#[allow(clippy::too_many_arguments)]
+fn negamax_a_b(pos: &Chess, key: u64, seed:u64, depth: u64, ply: u64, mut alpha:i64, beta:i64, ctx: &mut SearchContext) -> (i64, Vec<Move>) {
+  // repetitions and the fifty-move rule end the line with a draw
+  if ctx.is_draw(pos, key) {
+    return (0, vec![]);
+  }
   if depth == 0 {
     // instead of just returning the score, we call quiescent search
     // return evaluate::evaluate(fen_str);
-    return quiescent_search(fen_str, alpha, beta);
+    return quiescent_search(pos, ply, alpha, beta, ctx);
   }
-  let fen: fen::Fen = fen_str.parse().unwrap();
-  let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
-  let legals = pos.legal_moves();
+  ctx.nodes += 1;
+  let mut legals = pos.legal_moves();
+  // captures by MVV-LVA, then the quiet moves that caused cutoffs in other lines
+  let heuristics = &ctx.heuristics;
+  legals.sort_by(|a, b| heuristics.ordering(a, b, pos.turn(), ply));
   let mut best_score = None;
+  let mut best_pv = vec![];
   let mut rng = SmallRng::seed_from_u64(seed);
   for legal in legals {
-    let new_pos = pos.clone().play(&legal);
-    if new_pos.is_ok() {
-      let new_fen = fen::Fen::from_position(new_pos.unwrap(), EnPassantMode::Legal).to_string();
-      let score = -negamax_a_b(new_fen.as_str(), rng.gen::<u64>(), depth - 1, -beta, -alpha);
-      if best_score == None || score > best_score.unwrap() {
-        best_score = Some(score);
-      }
-      if score >= beta {
-        return beta;
-      }
-      if score > alpha {
-        best_score = Some(score);
-        alpha = score;
-      }
+    let mut new_pos = pos.clone();
+    new_pos.play_unchecked(&legal);
+    ctx.evaluator.push(&new_pos);
+    let new_key = zobrist_after(pos, key, &legal, &new_pos);
+    ctx.keys.push(key);
+    let (mut score, mut pv) = negamax_a_b(&new_pos, new_key, rng.gen::<u64>(), depth - 1, ply + 1, -beta, -alpha, ctx);
+    ctx.evaluator.pop();
+    ctx.keys.pop();
+    score = -score;
+    if best_score.is_none() || score > best_score.unwrap() {
+      best_score = Some(score);
+      pv.push(legal.clone());
+      best_pv = pv;
+    }
+    if score >= beta {
+      ctx.heuristics.cutoff(pos.turn(), &legal, ply, depth);
+      return (beta, best_pv);
+    }
+    if score > alpha {
+      best_score = Some(score);
+      alpha = score;
     }
   }
   // or allows us to return the evaluation of the position if no legal moves are available, adjusted with the depth so we aim for faster checkmate
-  best_score.unwrap_or(evaluate::evaluate(fen_str)-depth as i64)
+  match best_score {
+    Some(best_score) => (best_score, best_pv),
+    None => (terminal_score(pos, ply), best_pv),
+  }
   // best_score.unwrap_or(evaluate::evaluate(fen_str))
 }
 
-pub fn quiescent_search(fen_str: &str, mut alpha:i64, beta:i64) -> i64 {
+// returns the score and the captures leading to the quiet position it comes from, in reverse order
+pub fn quiescent_search(pos: &Chess, ply: u64, mut alpha:i64, beta:i64, ctx: &mut SearchContext) -> (i64, Vec<Move>) {
   // https://www.chessprogramming.org/Quiescence_Search
-  let stand_pat = evaluate::evaluate(fen_str);
+  ctx.nodes += 1;
+  let stand_pat = leaf_score(pos, ply, ctx.evaluator.as_mut());
   if stand_pat >= beta {
-    return beta;
+    return (beta, vec![]);
   }
   if alpha < stand_pat {
     alpha = stand_pat;
   }
 
-  let fen: fen::Fen = fen_str.parse().unwrap();
-  let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
   let capture_moves = pos.capture_moves();
+  let mut best_pv = vec![];
   
   for capture in capture_moves {
-    let new_pos = pos.clone().play(&capture);
-    if new_pos.is_ok() {
-      let new_fen = fen::Fen::from_position(new_pos.unwrap(), EnPassantMode::Legal).to_string();
-      let score = -quiescent_search(new_fen.as_str(), -beta, -alpha);
-      if score >= beta {
-        return beta;
-      }
-      if score > alpha {
-        alpha = score;
-      }
+    let mut new_pos = pos.clone();
+    new_pos.play_unchecked(&capture);
+    ctx.evaluator.push(&new_pos);
+    let (score, mut pv) = quiescent_search(&new_pos, ply + 1, -beta, -alpha, ctx);
+    ctx.evaluator.pop();
+    let score = -score;
+    if score >= beta {
+      return (beta, vec![]);
+    }
+    if score > alpha {
+      alpha = score;
+      pv.push(capture);
+      best_pv = pv;
     }
   }
-  alpha
+  (alpha, best_pv)
 }
 
 
 #[cfg(test)]
 mod test {
   use super::*;
+  use crate::evaluate::Classic;
   const NAME: &str = "negamax_a_b_quiescent";
 
   // cargo test --release -- --nocapture checkmate_black_wins_in_1 
@@ -112,18 +155,24 @@ mod test {
   fn test_exec_time() {
     use std::time::Instant;
     let now = Instant::now();
-    println!("[{:?}] {:?}",NAME,root("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 0, 5));
+    println!("[{:?}] {:?}",NAME,root("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 0, 5, Box::new(Classic::default())).unwrap().san);
     let elapsed = now.elapsed();
     println!("[{:?}] Elapsed: {:.2?}",NAME, elapsed);
   }
 
   #[test]
   fn checkmate_black_wins_in_1() {
-    assert_eq!(root("4k3/8/8/8/8/2r5/1q6/5K2 b - - 3 2", 1, 3), "Rc1");
+    assert_eq!(root("4k3/8/8/8/8/2r5/1q6/5K2 b - - 3 2", 1, 3, Box::new(Classic::default())).unwrap().san, "Rc1");
   }
+
+  #[test]
+  fn depth_0_is_an_error() {
+    assert_eq!(root("4k3/8/8/8/8/2r5/1q6/5K2 b - - 3 2", 1, 0, Box::new(Classic::default())).err().map(|err| err.code()), Some("INVALID_DEPTH"));
+  }
+
   #[test]
   fn checkmate_black_wins_in_2() {
-    let mov = root("4k3/8/8/8/1qr5/8/8/6K1 b - - 3 2", 1, 4);
+    let mov = root("4k3/8/8/8/1qr5/8/8/6K1 b - - 3 2", 1, 4, Box::new(Classic::default())).unwrap().san;
     if !(mov == "Qb2" || mov == "Qd2" || mov == "Rc2") {
       panic!("{}", mov);
     }
@@ -131,12 +180,12 @@ mod test {
 
   #[test]
   fn checkmate_white_wins_in_1() {
-    assert_eq!(root("5k2/2R5/1Q6/8/8/8/8/4K3 w - - 3 3", 1, 4), "Qb8");
+    assert_eq!(root("5k2/2R5/1Q6/8/8/8/8/4K3 w - - 3 3", 1, 4, Box::new(Classic::default())).unwrap().san, "Qb8");
   }
 
   #[test]
   fn checkmate_white_wins_in_2() {
-    let mov = root("5k2/8/1Q6/2R5/8/8/8/4K3 w - - 3 3", 1, 4);
+    let mov = root("5k2/8/1Q6/2R5/8/8/8/4K3 w - - 3 3", 1, 4, Box::new(Classic::default())).unwrap().san;
     if !(mov == "Qb7" || mov == "Qa7" || mov == "Rc7") {
       panic!("{}", mov);
     }
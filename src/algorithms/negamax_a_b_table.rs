@@ -1,120 +1,162 @@
 use std::vec;
 
 use shakmaty::*;//{fen::Fen, Board,Piece,Square, CastlingMode, Chess, Position, san::San};
+use shakmaty::zobrist::ZobristHash;
 
 use rand::{Rng, SeedableRng};
 use rand::rngs::SmallRng;
 
-use crate::evaluate;
-use crate::MoveAndTable;
+use crate::error::ChessError;
+use crate::evaluate::Evaluator;
+use crate::ordering;
+use crate::utils::{parse_fen, zobrist_after};
+use crate::utils::MoveAndTable;
+use crate::search::{leaf_score, terminal_score, SearchContext, SearchResult};
 extern crate web_sys;
 
-use super::hashtable::{HashTable, PositionInfo};
+use super::hashtable::{encode_move, Bound, HashTable, PositionInfo};
 
-pub fn root(fen_str: &str, seed: u64, depth: u64, table: Option<HashTable>) -> MoveAndTable {
+pub fn root(fen_str: &str, seed: u64, depth: u64, table: Option<HashTable>, evaluator: Box<dyn Evaluator>) -> Result<MoveAndTable, ChessError> {
   /* let string = format!("[Rust-negamax_a_b_table] {}", if table.is_none() { "None" } else { "Some" });
   web_sys::console::log_1(&string.into()); */
-  let mut table = table.unwrap_or_else(|| HashTable::new());
+  let mut table = table.unwrap_or_default();
+  let result = search(fen_str, seed, depth, &mut table, &mut SearchContext::with_evaluator(evaluator))?;
+  Ok(MoveAndTable::new(result, table))
+}
+
+// same as root, but works on a table owned by the caller so it can be kept between moves,
+// the context can hold the history of the game, to detect repetitions
+pub fn search(fen_str: &str, seed: u64, depth: u64, table: &mut HashTable, ctx: &mut SearchContext) -> Result<SearchResult, ChessError> {
+  if depth == 0 {
+    return Err(ChessError::InvalidDepth(depth));
+  }
+  let pos = parse_fen(fen_str)?;
+  let key = pos.zobrist_hash();
+  ctx.keys.push(key);
+  table.new_search();
+  let mut legals = pos.legal_moves();
+  if legals.is_empty() {
+    return Err(ChessError::NoLegalMoves(fen_str.to_string()));
+  }
 
-  // if fen_str is already in the table with enough depth, return already computed move with the table
-  let table_entry = table.find(&fen_str, depth);
-  if let Some(entry) = table_entry {
-    let res = MoveAndTable::new(entry.mov.to_string(), table);
-    return res;
+  // the root is always searched, a stored score could come from another game history or evaluator,
+  // the stored move is searched first
+  if let Some(mov) = table.find(key, 0).and_then(|entry| entry.mov) {
+    ordering::best_move_first(&mut legals, mov);
   }
 
-  let fen: fen::Fen = fen_str.parse().unwrap();
-  let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
-  let legals = pos.legal_moves();
-  let mut best_moves:Vec<Move> = vec![];
+  // each best move is stored with the principal variation that follows it
+  let mut best_moves:Vec<Vec<Move>> = vec![];
   let mut alpha = -1_000_000;
   let beta = 1_000_000;
+  let draws = ctx.draws;
   
   for legal in legals {
-    let new_pos = pos.clone().play(&legal);
-    if new_pos.is_ok() {
-      let new_fen = fen::Fen::from_position(new_pos.unwrap(), EnPassantMode::Legal).to_string();
-      let table_entry = table.find(&new_fen, depth);
-      /* let string = format!("[Rust-negamax_a_b_table] Entry: {}", if table_entry.is_none() { "None" } else { "Some" });
-      web_sys::console::log_1(&string.into()); */
-      // useless
-      let score = if table_entry.is_some() {
-        table_entry.unwrap().score
-      } else {
-        let score = -negamax_a_b_table(new_fen.as_str(), seed, depth - 1, -beta, -alpha, &mut table);
-        let san_move = san::San::from_move(&pos, &legal).to_string();
-        let position_info = PositionInfo::new(depth, score, san_move);
-        table.insert(&new_fen, position_info);
-        score
-      };
-
-
-      /* let score = -negamax_a_b_table(new_fen.as_str(), seed, depth - 1, -beta, -alpha, &mut table);
-      table.insert(&new_fen, PositionInfo::new(depth, score, legal.to_string())); */
-      // println!("{:?} -> {:?}", san::San::from_move(&pos,&legal).to_string(), score);
-      if score >= beta {
-        // update table entry for starting position
-        let san_move = san::San::from_move(&pos, &legal);
-        let position_info = PositionInfo::new(depth, score, san_move.to_string());
-        table.insert(&fen_str, position_info);
-        // return move and table
-        let res = MoveAndTable::new(san::San::from_move(&pos,&legal).to_string(), table);
-        return res;
-      }
-      if score > alpha {
-        alpha = score;
-        best_moves = vec![legal];
+    let mut new_pos = pos.clone();
+    new_pos.play_unchecked(&legal);
+    ctx.evaluator.push(&new_pos);
+    let new_key = zobrist_after(&pos, key, &legal, &new_pos);
+    let (score, mut pv) = negamax_a_b_table(&new_pos, new_key, seed, depth - 1, 1, -beta, -alpha, table, ctx);
+    ctx.evaluator.pop();
+    let score = -score;
+    pv.push(legal);
+    pv.reverse();
+
+    // println!("{:?} -> {:?}", san::San::from_move(&pos,&legal).to_string(), score);
+    if score >= beta {
+      // update table entry for starting position
+      if ctx.draws == draws {
+        let position_info = PositionInfo::new(depth, score, Bound::Lower, Some(encode_move(&pv[0])));
+        table.insert(key, position_info, 0);
       }
+      return Ok(SearchResult::new(&pos, &pv, score, depth, ctx));
+    }
+    if score > alpha {
+      alpha = score;
+      best_moves = vec![pv];
     }
   }
   let mut rng = SmallRng::seed_from_u64(seed);
   let move_index = rng.gen_range(0..best_moves.len());
-  let san_move = san::San::from_move(&pos, &best_moves[move_index]);
   // update table entry for starting position
-  let position_info = PositionInfo::new(depth, alpha, san_move.to_string());
-  table.insert(&fen_str, position_info);
-  // return move and table
-  let res = MoveAndTable::new(san_move.to_string(), table);
-  return res;
+  if ctx.draws == draws {
+    let position_info = PositionInfo::new(depth, alpha, Bound::Exact, Some(encode_move(&best_moves[move_index][0])));
+    table.insert(key, position_info, 0);
+  }
+  Ok(SearchResult::new(&pos, &best_moves[move_index], alpha, depth, ctx))
 }
 
-fn negamax_a_b_table(fen_str: &str, seed:u64, depth: u64, mut alpha:i64, beta:i64, table: &mut HashTable) -> i64 {
+// returns the score and the principal variation in reverse order, key is the zobrist hash of pos
+#[allow(clippy::too_many_arguments)]
+fn negamax_a_b_table(pos: &Chess, key: u64, seed:u64, depth: u64, ply: u64, mut alpha:i64, beta:i64, table: &mut HashTable, ctx: &mut SearchContext) -> (i64, Vec<Move>) {
+  // repetitions and the fifty-move rule end the line with a draw, checked before the table so
+  // a draw node is never probed or stored, its ancestors are not stored either
+  if ctx.is_draw(pos, key) {
+    ctx.draws += 1;
+    return (0, vec![]);
+  }
+  let draws = ctx.draws;
+  ctx.nodes += 1;
+  let table_entry = table.find(key, ply);
+  if let Some(score) = table_entry.as_ref().and_then(|entry| entry.cutoff(depth, alpha, beta)) {
+    return (score, vec![]);
+  }
   if depth == 0 {
-    return evaluate::evaluate(fen_str);
+    return (leaf_score(pos, ply, ctx.evaluator.as_mut()), vec![]);
+  }
+  // the stored best move first, then captures by MVV-LVA and the quiet moves that caused cutoffs in other lines
+  let mut legals = pos.legal_moves();
+  let heuristics = &ctx.heuristics;
+  legals.sort_by(|a, b| heuristics.ordering(a, b, pos.turn(), ply));
+  if let Some(mov) = table_entry.and_then(|entry| entry.mov) {
+    ordering::best_move_first(&mut legals, mov);
   }
-  let fen: fen::Fen = fen_str.parse().unwrap();
-  let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
-  let legals = pos.legal_moves();
+  let original_alpha = alpha;
   let mut best_score = None;
+  let mut best_pv = vec![];
   let mut rng = SmallRng::seed_from_u64(seed);
   for legal in legals {
-    let new_pos = pos.clone().play(&legal);
-    if new_pos.is_ok() {
-      let new_fen = fen::Fen::from_position(new_pos.unwrap(), EnPassantMode::Legal).to_string();
-      let score = -negamax_a_b_table(new_fen.as_str(), rng.gen::<u64>(), depth - 1, -beta, -alpha, table);
-      let san_move = san::San::from_move(&pos, &legal).to_string();
-      table.insert(&new_fen, PositionInfo::new(depth, score, san_move.to_string()));
-      if best_score == None || score > best_score.unwrap() {
-        best_score = Some(score);
-      }
-      if score >= beta {
-        return beta;
-      }
-      if score > alpha {
-        best_score = Some(score);
-        alpha = score;
+    let mut new_pos = pos.clone();
+    new_pos.play_unchecked(&legal);
+    ctx.evaluator.push(&new_pos);
+    let new_key = zobrist_after(pos, key, &legal, &new_pos);
+    ctx.keys.push(key);
+    let (mut score, mut pv) = negamax_a_b_table(&new_pos, new_key, rng.gen::<u64>(), depth - 1, ply + 1, -beta, -alpha, table, ctx);
+    ctx.evaluator.pop();
+    ctx.keys.pop();
+    score = -score;
+    if best_score.is_none() || score > best_score.unwrap() {
+      best_score = Some(score);
+      pv.push(legal.clone());
+      best_pv = pv;
+    }
+    if score >= beta {
+      ctx.heuristics.cutoff(pos.turn(), &legal, ply, depth);
+      if ctx.draws == draws {
+        table.insert(key, PositionInfo::new(depth, beta, Bound::Lower, best_pv.last().map(encode_move)), ply);
       }
+      return (beta, best_pv);
+    }
+    if score > alpha {
+      best_score = Some(score);
+      alpha = score;
     }
   }
   // or allows us to return the evaluation of the position if no legal moves are available, adjusted with the depth so we aim for faster checkmate
-  let value = evaluate::evaluate(fen_str)-depth as i64;
-  best_score.unwrap_or(value)
+  let best_score = best_score.unwrap_or_else(|| terminal_score(pos, ply));
+  // the score is exact only if some move raised alpha, otherwise it's just an upper bound
+  let bound = if best_score > original_alpha { Bound::Exact } else { Bound::Upper };
+  if ctx.draws == draws {
+    table.insert(key, PositionInfo::new(depth, best_score, bound, best_pv.last().map(encode_move)), ply);
+  }
+  (best_score, best_pv)
   // best_score.unwrap_or(evaluate::evaluate(fen_str))
 }
 
 #[cfg(test)]
 mod test {
   use super::*;
+  use crate::evaluate::Classic;
 
   // cargo test --release -- --nocapture checkmate_black_wins_in_1 
   #[test]
@@ -122,18 +164,24 @@ mod test {
   fn test_exec_time() {
     use std::time::Instant;
     let now = Instant::now();
-    println!("[negamax_a_b] {:?}",root("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 0, 5, None).mov);
+    println!("[negamax_a_b] {:?}",root("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 0, 5, None, Box::new(Classic::default())).unwrap().result.san);
     let elapsed = now.elapsed();
     println!("[negamax_a_b] Elapsed: {:.2?}", elapsed);
   }
 
   #[test]
   fn checkmate_black_wins_in_1() {
-    assert_eq!(root("4k3/8/8/8/8/2r5/1q6/5K2 b - - 3 2", 1, 3, None).mov, "Rc1");
+    assert_eq!(root("4k3/8/8/8/8/2r5/1q6/5K2 b - - 3 2", 1, 3, None, Box::new(Classic::default())).unwrap().result.san, "Rc1");
+  }
+
+  #[test]
+  fn depth_0_is_an_error() {
+    assert_eq!(root("4k3/8/8/8/8/2r5/1q6/5K2 b - - 3 2", 1, 0, None, Box::new(Classic::default())).err().map(|err| err.code()), Some("INVALID_DEPTH"));
   }
+
   #[test]
   fn checkmate_black_wins_in_2() {
-    let mov = root("4k3/8/8/8/1qr5/8/8/6K1 b - - 3 2", 1, 4, None).mov;
+    let mov = root("4k3/8/8/8/1qr5/8/8/6K1 b - - 3 2", 1, 4, None, Box::new(Classic::default())).unwrap().result.san;
     if !(mov == "Qb2" || mov == "Qd2" || mov == "Rc2") {
       panic!("{}", mov);
     }
@@ -141,14 +189,74 @@ mod test {
 
   #[test]
   fn checkmate_white_wins_in_1() {
-    assert_eq!(root("5k2/2R5/1Q6/8/8/8/8/4K3 w - - 3 3", 1, 4, None).mov, "Qb8");
+    assert_eq!(root("5k2/2R5/1Q6/8/8/8/8/4K3 w - - 3 3", 1, 4, None, Box::new(Classic::default())).unwrap().result.san, "Qb8");
   }
 
   #[test]
   fn checkmate_white_wins_in_2() {
-    let mov = root("5k2/8/1Q6/2R5/8/8/8/4K3 w - - 3 3", 1, 4, None).mov;
+    let mov = root("5k2/8/1Q6/2R5/8/8/8/4K3 w - - 3 3", 1, 4, None, Box::new(Classic::default())).unwrap().result.san;
     if !(mov == "Qb7" || mov == "Qa7" || mov == "Rc7") {
       panic!("{}", mov);
     }
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn table_scores_match_plain_alpha_beta() {
+    // scores stored by shallower searches with other windows must not change the result
+    let fens = [
+      "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+      "rnbqkbnr/pppp1ppp/8/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2",
+    ];
+    for fen in fens {
+      let mut table = HashTable::new();
+      search(fen, 1, 2, &mut table, &mut SearchContext::new()).unwrap();
+      let with_table = search(fen, 1, 3, &mut table, &mut SearchContext::new()).unwrap();
+      let plain = crate::algorithms::negamax_a_b::negamax_a_b_root(fen, 1, 3, Box::new(Classic::default())).unwrap();
+      assert_eq!(with_table.score, plain.score);
+    }
+  }
+
+  #[test]
+  fn fastest_mate_preferred() {
+    // slower mates are found too at this depth, but the mate in 1 must win
+    let result = root("5k2/2R5/1Q6/8/8/8/8/4K3 w - - 3 3", 1, 4, None, Box::new(Classic::default())).unwrap().result;
+    assert_eq!(result.san, "Qb8");
+    assert_eq!(result.score, crate::search::Score::Mate(1));
+  }
+
+  #[test]
+  fn repetition_saves_lost_position() {
+    use shakmaty::zobrist::ZobristHash;
+    // black is a queen down, but Kg8 repeats a position for the third time
+    let mut pos = parse_fen("7k/8/8/8/8/8/8/3QK3 b - - 0 1").unwrap();
+    let mut history = vec![];
+    for uci in ["h8g8", "d1d2", "g8h8", "d2d1", "h8g8", "d1d2", "g8h8", "d2d1"] {
+      history.push(pos.zobrist_hash::<u64>());
+      let mov = uci.parse::<uci::Uci>().unwrap().to_move(&pos).unwrap();
+      pos.play_unchecked(&mov);
+    }
+    let fen = "7k/8/8/8/8/8/8/3QK3 b - - 8 5";
+    let result = search(fen, 1, 3, &mut HashTable::new(), &mut SearchContext::with_history(&history)).unwrap();
+    assert_eq!(result.san, "Kg8");
+    assert_eq!(result.score, crate::search::Score::Cp(0));
+    // without the history it's just lost
+    assert_ne!(search(fen, 1, 3, &mut HashTable::new(), &mut SearchContext::new()).unwrap().score, crate::search::Score::Cp(0));
+    // and the root entry of that search doesn't hide the repetition from a search that knows the history
+    let mut table = HashTable::new();
+    search(fen, 1, 3, &mut table, &mut SearchContext::new()).unwrap();
+    let result = search(fen, 1, 3, &mut table, &mut SearchContext::with_history(&history)).unwrap();
+    assert_eq!(result.san, "Kg8");
+    assert_eq!(result.score, crate::search::Score::Cp(0));
+  }
+
+  #[test]
+  fn root_searched_again() {
+    let fen = "rnbqkbnr/pppp1ppp/8/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2";
+    let mut table = HashTable::new();
+    let first = search(fen, 1, 3, &mut table, &mut SearchContext::new()).unwrap();
+    let second = search(fen, 1, 3, &mut table, &mut SearchContext::new()).unwrap();
+    assert!(second.nodes > 0);
+    assert_eq!(second.uci, first.uci);
+    assert_eq!(second.score, first.score);
+  }
+}
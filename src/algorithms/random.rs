@@ -1,18 +1,52 @@
-use shakmaty::{fen::Fen, CastlingMode, Chess, Position, san::San};
+use shakmaty::Position;
 
 use rand::{Rng, SeedableRng};
 use rand::rngs::SmallRng;
 
-pub fn random_move(fen_str: &str, seed: u64) -> String {
+use crate::error::ChessError;
+use crate::evaluate::Evaluator;
+use crate::search::{leaf_score, SearchContext, SearchResult};
+use crate::utils::parse_fen;
+
+pub fn random_move(fen_str: &str, seed: u64, evaluator: Box<dyn Evaluator>) -> Result<SearchResult, ChessError> {
+  search(fen_str, seed, &mut SearchContext::with_evaluator(evaluator))
+}
+
+// same as random_move, but the caller owns the context and its evaluator
+pub fn search(fen_str: &str, seed: u64, ctx: &mut SearchContext) -> Result<SearchResult, ChessError> {
   let mut rng = SmallRng::seed_from_u64(seed);
 
   let rand_num: f64 = rng.gen();
 
-  let fen: Fen = fen_str.parse().unwrap();
-  let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
+  let pos = parse_fen(fen_str)?;
   let legals = pos.legal_moves();
+  if legals.is_empty() {
+    return Err(ChessError::NoLegalMoves(fen_str.to_string()));
+  }
   let mov = &legals[(rand_num * legals.len() as f64) as usize];
-  let san_move = San::from_move(&pos, &mov);
   // alert(san_move.to_string().as_str());
-  return san_move.to_string();
-}
\ No newline at end of file
+  // no search here, the score is just the evaluation of the position after the move
+  let mut new_pos = pos.clone();
+  new_pos.play_unchecked(mov);
+  let score = -leaf_score(&new_pos, 1, ctx.evaluator.as_mut());
+  Ok(SearchResult::new(&pos, std::slice::from_ref(mov), score, 0, ctx))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::evaluate::Classic;
+
+  #[test]
+  fn errors_instead_of_panics() {
+    assert_eq!(random_move("not a fen", 0, Box::new(Classic::default())).err(), Some(ChessError::InvalidFen("not a fen".to_string())));
+    let checkmate = "1Q2k3/2R5/8/8/8/8/8/4K3 b - - 1 1";
+    assert_eq!(random_move(checkmate, 0, Box::new(Classic::default())).err(), Some(ChessError::NoLegalMoves(checkmate.to_string())));
+  }
+
+  #[test]
+  fn random_legal_move() {
+    let result = random_move("4k3/8/8/8/8/8/8/4K3 w - - 0 1", 3, Box::new(Classic::default())).unwrap();
+    assert!(["Kd1", "Kf1", "Kd2", "Ke2", "Kf2"].contains(&result.san.as_str()));
+  }
+}
@@ -0,0 +1,42 @@
+// perft from the command line, prints the count after each move and the total like other engines do
+// cargo run --release --bin perft -- <depth> [fen|startpos]
+
+use std::env;
+use std::process;
+use std::time::Instant;
+
+use wasm_chess_algorithms::perft::divide;
+use wasm_chess_algorithms::STARTING_FEN;
+
+fn main() {
+  let args: Vec<String> = env::args().skip(1).collect();
+  let depth = match args.first().and_then(|depth| depth.parse::<u64>().ok()) {
+    Some(depth) => depth,
+    None => {
+      eprintln!("usage: perft <depth> [fen|startpos]");
+      process::exit(2);
+    },
+  };
+  let fen = match args[1..].join(" ").as_str() {
+    "" | "startpos" => STARTING_FEN.to_string(),
+    fen => fen.to_string(),
+  };
+
+  let start = Instant::now();
+  let counts = match divide(&fen, depth) {
+    Ok(counts) => counts,
+    Err(err) => {
+      eprintln!("{}", err);
+      process::exit(1);
+    },
+  };
+  let elapsed = start.elapsed();
+  for (mov, count) in &counts {
+    println!("{}: {}", mov, count);
+  }
+  // divide has nothing to split at depth 0, the position itself is the only node
+  let total: u64 = if depth == 0 { 1 } else { counts.values().sum() };
+  println!();
+  println!("Nodes searched: {}", total);
+  println!("Time: {} ms, {:.0} nodes/s", elapsed.as_millis(), total as f64 / elapsed.as_secs_f64().max(1e-9));
+}
@@ -0,0 +1,58 @@
+// texel tuning of the evaluation weights from positions labeled with the result of their game,
+// the weights are written to output as JSON after every pass, ready for set_eval_params
+// cargo run --release --bin tune -- <positions.epd> <output.json> [passes] [start.json]
+
+use std::env;
+use std::fs;
+use std::process;
+use std::time::Instant;
+
+use wasm_chess_algorithms::tune::{best_k, error, load_epd, quiet_positions, tune, weights};
+use wasm_chess_algorithms::EvalParams;
+
+const DEFAULT_PASSES: usize = 100;
+
+fn fail(message: String) -> ! {
+  eprintln!("{}", message);
+  process::exit(1);
+}
+
+fn main() {
+  let args: Vec<String> = env::args().skip(1).collect();
+  if args.len() < 2 {
+    eprintln!("usage: tune <positions.epd> <output.json> [passes] [start.json]");
+    process::exit(2);
+  }
+  let output = &args[1];
+  let passes = match args.get(2).map(|passes| passes.parse::<usize>()) {
+    Some(Ok(passes)) => passes,
+    Some(Err(_)) => fail(format!("invalid number of passes {}", args[2])),
+    None => DEFAULT_PASSES,
+  };
+  // the defaults, unless the tuning starts from the weights of a previous run
+  let params = match args.get(3) {
+    Some(path) => {
+      let json = fs::read_to_string(path).unwrap_or_else(|err| fail(format!("{}: {}", path, err)));
+      EvalParams::from_json(&json).unwrap_or_else(|err| fail(format!("{}: {}", path, err)))
+    },
+    None => EvalParams::default(),
+  };
+
+  let text = fs::read_to_string(&args[0]).unwrap_or_else(|err| fail(format!("{}: {}", args[0], err)));
+  let samples = load_epd(&text).unwrap_or_else(|err| fail(format!("{}: {}", args[0], err)));
+  if samples.is_empty() {
+    fail(format!("{}: no positions", args[0]));
+  }
+  let samples = quiet_positions(samples, &params);
+  let k = best_k(&samples, &params);
+  println!("{} positions, {} weights, K = {:.3}, error {:.6}", samples.len(), weights(&params).len(), k, error(&samples, &params, k));
+
+  let start = Instant::now();
+  tune(&samples, &params, k, passes, |pass, error, params| {
+    if let Err(err) = fs::write(output, params.to_json()) {
+      fail(format!("{}: {}", output, err));
+    }
+    println!("pass {}: error {:.6}, {} s", pass, error, start.elapsed().as_secs());
+  });
+  println!("weights written to {}", output);
+}
@@ -0,0 +1,364 @@
+// UCI frontend for the search algorithms, to play the bot in chess GUIs like Cute Chess or Arena
+// cargo build --release --bin uci
+
+use std::io::{self, BufRead};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use shakmaty::{Color, Position};
+
+use wasm_chess_algorithms::{evaluator_from_name, load_network_file, network, time_for_move, unload_network, Algorithm, ChessError, Game, HashTable, SearchContext, SearchResult, DEFAULT_EVALUATOR, EVALUATORS, MAX_SIZE_MB, MOVE_OVERHEAD};
+
+const NAME: &str = "wasm-chess-algorithms";
+const AUTHOR: &str = "Francesco Torgano";
+// depth used by the fixed depth algorithms when go doesn't give one
+const DEFAULT_DEPTH: u64 = 4;
+
+// limits of a search, as sent with the go command
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Go {
+  depth: Option<u64>,
+  movetime: Option<u64>,
+  wtime: Option<u64>,
+  btime: Option<u64>,
+  winc: Option<u64>,
+  binc: Option<u64>,
+  movestogo: Option<u64>,
+  infinite: bool,
+}
+impl Go {
+  fn parse(args: &[&str]) -> Go {
+    let mut go = Go::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+      let mut value = || args.next().and_then(|value| value.parse::<u64>().ok());
+      match *arg {
+        "depth" => go.depth = value(),
+        "movetime" => go.movetime = value(),
+        "wtime" => go.wtime = value(),
+        "btime" => go.btime = value(),
+        "winc" => go.winc = value(),
+        "binc" => go.binc = value(),
+        "movestogo" => go.movestogo = value(),
+        "infinite" => go.infinite = true,
+        _ => {},
+      }
+    }
+    go
+  }
+
+  // milliseconds the timed searches can use, without any limit the search goes on until stop
+  fn max_time(&self, turn: Color) -> u64 {
+    let (time, inc) = match turn {
+      Color::White => (self.wtime, self.winc),
+      Color::Black => (self.btime, self.binc),
+    };
+    if self.infinite {
+      u64::MAX
+    } else if let Some(movetime) = self.movetime {
+      movetime.saturating_sub(MOVE_OVERHEAD).max(1)
+    } else if let Some(time) = time {
+      time_for_move(time, inc.unwrap_or(0), self.movestogo)
+    } else {
+      u64::MAX
+    }
+  }
+
+  // without limits the search has to wait for stop before sending its move
+  fn waits_for_stop(&self) -> bool {
+    self.infinite || (self.depth.is_none() && self.movetime.is_none() && self.wtime.is_none() && self.btime.is_none())
+  }
+}
+
+// position startpos|fen <fen> [moves <moves>...]
+fn parse_position(args: &[&str]) -> Result<Game, ChessError> {
+  let moves_index = args.iter().position(|arg| *arg == "moves").unwrap_or(args.len());
+  let moves = args.get(moves_index + 1..).unwrap_or(&[]);
+  let start = match args.first() {
+    Some(&"fen") => args[1..moves_index].join(" "),
+    _ => "startpos".to_string(),
+  };
+  Game::from_moves(&start, moves)
+}
+
+// setoption name <name> [value <value>], both can contain spaces
+fn parse_option(args: &[&str]) -> (String, String) {
+  let value_index = args.iter().position(|arg| *arg == "value").unwrap_or(args.len());
+  let name = args.get(1..value_index).unwrap_or(&[]).join(" ");
+  let value = args.get(value_index + 1..).unwrap_or(&[]).join(" ");
+  (name, value)
+}
+
+// the fixed depth algorithms search to depth whatever the clock says and can't be stopped,
+// under time control the GUI is told so, random is the only one that is always fast
+fn fixed_depth_warning(algorithm: Algorithm, go: &Go, depth: u64) -> Option<String> {
+  let limited = go.infinite || go.movetime.is_some() || go.wtime.is_some() || go.btime.is_some();
+  if algorithm.is_timed() || algorithm == Algorithm::Random || !limited {
+    return None;
+  }
+  Some(format!("info string {} searches to depth {} and ignores the time limits and stop", algorithm.name(), depth))
+}
+
+fn info_line(result: &SearchResult) -> String {
+  let nps = result.nodes * 1000 / result.time.max(1);
+  format!(
+    "info depth {} score {} nodes {} nps {} time {} pv {}",
+    result.depth, result.score, result.nodes, nps, result.time, result.pv.join(" ")
+  )
+}
+
+struct Uci {
+  game: Game,
+  algorithm: Algorithm,
+  evaluator: String,
+  table: Arc<Mutex<HashTable>>,
+  stop: Arc<AtomicBool>,
+  search: Option<JoinHandle<()>>,
+  seed: u64,
+}
+impl Uci {
+  fn new() -> Uci {
+    Uci {
+      game: Game::from_moves::<&str>("startpos", &[]).unwrap(),
+      algorithm: Algorithm::IterativeDeepeningTable,
+      evaluator: DEFAULT_EVALUATOR.to_string(),
+      table: Arc::new(Mutex::new(HashTable::new())),
+      stop: Arc::new(AtomicBool::new(false)),
+      search: None,
+      seed: 0,
+    }
+  }
+
+  // returns false once the GUI wants to quit
+  fn command(&mut self, line: &str) -> bool {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    match args.first() {
+      Some(&"uci") => {
+        println!("id name {}", NAME);
+        println!("id author {}", AUTHOR);
+        println!("option name Hash type spin default {} min 1 max {}", self.table.lock().unwrap().size_mb(), MAX_SIZE_MB);
+        let names: Vec<String> = Algorithm::ALL.iter().map(|algorithm| format!("var {}", algorithm.name())).collect();
+        println!("option name Algorithm type combo default {} {}", self.algorithm.name(), names.join(" "));
+        let names: Vec<String> = EVALUATORS.iter().map(|name| format!("var {}", name)).collect();
+        println!("option name Evaluator type combo default {} {}", self.evaluator, names.join(" "));
+        println!("option name EvalFile type string default <empty>");
+        println!("uciok");
+      },
+      Some(&"isready") => println!("readyok"),
+      Some(&"ucinewgame") => {
+        self.wait();
+        self.table.lock().unwrap().clear();
+        self.game = Game::from_moves::<&str>("startpos", &[]).unwrap();
+      },
+      Some(&"setoption") => {
+        self.wait();
+        self.set_option(&args[1..]);
+      },
+      Some(&"position") => {
+        self.wait();
+        match parse_position(&args[1..]) {
+          Ok(game) => self.game = game,
+          Err(err) => println!("info string {}", err),
+        }
+      },
+      Some(&"go") => {
+        self.wait();
+        self.go(Go::parse(&args[1..]));
+      },
+      Some(&"stop") => self.stop(),
+      Some(&"quit") => {
+        self.stop();
+        return false;
+      },
+      _ => {},
+    }
+    true
+  }
+
+  fn set_option(&mut self, args: &[&str]) {
+    let (name, value) = parse_option(args);
+    match name.to_lowercase().as_str() {
+      "hash" => match value.parse::<usize>() {
+        Ok(size_mb) => self.table.lock().unwrap().resize(size_mb.clamp(1, MAX_SIZE_MB)),
+        Err(_) => println!("info string invalid hash size {}", value),
+      },
+      "algorithm" => match Algorithm::from_name(&value) {
+        Some(algorithm) => self.algorithm = algorithm,
+        None => println!("info string unknown algorithm {}", value),
+      },
+      // the scores in the table were computed by the old evaluator
+      "evaluator" => match evaluator_from_name(&value) {
+        Ok(_) => {
+          self.evaluator = value;
+          self.table.lock().unwrap().clear();
+        },
+        Err(err) => println!("info string {}", err),
+      },
+      // the network of the nnue evaluator, without one it uses the classic evaluation
+      "evalfile" => {
+        if value.is_empty() || value == "<empty>" {
+          unload_network();
+          self.table.lock().unwrap().clear();
+        } else {
+          match load_network_file(&value) {
+            Ok(()) => {
+              println!("info string loaded network {} with {} hidden neurons", value, network().unwrap().hidden());
+              self.table.lock().unwrap().clear();
+            },
+            Err(err) => println!("info string {}", err),
+          }
+        }
+      },
+      _ => println!("info string unknown option {}", name),
+    }
+  }
+
+  // starts the search in another thread, so that stop can still be read
+  fn go(&mut self, go: Go) {
+    self.stop.store(false, Ordering::Relaxed);
+    self.seed += 1;
+    let stop = self.stop.clone();
+    let table = self.table.clone();
+    let fen = self.game.fen();
+    let history = self.game.history.clone();
+    let max_time = go.max_time(self.game.pos.turn());
+    let algorithm = self.algorithm;
+    let evaluator = self.evaluator.clone();
+    let seed = self.seed;
+    let depth = go.depth.unwrap_or(DEFAULT_DEPTH).max(1);
+    if let Some(warning) = fixed_depth_warning(algorithm, &go, depth) {
+      println!("{}", warning);
+    }
+
+    self.search = Some(thread::spawn(move || {
+      let mut ctx = SearchContext::with_history(&history);
+      ctx.stop = stop.clone();
+      // the name was checked by setoption
+      ctx.set_evaluator(&evaluator).unwrap();
+      ctx.max_depth = go.depth.unwrap_or(u64::MAX);
+      ctx.info = Some(Box::new(|result: &SearchResult| println!("{}", info_line(result))));
+      let result = algorithm.search(&fen, seed, depth, max_time, &mut table.lock().unwrap(), &mut ctx);
+      // the move can only be sent after stop when searching without limits
+      if go.waits_for_stop() {
+        while !stop.load(Ordering::Relaxed) {
+          thread::sleep(Duration::from_millis(5));
+        }
+      }
+      match result {
+        Ok(result) => match result.pv.get(1) {
+          Some(ponder) => println!("bestmove {} ponder {}", result.uci, ponder),
+          None => println!("bestmove {}", result.uci),
+        },
+        Err(err) => {
+          println!("info string {}", err);
+          println!("bestmove 0000");
+        },
+      }
+    }));
+  }
+
+  fn stop(&mut self) {
+    self.stop.store(true, Ordering::Relaxed);
+    self.wait();
+  }
+
+  // waits for the running search, if any, to send its move
+  fn wait(&mut self) {
+    if let Some(search) = self.search.take() {
+      let _ = search.join();
+    }
+  }
+}
+
+fn main() {
+  let mut uci = Uci::new();
+  for line in io::stdin().lock().lines() {
+    let line = match line {
+      Ok(line) => line,
+      Err(_) => break,
+    };
+    if !uci.command(&line) {
+      return;
+    }
+  }
+  uci.stop();
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn parse_go() {
+    let go = Go::parse(&["wtime", "60000", "btime", "30000", "winc", "1000", "binc", "1000"]);
+    assert_eq!(go.wtime, Some(60000));
+    assert_eq!(go.binc, Some(1000));
+    assert!(!go.waits_for_stop());
+    assert_eq!(go.max_time(Color::White), time_for_move(60000, 1000, None));
+    assert_eq!(go.max_time(Color::Black), time_for_move(30000, 1000, None));
+    assert_eq!(Go::parse(&["wtime", "1000", "movestogo", "1"]).max_time(Color::White), time_for_move(1000, 0, Some(1)));
+
+    assert_eq!(Go::parse(&["movetime", "1000"]).max_time(Color::White), 950);
+    let go = Go::parse(&["depth", "5"]);
+    assert_eq!((go.depth, go.max_time(Color::White), go.waits_for_stop()), (Some(5), u64::MAX, false));
+    assert!(Go::parse(&["infinite"]).waits_for_stop());
+    assert!(Go::parse(&[]).waits_for_stop());
+  }
+
+  #[test]
+  fn fixed_depth_under_time_control() {
+    let clock = Go::parse(&["wtime", "60000", "btime", "60000"]);
+    assert_eq!(
+      fixed_depth_warning(Algorithm::NegamaxAB, &clock, 4).unwrap(),
+      "info string negamax_a_b searches to depth 4 and ignores the time limits and stop"
+    );
+    assert!(fixed_depth_warning(Algorithm::NegamaxABTable, &Go::parse(&["movetime", "1000"]), 4).is_some());
+    assert!(fixed_depth_warning(Algorithm::Negamax, &Go::parse(&["infinite"]), 4).is_some());
+    assert_eq!(fixed_depth_warning(Algorithm::NegamaxAB, &Go::parse(&["depth", "5"]), 5), None);
+    assert_eq!(fixed_depth_warning(Algorithm::IterativeDeepeningTable, &clock, 4), None);
+    assert_eq!(fixed_depth_warning(Algorithm::Random, &clock, 4), None);
+  }
+
+  #[test]
+  fn position_command() {
+    let game = parse_position(&["startpos", "moves", "e2e4", "e7e5"]).unwrap();
+    assert_eq!(game.fen(), "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2");
+    assert_eq!(game.history.len(), 2);
+    let fen = "4k3/8/8/8/8/8/8/4K2R w K - 0 1";
+    let args: Vec<&str> = ["fen"].iter().copied().chain(fen.split(' ')).chain(["moves", "e1g1"]).collect();
+    assert_eq!(parse_position(&args).unwrap().fen(), "4k3/8/8/8/8/8/8/5RK1 b - - 1 1");
+    assert_eq!(parse_position(&["startpos", "moves", "e2e5"]).unwrap_err(), ChessError::IllegalMove(0, "e2e5".to_string()));
+  }
+
+  #[test]
+  fn option_command() {
+    assert_eq!(parse_option(&["name", "Hash", "value", "64"]), ("Hash".to_string(), "64".to_string()));
+    assert_eq!(parse_option(&["name", "Clear", "Hash"]), ("Clear Hash".to_string(), "".to_string()));
+    let mut uci = Uci::new();
+    uci.set_option(&["name", "Hash", "value", "0"]);
+    assert_eq!(uci.table.lock().unwrap().size_mb(), 1);
+    uci.set_option(&["name", "Algorithm", "value", "negamax_a_b"]);
+    assert_eq!(uci.algorithm, Algorithm::NegamaxAB);
+    uci.set_option(&["name", "Evaluator", "value", "material"]);
+    assert_eq!(uci.evaluator, "material");
+    uci.set_option(&["name", "Evaluator", "value", "unknown"]);
+    assert_eq!(uci.evaluator, "material");
+    uci.set_option(&["name", "EvalFile", "value", "no/such/network.nnue"]);
+    assert!(network().is_none());
+  }
+
+  #[test]
+  fn evaluator_change_clears_table() {
+    use shakmaty::zobrist::ZobristHash;
+    let key = Game::from_moves::<&str>("startpos", &[]).unwrap().pos.zobrist_hash::<u64>();
+    let mut uci = Uci::new();
+    uci.command("go depth 2");
+    uci.wait();
+    assert!(uci.table.lock().unwrap().find(key, 0).is_some());
+    uci.set_option(&["name", "Evaluator", "value", "material"]);
+    assert!(uci.table.lock().unwrap().find(key, 0).is_none());
+  }
+
+}
@@ -0,0 +1,366 @@
+// XBoard/CECP frontend for the search algorithms, for XBoard, WinBoard and the other tools of the family
+// cargo build --release --bin xboard
+
+use std::io::{self, BufRead};
+
+use shakmaty::{Color, Outcome, Position};
+use shakmaty::zobrist::ZobristHash;
+
+use wasm_chess_algorithms::{evaluator_from_name, time_for_move, Algorithm, Game, HashTable, Score, SearchContext, SearchResult, DEFAULT_EVALUATOR, EVALUATORS, MAX_SIZE_MB, MOVE_OVERHEAD};
+
+const NAME: &str = "wasm-chess-algorithms";
+// depth used by the fixed depth algorithms when sd doesn't give one
+const DEFAULT_DEPTH: u64 = 4;
+// milliseconds per move of the timed algorithms when there's no time control
+const DEFAULT_MOVE_TIME: u64 = 5000;
+// CECP reports mate in N moves as 100000 + N
+const MATE_SCORE: i64 = 100_000;
+
+// score in the format of the thinking output
+fn cecp_score(score: Score) -> i64 {
+  match score {
+    Score::Cp(cp) => cp,
+    Score::Mate(moves) if moves > 0 => MATE_SCORE + moves,
+    Score::Mate(moves) => -MATE_SCORE + moves,
+  }
+}
+
+// ply score time nodes pv, time is in centiseconds
+fn thinking_line(result: &SearchResult) -> String {
+  format!("{} {} {} {} {}", result.depth, cecp_score(result.score), result.time / 10, result.nodes, result.pv_san.join(" "))
+}
+
+// level MPS BASE INC, BASE is in minutes or minutes:seconds, INC in seconds
+fn parse_level(args: &[&str]) -> Option<(u64, u64, u64)> {
+  let moves = args.first()?.parse().ok()?;
+  let base = match args.get(1)?.split_once(':') {
+    Some((minutes, seconds)) => minutes.parse::<u64>().ok()? * 60 + seconds.parse::<u64>().ok()?,
+    None => args.get(1)?.parse::<u64>().ok()? * 60,
+  };
+  let inc = args.get(2)?.parse::<f64>().ok()?;
+  Some((moves, base * 1000, (inc * 1000.0) as u64))
+}
+
+// result command to end the game, if it's over
+fn game_result(game: &Game) -> Option<&'static str> {
+  match game.pos.outcome() {
+    Some(Outcome::Decisive { winner: Color::White }) => return Some("1-0 {White mates}"),
+    Some(Outcome::Decisive { winner: Color::Black }) => return Some("0-1 {Black mates}"),
+    Some(Outcome::Draw) if game.pos.is_stalemate() => return Some("1/2-1/2 {Stalemate}"),
+    Some(Outcome::Draw) => return Some("1/2-1/2 {Insufficient material}"),
+    None => {},
+  }
+  // the draws the search scores, with the game history before the current position
+  if !SearchContext::with_history(&game.history).is_draw(&game.pos, game.pos.zobrist_hash()) {
+    None
+  } else if game.pos.halfmoves() >= 100 {
+    Some("1/2-1/2 {50 move rule}")
+  } else {
+    Some("1/2-1/2 {Draw by repetition}")
+  }
+}
+
+struct Xboard {
+  // the game is the start position and the moves played since, so moves can be taken back
+  start: String,
+  moves: Vec<String>,
+  game: Game,
+  // in force mode the engine only checks moves, it doesn't play
+  force: bool,
+  engine_color: Color,
+  post: bool,
+  algorithm: Algorithm,
+  evaluator: String,
+  table: HashTable,
+  seed: u64,
+  // limits: sd, st and level
+  depth: Option<u64>,
+  move_time: Option<u64>,
+  moves_per_session: u64,
+  increment: u64,
+  // engine clock in milliseconds, sent with time
+  time_left: Option<u64>,
+}
+impl Xboard {
+  fn new() -> Xboard {
+    Xboard {
+      start: "startpos".to_string(),
+      moves: vec![],
+      game: Game::from_moves::<&str>("startpos", &[]).unwrap(),
+      force: false,
+      engine_color: Color::Black,
+      post: false,
+      algorithm: Algorithm::IterativeDeepeningTable,
+      evaluator: DEFAULT_EVALUATOR.to_string(),
+      table: HashTable::new(),
+      seed: 0,
+      depth: None,
+      move_time: None,
+      moves_per_session: 0,
+      increment: 0,
+      time_left: None,
+    }
+  }
+
+  // returns false once the GUI wants to quit
+  fn command(&mut self, line: &str) -> bool {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    match args.first() {
+      Some(&"protover") => {
+        // the default choice of a combo is marked with *
+        let algorithms: Vec<String> = Algorithm::ALL.iter().map(|algorithm| {
+          let default = if *algorithm == self.algorithm { "*" } else { "" };
+          format!("{}{}", default, algorithm.name())
+        }).collect();
+        let evaluators: Vec<String> = EVALUATORS.iter().map(|name| {
+          let default = if *name == self.evaluator { "*" } else { "" };
+          format!("{}{}", default, name)
+        }).collect();
+        println!(
+          "feature myname=\"{}\" usermove=1 setboard=1 ping=1 memory=1 sigint=0 sigterm=0 colors=0 option=\"Algorithm -combo {}\" option=\"Evaluator -combo {}\" done=1",
+          NAME, algorithms.join(" /// "), evaluators.join(" /// ")
+        );
+      },
+      Some(&"new") => {
+        self.table.clear();
+        self.set_game("startpos", vec![]);
+        self.force = false;
+        self.engine_color = Color::Black;
+        self.depth = None;
+      },
+      Some(&"setboard") => {
+        let fen = args[1..].join(" ");
+        if Game::from_moves::<&str>(&fen, &[]).is_ok() {
+          self.set_game(&fen, vec![]);
+        } else {
+          println!("tellusererror Illegal position");
+        }
+      },
+      Some(&"force") | Some(&"result") => self.force = true,
+      Some(&"go") => {
+        self.force = false;
+        self.engine_color = self.game.pos.turn();
+        self.think();
+      },
+      Some(&"usermove") => self.user_move(args.get(1).copied().unwrap_or_default()),
+      Some(&"undo") => self.take_back(1),
+      Some(&"remove") => self.take_back(2),
+      Some(&"post") => self.post = true,
+      Some(&"nopost") => self.post = false,
+      Some(&"sd") => self.depth = args.get(1).and_then(|depth| depth.parse().ok()),
+      Some(&"st") => {
+        self.move_time = args.get(1).and_then(|seconds| seconds.parse::<u64>().ok()).map(|seconds| seconds * 1000);
+      },
+      Some(&"level") => match parse_level(&args[1..]) {
+        Some((moves, base, inc)) => {
+          self.moves_per_session = moves;
+          self.increment = inc;
+          self.time_left = Some(base);
+          self.move_time = None;
+        },
+        None => println!("Error (bad level): {}", line),
+      },
+      // clocks are sent in centiseconds
+      Some(&"time") => self.time_left = args.get(1).and_then(|time| time.parse::<u64>().ok()).map(|time| time * 10),
+      Some(&"memory") => {
+        if let Some(size_mb) = args.get(1).and_then(|size| size.parse::<usize>().ok()) {
+          self.table.resize(size_mb.clamp(1, MAX_SIZE_MB));
+        }
+      },
+      Some(&"option") => {
+        let (name, value) = line["option".len()..].trim().split_once('=').unwrap_or_default();
+        match (name, Algorithm::from_name(value)) {
+          ("Algorithm", Some(algorithm)) => self.algorithm = algorithm,
+          // the scores in the table were computed by the old evaluator
+          ("Evaluator", _) if evaluator_from_name(value).is_ok() => {
+            self.evaluator = value.to_string();
+            self.table.clear();
+          },
+          _ => println!("Error (bad option): {}", line),
+        }
+      },
+      Some(&"ping") => println!("pong {}", args.get(1).copied().unwrap_or_default()),
+      Some(&"quit") => return false,
+      // xboard, accepted, rejected, otim, random, hard, easy, computer and the rest are not needed
+      _ => {},
+    }
+    true
+  }
+
+  fn set_game(&mut self, start: &str, moves: Vec<String>) {
+    // the moves were already checked when they were played
+    self.game = Game::from_moves(start, &moves).unwrap();
+    self.start = start.to_string();
+    self.moves = moves;
+  }
+
+  fn user_move(&mut self, mov: &str) {
+    let mut moves = self.moves.clone();
+    moves.push(mov.to_string());
+    if Game::from_moves(&self.start, &moves).is_err() {
+      println!("Illegal move: {}", mov);
+      return;
+    }
+    self.set_game(&self.start.clone(), moves);
+    if !self.force && self.game.pos.turn() == self.engine_color {
+      self.think();
+    }
+  }
+
+  fn take_back(&mut self, plies: usize) {
+    let mut moves = self.moves.clone();
+    moves.truncate(moves.len().saturating_sub(plies));
+    self.set_game(&self.start.clone(), moves);
+  }
+
+  // milliseconds for the next move, st wins over the clock
+  fn max_time(&self) -> u64 {
+    if let Some(move_time) = self.move_time {
+      return move_time.saturating_sub(MOVE_OVERHEAD).max(1);
+    }
+    match self.time_left {
+      Some(time) => {
+        // moves still to play in this session, with sessions of moves_per_session moves
+        let moves_to_go = if self.moves_per_session > 0 {
+          let played = self.game.pos.fullmoves().get() as u64 - 1;
+          Some(self.moves_per_session - played % self.moves_per_session)
+        } else {
+          None
+        };
+        time_for_move(time, self.increment, moves_to_go)
+      },
+      // no time control, the depth limit or the algorithm decide
+      None => if self.depth.is_some() { u64::MAX } else { DEFAULT_MOVE_TIME },
+    }
+  }
+
+  // searches the position and plays the best move
+  fn think(&mut self) {
+    if let Some(result) = game_result(&self.game) {
+      println!("{}", result);
+      return;
+    }
+    self.seed += 1;
+    let mut ctx = SearchContext::with_history(&self.game.history);
+    ctx.max_depth = self.depth.unwrap_or(u64::MAX);
+    // the name was checked by the option command
+    ctx.set_evaluator(&self.evaluator).unwrap();
+    if self.post {
+      ctx.info = Some(Box::new(|result: &SearchResult| println!("{}", thinking_line(result))));
+    }
+    let depth = self.depth.unwrap_or(DEFAULT_DEPTH).max(1);
+    let max_time = self.max_time();
+    match self.algorithm.search(&self.game.fen(), self.seed, depth, max_time, &mut self.table, &mut ctx) {
+      Ok(result) => {
+        println!("move {}", result.uci);
+        let mut moves = self.moves.clone();
+        moves.push(result.uci);
+        self.set_game(&self.start.clone(), moves);
+        if let Some(result) = game_result(&self.game) {
+          println!("{}", result);
+        }
+      },
+      Err(err) => println!("Error (search failed): {}", err),
+    }
+  }
+}
+
+fn main() {
+  let mut xboard = Xboard::new();
+  for line in io::stdin().lock().lines() {
+    let line = match line {
+      Ok(line) => line,
+      Err(_) => break,
+    };
+    if !xboard.command(&line) {
+      return;
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn level_command() {
+    assert_eq!(parse_level(&["40", "5", "0"]), Some((40, 300_000, 0)));
+    assert_eq!(parse_level(&["0", "2:30", "1.5"]), Some((0, 150_000, 1500)));
+    assert_eq!(parse_level(&["0", "x", "0"]), None);
+
+    let mut xboard = Xboard::new();
+    xboard.command("level 40 5 0");
+    xboard.command("time 30000");
+    assert_eq!(xboard.max_time(), time_for_move(300_000, 0, Some(40)));
+    xboard.command("st 2");
+    assert_eq!(xboard.max_time(), 2000 - MOVE_OVERHEAD);
+  }
+
+  #[test]
+  fn thinking_output() {
+    assert_eq!(cecp_score(Score::Cp(-35)), -35);
+    assert_eq!(cecp_score(Score::Mate(3)), 100_003);
+    assert_eq!(cecp_score(Score::Mate(-2)), -100_002);
+  }
+
+  #[test]
+  fn moves_and_take_backs() {
+    let mut xboard = Xboard::new();
+    xboard.command("force");
+    xboard.command("usermove e2e4");
+    xboard.command("usermove e7e5");
+    xboard.command("usermove e2e4");
+    assert_eq!(xboard.moves, vec!["e2e4", "e7e5"]);
+    xboard.command("undo");
+    assert_eq!(xboard.moves, vec!["e2e4"]);
+    xboard.command("remove");
+    assert!(xboard.moves.is_empty());
+    assert_eq!(xboard.game.history.len(), 0);
+  }
+
+  #[test]
+  fn engine_replies_to_user_move() {
+    let mut xboard = Xboard::new();
+    xboard.command("new");
+    xboard.command("sd 3");
+    xboard.command("setboard 4k3/8/8/8/8/2r5/1q6/4K3 w - - 2 2");
+    xboard.command("usermove e1f1");
+    // the engine plays black and mates
+    assert_eq!(xboard.moves.len(), 2);
+    assert!(xboard.game.pos.is_checkmate());
+  }
+
+  #[test]
+  fn evaluator_change_clears_table() {
+    let mut xboard = Xboard::new();
+    xboard.command("sd 2");
+    xboard.command("go");
+    let key = Game::from_moves::<&str>("startpos", &[]).unwrap().pos.zobrist_hash();
+    assert!(xboard.table.find(key, 0).is_some());
+    xboard.command("option Evaluator=material");
+    assert_eq!(xboard.evaluator, "material");
+    assert!(xboard.table.find(key, 0).is_none());
+  }
+
+  #[test]
+  fn draw_results() {
+    let mut xboard = Xboard::new();
+    xboard.command("force");
+    // the knights go back and forth until the start position is on the board for the third time
+    for uci in ["g1f3", "g8f6", "f3g1", "f6g8", "g1f3", "g8f6", "f3g1"] {
+      xboard.command(&format!("usermove {}", uci));
+      assert_eq!(game_result(&xboard.game), None);
+    }
+    xboard.command("usermove f6g8");
+    assert_eq!(game_result(&xboard.game), Some("1/2-1/2 {Draw by repetition}"));
+
+    xboard.command("setboard 4k3/8/8/8/8/8/8/R3K3 w - - 99 80");
+    assert_eq!(game_result(&xboard.game), None);
+    xboard.command("usermove a1a2");
+    assert_eq!(game_result(&xboard.game), Some("1/2-1/2 {50 move rule}"));
+    // unless the last move mates
+    xboard.command("setboard 4k3/R7/8/8/8/8/8/4K2R w K - 99 80");
+    xboard.command("usermove h1h8");
+    assert_eq!(game_result(&xboard.game), Some("1-0 {White mates}"));
+  }
+}
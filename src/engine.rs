@@ -0,0 +1,269 @@
+use wasm_bindgen::prelude::*;
+
+use crate::algorithms;
+use crate::algorithms::hashtable::HashTable;
+use crate::error::ChessError;
+use crate::evaluate::{eval_params, evaluator_from_name, Classic, EvalParams, Evaluator};
+use crate::game::{Game, STARTING_FEN};
+use crate::search::{SearchContext, SearchResult};
+use crate::utils::to_js;
+
+// Engine that keeps its transposition table in rust memory between moves, so
+// the table never has to be copied over to JS.
+// One engine should be kept per game, JS has to call `free()` once the game is over.
+#[wasm_bindgen]
+pub struct Engine {
+  table: HashTable,
+  fen: String,
+  // zobrist keys of the positions played before fen, to detect repetitions
+  history: Vec<u64>,
+  max_time: u64,
+  depth: u64,
+  evaluator: Box<dyn Evaluator>,
+}
+
+#[wasm_bindgen]
+impl Engine {
+  // creates an engine that searches for max_time milliseconds per move
+  #[wasm_bindgen(constructor)]
+  pub fn new(max_time: u64) -> Engine {
+    Engine {
+      table: HashTable::new(),
+      fen: STARTING_FEN.to_string(),
+      history: vec![],
+      max_time,
+      depth: 0,
+      evaluator: Box::new(Classic::new(eval_params())),
+    }
+  }
+
+  // start is a FEN or "startpos", followed by the moves played since then in UCI or SAN,
+  // passing the moves lets the engine know the game history and avoid or claim repetitions.
+  // The position is validated here, so a bad FEN or move is reported before searching
+  pub fn set_position(&mut self, start: &str, moves: Option<Vec<String>>) -> Result<(), ChessError> {
+    let game = Game::from_moves(start, &moves.unwrap_or_default())?;
+    self.fen = game.fen();
+    self.history = game.history;
+    Ok(())
+  }
+
+  pub fn position(&self) -> String {
+    self.fen.clone()
+  }
+
+  pub fn set_max_time(&mut self, max_time: u64) {
+    self.max_time = max_time;
+  }
+
+  // with a depth greater than 0 the engine searches at fixed depth instead of using max_time
+  pub fn set_depth(&mut self, depth: u64) {
+    self.depth = depth;
+  }
+
+  // chooses the evaluator used by the next searches by name, an unknown name keeps the current one,
+  // the table is emptied since its scores were computed by the old evaluator
+  pub fn set_evaluator(&mut self, name: &str) -> Result<(), ChessError> {
+    self.evaluator = evaluator_from_name(name)?;
+    self.table.clear();
+    Ok(())
+  }
+
+  // uses the classic evaluator with the weights in json for the next searches,
+  // the terms it doesn't have keep their default value, the table is emptied as in set_evaluator
+  pub fn set_eval_params(&mut self, json: &str) -> Result<(), ChessError> {
+    self.evaluator = Box::new(Classic::new(EvalParams::from_json(json)?));
+    self.table.clear();
+    Ok(())
+  }
+
+  pub fn evaluator(&self) -> String {
+    self.evaluator.name().to_string()
+  }
+
+  // searches the current position and returns a SearchResult object
+  #[wasm_bindgen(js_name = search)]
+  pub fn search_js(&mut self, seed: u64) -> Result<JsValue, JsValue> {
+    to_js(&self.search(seed)?)
+  }
+
+  // empties the transposition table, to be used when starting a new game
+  pub fn clear(&mut self) {
+    self.table.clear();
+  }
+
+  // sets the memory used by the transposition table in MB, emptying it
+  pub fn set_hash_size(&mut self, size_mb: usize) {
+    self.table.resize(size_mb);
+  }
+
+  pub fn hash_size(&self) -> usize {
+    self.table.size_mb()
+  }
+
+  // how full the transposition table is in per mille
+  pub fn hashfull(&self) -> u64 {
+    self.table.hashfull()
+  }
+
+  // the transposition table in binary form, as a Uint8Array, to be saved between sessions
+  pub fn export_table(&self) -> Vec<u8> {
+    self.table.to_bytes()
+  }
+
+  // replaces the transposition table with one saved by export_table,
+  // a corrupt table or one from another version is rejected and the current one is kept
+  pub fn import_table(&mut self, bytes: &[u8]) -> Result<(), ChessError> {
+    self.table = HashTable::from_bytes(bytes)?;
+    Ok(())
+  }
+}
+
+impl Engine {
+  pub fn search(&mut self, seed: u64) -> Result<SearchResult, ChessError> {
+    let mut ctx = SearchContext::with_history(&self.history);
+    // the context borrows the evaluator for the search and gives it back
+    std::mem::swap(&mut ctx.evaluator, &mut self.evaluator);
+    let result = if self.depth > 0 {
+      algorithms::negamax_a_b_table::search(&self.fen, seed, self.depth, &mut self.table, &mut ctx)
+    } else {
+      algorithms::iterative_deepening_table::search(&self.fen, seed, self.max_time, &mut self.table, &mut ctx)
+    };
+    std::mem::swap(&mut ctx.evaluator, &mut self.evaluator);
+    result
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use shakmaty::zobrist::ZobristHash;
+  use crate::utils::parse_fen;
+
+  fn key(fen_str: &str) -> u64 {
+    parse_fen(fen_str).unwrap().zobrist_hash()
+  }
+
+  #[test]
+  fn table_kept_between_searches() {
+    let first = "4k3/8/8/8/8/2r5/1q6/5K2 b - - 3 2";
+    let second = "5k2/2R5/1Q6/8/8/8/8/4K3 w - - 3 3";
+    let mut engine = Engine::new(0);
+    engine.set_depth(3);
+    engine.set_position(first, None).unwrap();
+    assert_eq!(engine.search(1).unwrap().san, "Rc1");
+    assert!(engine.table.find(key(first), 0).is_some());
+
+    engine.set_position(second, None).unwrap();
+    assert_eq!(engine.search(1).unwrap().san, "Qb8");
+    assert!(engine.table.find(key(first), 0).is_some());
+    assert!(engine.table.find(key(second), 0).is_some());
+
+    engine.clear();
+    assert!(engine.table.find(key(second), 0).is_none());
+  }
+
+  #[test]
+  fn table_export_import() {
+    let fen = "4k3/8/8/8/8/2r5/1q6/5K2 b - - 3 2";
+    let mut engine = Engine::new(0);
+    engine.set_depth(3);
+    engine.set_position(fen, None).unwrap();
+    engine.search(1).unwrap();
+
+    let mut other = Engine::new(0);
+    other.import_table(&engine.export_table()).unwrap();
+    assert_eq!(other.table.find(key(fen), 0), engine.table.find(key(fen), 0));
+    assert_eq!(other.import_table(&[1, 2, 3]).unwrap_err().code(), "CORRUPT_TABLE");
+    assert!(other.table.find(key(fen), 0).is_some());
+  }
+
+  #[test]
+  fn hash_size() {
+    let mut engine = Engine::new(0);
+    engine.set_hash_size(1);
+    assert_eq!(engine.hash_size(), 1);
+    assert_eq!(engine.hashfull(), 0);
+  }
+
+  #[test]
+  fn invalid_position_is_rejected() {
+    let mut engine = Engine::new(0);
+    let err = engine.set_position("not a fen", None).unwrap_err();
+    assert_eq!(err.code(), "INVALID_FEN");
+    // the previous position is kept
+    assert_eq!(engine.position(), STARTING_FEN);
+
+    engine.set_depth(1);
+    engine.set_position("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1", None).unwrap();
+    assert_eq!(engine.search(1).unwrap_err().code(), "NO_LEGAL_MOVES");
+  }
+
+  #[test]
+  fn position_from_moves() {
+    let mut engine = Engine::new(1000);
+    engine.set_position("startpos", Some(vec!["e4".to_string(), "e7e5".to_string()])).unwrap();
+    assert_eq!(engine.position(), "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2");
+    let err = engine.set_position("startpos", Some(vec!["e4".to_string(), "e4".to_string()])).unwrap_err();
+    assert_eq!(err, ChessError::IllegalMove(1, "e4".to_string()));
+
+    // black is lost, but the moves played so far let it draw by threefold repetition
+    let moves = ["Kg8", "Qd2", "Kh8", "Qd1", "Kg8", "Qd2", "Kh8", "Qd1"];
+    engine.set_position("7k/8/8/8/8/8/8/3QK3 b - - 0 1", Some(moves.iter().map(|mov| mov.to_string()).collect())).unwrap();
+    engine.set_depth(3);
+    let result = engine.search(1).unwrap();
+    assert_eq!(result.san, "Kg8");
+    assert_eq!(result.score, crate::search::Score::Cp(0));
+  }
+
+  #[test]
+  fn choose_evaluator() {
+    let mut engine = Engine::new(0);
+    assert_eq!(engine.evaluator(), "classic");
+    engine.set_evaluator("material").unwrap();
+    assert_eq!(engine.evaluator(), "material");
+    assert_eq!(engine.set_evaluator("neural").unwrap_err().code(), "UNKNOWN_EVALUATOR");
+    assert_eq!(engine.evaluator(), "material");
+    // the evaluator is kept after searching
+    engine.set_depth(1);
+    engine.set_position("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1", None).unwrap();
+    assert_eq!(engine.search(1).unwrap().score, crate::search::Score::Cp(100));
+    assert_eq!(engine.evaluator(), "material");
+    // the scores of the material evaluator are gone with it
+    assert!(engine.table.find(key("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1"), 0).is_some());
+    engine.set_evaluator("classic").unwrap();
+    assert!(engine.table.find(key("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1"), 0).is_none());
+  }
+
+  #[test]
+  fn engine_eval_params() {
+    let mut engine = Engine::new(0);
+    engine.set_depth(1);
+    engine.set_position("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1", None).unwrap();
+    let default = engine.search(1).unwrap().score;
+    engine.set_eval_params(r#"{ "material": { "pawn": 300 } }"#).unwrap();
+    assert_eq!(engine.evaluator(), "classic");
+    assert_ne!(engine.search(1).unwrap().score, default);
+    assert_eq!(engine.set_eval_params("{").unwrap_err().code(), "INVALID_PARAMS");
+  }
+
+  #[test]
+  fn draw_not_kept_for_other_histories() {
+    // every pawn is blocked, the kings shuffle between g1 and h1, a8 and b8
+    let start = "k7/8/1p6/1P2p3/1P2P3/4P2p/4P2P/6K1 b - - 10 40";
+    let moves: Vec<String> = ["a8b8", "g1h1", "b8a8", "h1g1", "a8b8", "g1h1"].iter().map(|uci| uci.to_string()).collect();
+    let mut engine = Engine::new(0);
+    engine.set_depth(4);
+    engine.set_position(start, Some(moves)).unwrap();
+    // Ka8 Kg1 repeats a position for the third time
+    assert_eq!(engine.search(1).unwrap().score, crate::search::Score::Cp(0));
+    // the same position in another game isn't a draw
+    let fen = engine.position();
+    engine.set_position(&fen, None).unwrap();
+    let result = engine.search(1).unwrap();
+    assert_ne!(result.score, crate::search::Score::Cp(0));
+    let mut fresh = Engine::new(0);
+    fresh.set_depth(4);
+    fresh.set_position(&fen, None).unwrap();
+    assert_eq!(result.score, fresh.search(1).unwrap().score);
+  }
+}
@@ -0,0 +1,97 @@
+use std::fmt;
+
+use wasm_bindgen::JsValue;
+
+// errors that can be returned by the crate, they are thrown as JS errors
+// with a stable `code` property when crossing the wasm boundary
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChessError {
+  // the string is not a valid FEN
+  InvalidFen(String),
+  // the FEN is valid, but the position can't happen in a game
+  IllegalPosition(String),
+  // there's nothing to search, the game is already over
+  NoLegalMoves(String),
+  // the transposition table received from the outside can't be used
+  CorruptTable(String),
+  // the move at this index of a move list can't be played, it's illegal or not valid UCI or SAN
+  IllegalMove(usize, String),
+  // there's no evaluator with this name
+  UnknownEvaluator(String),
+  // the evaluation params can't be read, the string says why
+  InvalidParams(String),
+  // the network weights can't be read, the string says why
+  InvalidNetwork(String),
+  // fixed depth searches need to look at least one move ahead
+  InvalidDepth(u64),
+}
+impl ChessError {
+  pub fn code(&self) -> &'static str {
+    match self {
+      ChessError::InvalidFen(_) => "INVALID_FEN",
+      ChessError::IllegalPosition(_) => "ILLEGAL_POSITION",
+      ChessError::NoLegalMoves(_) => "NO_LEGAL_MOVES",
+      ChessError::CorruptTable(_) => "CORRUPT_TABLE",
+      ChessError::IllegalMove(..) => "ILLEGAL_MOVE",
+      ChessError::UnknownEvaluator(_) => "UNKNOWN_EVALUATOR",
+      ChessError::InvalidParams(_) => "INVALID_PARAMS",
+      ChessError::InvalidNetwork(_) => "INVALID_NETWORK",
+      ChessError::InvalidDepth(_) => "INVALID_DEPTH",
+    }
+  }
+}
+
+impl fmt::Display for ChessError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ChessError::InvalidFen(fen) => write!(f, "invalid fen: {}", fen),
+      ChessError::IllegalPosition(fen) => write!(f, "illegal position: {}", fen),
+      ChessError::NoLegalMoves(fen) => write!(f, "no legal moves in position: {}", fen),
+      ChessError::CorruptTable(reason) => write!(f, "corrupt transposition table: {}", reason),
+      ChessError::IllegalMove(index, mov) => write!(f, "illegal move {} at index {}", mov, index),
+      ChessError::UnknownEvaluator(name) => write!(f, "unknown evaluator: {}", name),
+      ChessError::InvalidParams(reason) => write!(f, "invalid evaluation params: {}", reason),
+      ChessError::InvalidNetwork(reason) => write!(f, "invalid network: {}", reason),
+      ChessError::InvalidDepth(depth) => write!(f, "invalid depth {}, it must be at least 1", depth),
+    }
+  }
+}
+
+impl std::error::Error for ChessError {}
+
+impl From<ChessError> for JsValue {
+  fn from(err: ChessError) -> JsValue {
+    let js_error = js_sys::Error::new(&err.to_string());
+    js_error.set_name("ChessError");
+    // setting a property on a freshly created object can't fail
+    let _ = js_sys::Reflect::set(&js_error, &"code".into(), &err.code().into());
+    if let ChessError::IllegalMove(index, _) = err {
+      let _ = js_sys::Reflect::set(&js_error, &"index".into(), &(index as u32).into());
+    }
+    js_error.into()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn error_codes() {
+    assert_eq!(ChessError::InvalidFen("".to_string()).code(), "INVALID_FEN");
+    assert_eq!(ChessError::IllegalPosition("".to_string()).code(), "ILLEGAL_POSITION");
+    assert_eq!(ChessError::NoLegalMoves("".to_string()).code(), "NO_LEGAL_MOVES");
+    assert_eq!(ChessError::CorruptTable("".to_string()).code(), "CORRUPT_TABLE");
+    assert_eq!(ChessError::IllegalMove(0, "".to_string()).code(), "ILLEGAL_MOVE");
+    assert_eq!(ChessError::UnknownEvaluator("".to_string()).code(), "UNKNOWN_EVALUATOR");
+    assert_eq!(ChessError::InvalidParams("".to_string()).code(), "INVALID_PARAMS");
+    assert_eq!(ChessError::InvalidNetwork("".to_string()).code(), "INVALID_NETWORK");
+    assert_eq!(ChessError::InvalidDepth(0).code(), "INVALID_DEPTH");
+  }
+
+  #[test]
+  fn error_message() {
+    let err = ChessError::InvalidFen("8/8/8 w".to_string());
+    assert_eq!(err.to_string(), "invalid fen: 8/8/8 w");
+  }
+}
@@ -1,122 +1,447 @@
+use std::ops::{Add, AddAssign, Mul, Neg, Sub, SubAssign};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
 use shakmaty::*;
-use shakmaty::fen::Fen;
-
-const KING_VALUE: i64 = 10_000;
-const QUEEN_VALUE: i64 = 1_000;
-const ROOK_VALUE: i64 = 500;
-const BISHOP_VALUE: i64 = 350;
-const KNIGHT_VALUE: i64 = 300;
-const PAWN_VALUE: i64 = 100;
-
-pub fn evaluate(fen_str: &str) -> i64 {
-  let fen: Fen = fen_str.parse().unwrap();
-  let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
-  let board_iter: board::IntoIter = pos.board().to_owned().into_iter();
-  let mut score: i64 = 0;
-  let who_moves = pos.turn();
-
-  // if game is stalemate, return 0
-
-  // if game is done
-  if pos.is_game_over()  {
-    let outcome = pos.outcome().unwrap();
-    // if outcome is checkmate and white won, set score = KING_VALUE
-    // if outcome is checkmate and black won, set score = -KING_VALUE
-    // if outcome is draw, set score = 0
-    match outcome {
-      Outcome::Decisive{winner: Color::White} => {
-        // println!("Black lost");
-        score = KING_VALUE
-      },
-      Outcome::Decisive{winner: Color::Black} => {
-        // println!("White lost");
-        score = -KING_VALUE
-      },
-      Outcome::Draw => score= 0,
-    }
-    // -KING_VALUE because the who_moves side lost
-    
-  } else {
-    // if game is not over, evaluate board
-    for (_square, piece) in board_iter {
-      score += piece_value(piece);
+
+use crate::error::ChessError;
+
+mod explain;
+mod king;
+mod mobility;
+mod nnue;
+mod pawns;
+mod pieces;
+mod pst;
+
+pub use explain::{explain_evaluation, Explanation};
+pub use king::KingParams;
+pub use mobility::MobilityParams;
+pub use nnue::{load_network, load_network_file, network, unload_network, Network, Nnue};
+pub use pawns::{PassedParams, PawnParams};
+pub use pieces::PieceParams;
+pub use pst::PstParams;
+
+pub const KING_VALUE: i64 = 10_000;
+
+// game phase with all the pieces on the board, knights and bishops count 1, rooks 2 and queens 4
+pub const PHASE_MAX: i64 = 24;
+
+// value of a term in the middlegame and in the endgame, blended by the game phase
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Taper {
+  pub mg: i64,
+  pub eg: i64,
+}
+pub const fn taper(mg: i64, eg: i64) -> Taper {
+  Taper { mg, eg }
+}
+impl Taper {
+  // phase goes from PHASE_MAX in the opening to 0 when only kings and pawns are left
+  pub fn blend(self, phase: i64) -> i64 {
+    (self.mg * phase + self.eg * (PHASE_MAX - phase)) / PHASE_MAX
+  }
+}
+impl Add for Taper {
+  type Output = Taper;
+  fn add(self, other: Taper) -> Taper {
+    taper(self.mg + other.mg, self.eg + other.eg)
+  }
+}
+impl Sub for Taper {
+  type Output = Taper;
+  fn sub(self, other: Taper) -> Taper {
+    taper(self.mg - other.mg, self.eg - other.eg)
+  }
+}
+impl Neg for Taper {
+  type Output = Taper;
+  fn neg(self) -> Taper {
+    taper(-self.mg, -self.eg)
+  }
+}
+impl Mul<i64> for Taper {
+  type Output = Taper;
+  fn mul(self, times: i64) -> Taper {
+    taper(self.mg * times, self.eg * times)
+  }
+}
+impl AddAssign for Taper {
+  fn add_assign(&mut self, other: Taper) {
+    *self = *self + other;
+  }
+}
+impl SubAssign for Taper {
+  fn sub_assign(&mut self, other: Taper) {
+    *self = *self - other;
+  }
+}
+
+// value of each piece, the kings are always on the board and cancel out
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct MaterialParams {
+  pub pawn: i64,
+  pub knight: i64,
+  pub bishop: i64,
+  pub rook: i64,
+  pub queen: i64,
+}
+impl Default for MaterialParams {
+  fn default() -> Self {
+    MaterialParams { pawn: 100, knight: 300, bishop: 350, rook: 500, queen: 1_000 }
+  }
+}
+impl MaterialParams {
+  pub fn value(&self, role: Role) -> i64 {
+    match role {
+      Role::King => KING_VALUE,
+      Role::Queen => self.queen,
+      Role::Rook => self.rook,
+      Role::Bishop => self.bishop,
+      Role::Knight => self.knight,
+      Role::Pawn => self.pawn,
     }
   }
+}
 
+// weights of every term of the evaluation, the defaults are the values the engine plays with,
+// a term missing from the JSON keeps its default
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct EvalParams {
+  pub material: MaterialParams,
+  pub pst: PstParams,
+  pub pawns: PawnParams,
+  pub passed: PassedParams,
+  pub king: KingParams,
+  pub mobility: MobilityParams,
+  pub pieces: PieceParams,
+}
+impl EvalParams {
+  pub fn from_json(json: &str) -> Result<EvalParams, ChessError> {
+    serde_json::from_str(json).map_err(|err| ChessError::InvalidParams(err.to_string()))
+  }
 
-  if who_moves == Color::White {
-    return score;
-  } else {
-    return -score;
+  pub fn to_json(&self) -> String {
+    // a struct of numbers always serializes
+    serde_json::to_string_pretty(self).unwrap()
+  }
+}
+
+// params used by the evaluators created by name, None until someone sets them
+static PARAMS: Mutex<Option<EvalParams>> = Mutex::new(None);
+
+// the evaluators created from now on use params, None goes back to the defaults
+pub fn set_eval_params(params: Option<EvalParams>) {
+  *PARAMS.lock().unwrap() = params;
+}
+
+pub fn eval_params() -> EvalParams {
+  PARAMS.lock().unwrap().clone().unwrap_or_default()
+}
+
+// evaluation function used by the searches, so they can be compared on the same search
+pub trait Evaluator {
+  // name used to choose the evaluator from JS and the frontends
+  fn name(&self) -> &'static str;
+  // static score of the position from the point of view of the side to move,
+  // checkmates and draws are scored by the search, that knows how far they are
+  fn evaluate(&mut self, pos: &Chess) -> i64;
+  // the search moved to pos, a child of the position pushed before it, and then went back with pop,
+  // evaluators that keep state along the line update it here instead of starting over at each leaf
+  fn push(&mut self, _pos: &Chess) {}
+  fn pop(&mut self) {}
+}
+
+// material, piece-square tables and the positional terms, tapered between middlegame and endgame
+#[derive(Debug, Clone, Default)]
+pub struct Classic {
+  pub params: EvalParams,
+}
+impl Classic {
+  pub fn new(params: EvalParams) -> Classic {
+    Classic { params }
+  }
+}
+impl Evaluator for Classic {
+  fn name(&self) -> &'static str {
+    "classic"
+  }
+  fn evaluate(&mut self, pos: &Chess) -> i64 {
+    evaluate(pos, &self.params)
+  }
+}
+
+// counts the material and nothing else
+#[derive(Debug, Clone, Default)]
+pub struct Material {
+  pub params: MaterialParams,
+}
+impl Evaluator for Material {
+  fn name(&self) -> &'static str {
+    "material"
+  }
+  fn evaluate(&mut self, pos: &Chess) -> i64 {
+    evaluate_material(pos, &self.params)
   }
 }
 
+pub const EVALUATORS: [&str; 3] = ["classic", "material", "nnue"];
+pub const DEFAULT_EVALUATOR: &str = "classic";
 
-pub fn piece_value(piece: Piece) -> i64 {
-  let value = match piece.role {
-    Role::King => KING_VALUE,
-    Role::Queen => QUEEN_VALUE,
-    Role::Rook => ROOK_VALUE,
-    Role::Bishop => BISHOP_VALUE,
-    Role::Knight => KNIGHT_VALUE,
-    Role::Pawn => PAWN_VALUE
-  };
-  if piece.color.is_white() {
-    value
+// the evaluator called name, with the params set by set_eval_params
+pub fn evaluator_from_name(name: &str) -> Result<Box<dyn Evaluator>, ChessError> {
+  match name {
+    "classic" => Ok(Box::new(Classic::new(eval_params()))),
+    "material" => Ok(Box::new(Material { params: eval_params().material })),
+    // without a network there's nothing to run, the classic evaluation takes its place
+    "nnue" => match network() {
+      Some(network) => Ok(Box::new(Nnue::new(network))),
+      None => Ok(Box::new(Classic::new(eval_params()))),
+    },
+    _ => Err(ChessError::UnknownEvaluator(name.to_string())),
+  }
+}
+
+// terms the classic evaluation adds up, each one is computed for both sides
+pub const TERMS: [&str; 7] = ["material", "piece_squares", "pawn_structure", "passed_pawns", "king_safety", "mobility", "pieces"];
+
+// value of each term for the side of color, in the order of TERMS
+pub fn side_terms(pos: &Chess, color: Color, params: &EvalParams) -> [Taper; 7] {
+  let board = pos.board();
+  let material = material(board, color, &params.material);
+  [
+    taper(material, material),
+    pst::piece_squares(board, color, &params.pst),
+    pawns::pawn_structure(board, color, &params.pawns),
+    pawns::passed_pawns(board, pos.turn(), color, &params.passed),
+    king::king_safety(board, color, &params.king),
+    mobility::mobility(board, color, &params.mobility),
+    pieces::pieces(board, color, &params.pieces),
+  ]
+}
+
+// static score of the position from the point of view of the side to move, used by Classic
+pub fn evaluate(pos: &Chess, params: &EvalParams) -> i64 {
+  let white = side_terms(pos, Color::White, params);
+  let black = side_terms(pos, Color::Black, params);
+  let mut score = Taper::default();
+  for (white, black) in white.iter().zip(black.iter()) {
+    score += *white - *black;
+  }
+
+  let score = score.blend(game_phase(pos.board()));
+  if pos.turn() == Color::White {
+    score
+  } else {
+    -score
+  }
+}
+
+// material only evaluation, from the point of view of the side to move
+pub fn evaluate_material(pos: &Chess, params: &MaterialParams) -> i64 {
+  let score = material(pos.board(), Color::White, params) - material(pos.board(), Color::Black, params);
+  if pos.turn() == Color::White {
+    score
   } else {
-    -value
+    -score
+  }
+}
+
+// material of the side of color, without the king that both sides always have
+pub fn material(board: &Board, color: Color, params: &MaterialParams) -> i64 {
+  let mut score: i64 = 0;
+  for square in board.by_color(color) & !board.kings() {
+    if let Some(role) = board.role_at(square) {
+      score += params.value(role);
+    }
   }
+  score
+}
+
+// from PHASE_MAX with all the pieces down to 0 with only kings and pawns,
+// extra pieces from promotions can't push it over PHASE_MAX
+pub fn game_phase(board: &Board) -> i64 {
+  let phase = board.knights().count() + board.bishops().count() + 2 * board.rooks().count() + 4 * board.queens().count();
+  (phase as i64).min(PHASE_MAX)
 }
-// evaluate("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
 
 #[cfg(test)]
 mod test {
   use super::*;
+  use crate::utils::parse_fen;
+
+  fn evaluate_fen(fen_str: &str) -> i64 {
+    evaluate(&parse_fen(fen_str).unwrap(), &EvalParams::default())
+  }
+
+  fn material_fen(fen_str: &str) -> i64 {
+    evaluate_material(&parse_fen(fen_str).unwrap(), &MaterialParams::default())
+  }
+
+  // same position with the colors swapped, black plays what white played
+  fn mirror_fen(fen_str: &str) -> String {
+    let fields: Vec<&str> = fen_str.split(' ').collect();
+    let swap_case = |text: &str| -> String {
+      text.chars().map(|c| if c.is_ascii_uppercase() { c.to_ascii_lowercase() } else { c.to_ascii_uppercase() }).collect()
+    };
+    let board: Vec<String> = fields[0].split('/').rev().map(swap_case).collect();
+    let turn = if fields[1] == "w" { "b" } else { "w" };
+    let castling = if fields[2] == "-" { "-".to_string() } else {
+      let swapped = swap_case(fields[2]);
+      let mut rights: Vec<char> = swapped.chars().collect();
+      rights.sort_by_key(|c| (c.is_ascii_lowercase(), *c));
+      rights.into_iter().collect()
+    };
+    let ep = if fields[3] == "-" { "-".to_string() } else {
+      fields[3].replace('3', "x").replace('6', "3").replace('x', "6")
+    };
+    format!("{} {} {} {} {} {}", board.join("/"), turn, castling, ep, fields[4], fields[5])
+  }
 
   #[test]
   fn starting_pos_eval() {
-    assert_eq!(evaluate("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"), 0);
+    assert_eq!(evaluate_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"), 0);
   }
 
   #[test]
   fn pos_eval_white_turn_white_advantage() {
     // white turn
     // white has one pawn more
-    assert_eq!(evaluate("rnbqkbnr/pppp1ppp/8/4P3/8/8/PPP1PPPP/RNBQKBNR w KQkq - 0 1"), 100);
+    assert_eq!(material_fen("rnbqkbnr/pppp1ppp/8/4P3/8/8/PPP1PPPP/RNBQKBNR w KQkq - 0 1"), 100);
   }
 
   #[test]
   fn pos_eval_white_turn_black_advantage() {
     // white turn
     // white has one pawn less
-    assert_eq!(evaluate("rnbqkbnr/pppp1ppp/8/8/3p4/8/PPP1PPPP/RNBQKBNR w KQkq - 0 1"), -100);
+    assert_eq!(material_fen("rnbqkbnr/pppp1ppp/8/8/3p4/8/PPP1PPPP/RNBQKBNR w KQkq - 0 1"), -100);
   }
 
   #[test]
   fn pos_eval_black_turn_white_advantage() {
     // white turn
     // white has one pawn more
-    assert_eq!(evaluate("rnbqkbnr/pppp1ppp/8/4P3/8/8/PPP1PPPP/RNBQKBNR b KQkq - 0 2"), -100);
+    assert_eq!(material_fen("rnbqkbnr/pppp1ppp/8/4P3/8/8/PPP1PPPP/RNBQKBNR b KQkq - 0 2"), -100);
   }
 
   #[test]
   fn pos_eval_black_turn_black_advantage() {
     // black turn
     // white has one pawn less
-    assert_eq!(evaluate("rnbqkbnr/pppp1ppp/8/8/3pP3/8/PPP2PPP/RNBQKBNR b KQkq - 0 3"), 100);
+    assert_eq!(material_fen("rnbqkbnr/pppp1ppp/8/8/3pP3/8/PPP2PPP/RNBQKBNR b KQkq - 0 3"), 100);
+  }
+
+  // the positional terms move the full evaluation around, but a pawn up is still worth about a pawn
+  fn assert_about_a_pawn(score: i64) {
+    assert!((50..=200).contains(&score), "{}", score);
   }
 
   #[test]
-  fn pos_eval_checkmate_white_wins() {
-    // black turn
-    // white wins
-    assert_eq!(evaluate("1Q2k3/2R5/8/8/8/8/8/4K3 b - - 1 1"), -10_000);
+  fn tapered_eval_pawn_up() {
+    // the same positions as the pos_eval tests, from the point of view of the side a pawn up
+    assert_about_a_pawn(evaluate_fen("rnbqkbnr/pppp1ppp/8/4P3/8/8/PPP1PPPP/RNBQKBNR w KQkq - 0 1"));
+    assert_about_a_pawn(-evaluate_fen("rnbqkbnr/pppp1ppp/8/8/3p4/8/PPP1PPPP/RNBQKBNR w KQkq - 0 1"));
+    assert_about_a_pawn(-evaluate_fen("rnbqkbnr/pppp1ppp/8/4P3/8/8/PPP1PPPP/RNBQKBNR b KQkq - 0 2"));
+    assert_about_a_pawn(evaluate_fen("rnbqkbnr/pppp1ppp/8/8/3pP3/8/PPP2PPP/RNBQKBNR b KQkq - 0 3"));
   }
 
   #[test]
-  fn pos_eval_checkmate_black_wins() {
-    // white turn
-    // black wins
-    assert_eq!(evaluate("4k3/8/8/8/8/8/1q6/2r1K3 w - - 2 2"), -10_000);
+  fn tapered_eval_more_material_is_better() {
+    let pawn_up = evaluate_fen("rnbqkbnr/pppp1ppp/8/4P3/8/8/PPP1PPPP/RNBQKBNR w KQkq - 0 1");
+    let knight_up = evaluate_fen("rnbqkb1r/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+    let queen_up = evaluate_fen("rnb1kbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+    assert!(0 < pawn_up && pawn_up < knight_up && knight_up < queen_up);
+  }
+
+  #[test]
+  fn symmetric_for_both_colors() {
+    let fens = [
+      "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+      "rnbqkbnr/pppp1ppp/8/8/3pP3/8/PPP2PPP/RNBQKBNR b KQkq - 0 3",
+      "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+      "r4rk1/1pp1qppp/p1np1n2/2b1p1B1/2B1P1b1/P1NP1N2/1PP1QPPP/R4RK1 w - - 0 10",
+    ];
+    for fen in fens {
+      assert_eq!(evaluate_fen(fen), evaluate_fen(&mirror_fen(fen)), "{}", fen);
+    }
+  }
+
+  #[test]
+  fn phase_from_material() {
+    let start = parse_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1").unwrap();
+    assert_eq!(game_phase(start.board()), PHASE_MAX);
+    let pawn_ending = parse_fen("4k3/pppp4/8/8/8/8/4PPPP/4K3 w - - 0 1").unwrap();
+    assert_eq!(game_phase(pawn_ending.board()), 0);
+    assert_eq!(taper(100, 20).blend(PHASE_MAX), 100);
+    assert_eq!(taper(100, 20).blend(0), 20);
+    assert_eq!(taper(100, 20).blend(PHASE_MAX / 2), 60);
+  }
+
+  // the network and the weights are global, tests that change them hold this lock so they don't run
+  // together and put back what was there before, the other tests only use their defaults
+  static GLOBALS: Mutex<()> = Mutex::new(());
+
+  #[test]
+  fn evaluators_by_name() {
+    let pos = parse_fen("rnbqkbnr/pppp1ppp/8/4P3/8/8/PPP1PPPP/RNBQKBNR w KQkq - 0 1").unwrap();
+    let _globals = GLOBALS.lock().unwrap_or_else(|err| err.into_inner());
+    for name in EVALUATORS {
+      let mut evaluator = evaluator_from_name(name).unwrap();
+      // no network is loaded
+      assert_eq!(evaluator.name(), if name == "nnue" { "classic" } else { name });
+      assert!(evaluator.evaluate(&pos) > 0);
+    }
+    assert_eq!(evaluator_from_name("material").unwrap().evaluate(&pos), 100);
+    assert_eq!(evaluator_from_name("neural").err(), Some(ChessError::UnknownEvaluator("neural".to_string())));
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn nnue_evaluator() {
+    let pos = parse_fen("rnbqkbnr/pppp1ppp/8/4P3/8/8/PPP1PPPP/RNBQKBNR w KQkq - 0 1").unwrap();
+    let network = Network::new(1, vec![0; 768], vec![0], vec![0, 0], 0).unwrap();
+    let mut nnue = Nnue::new(std::sync::Arc::new(network));
+    assert_eq!(nnue.name(), "nnue");
+    assert_eq!(nnue.evaluate(&pos), 0);
+  }
+
+  #[test]
+  fn network_by_name() {
+    let _globals = GLOBALS.lock().unwrap_or_else(|err| err.into_inner());
+    let previous = network();
+    let network = Network::new(1, vec![0; 768], vec![0], vec![0, 0], 0).unwrap();
+    load_network(&network.to_bytes()).unwrap();
+    let loaded = evaluator_from_name("nnue").unwrap().name();
+    // a bad network keeps the one loaded
+    let invalid = load_network(b"NNUE").unwrap_err().code();
+    let kept = evaluator_from_name("nnue").unwrap().name();
+    unload_network();
+    let unloaded = evaluator_from_name("nnue").unwrap().name();
+    if let Some(previous) = previous {
+      load_network(&previous.to_bytes()).unwrap();
+    }
+    assert_eq!(loaded, "nnue");
+    assert_eq!(invalid, "INVALID_NETWORK");
+    assert_eq!(kept, "nnue");
+    assert_eq!(unloaded, "classic");
+  }
+
+  #[test]
+  fn params_from_json() {
+    let params = EvalParams::default();
+    assert_eq!(EvalParams::from_json(&params.to_json()).unwrap(), params);
+    // missing terms keep their defaults
+    let params = EvalParams::from_json(r#"{ "material": { "pawn": 120 }, "pieces": { "bishop_pair": { "mg": 0, "eg": 80 } } }"#).unwrap();
+    assert_eq!(params.material.pawn, 120);
+    assert_eq!(params.material.queen, 1_000);
+    assert_eq!(params.pieces.bishop_pair, taper(0, 80));
+    assert_eq!(params.pst, PstParams::default());
+    assert_eq!(EvalParams::from_json("{ \"material\": 3 }").unwrap_err().code(), "INVALID_PARAMS");
+    // the weights change the evaluation
+    let pos = parse_fen("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1").unwrap();
+    let mut material = Material { params: params.material.clone() };
+    assert_eq!(material.evaluate(&pos), 120);
+    assert_ne!(evaluate(&pos, &params), evaluate(&pos, &EvalParams::default()));
+  }
+}
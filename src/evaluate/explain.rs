@@ -0,0 +1,180 @@
+use serde::{Deserialize, Serialize};
+use shakmaty::*;
+
+use super::{eval_params, game_phase, side_terms, EvalParams, Taper, PHASE_MAX, TERMS};
+use crate::error::ChessError;
+use crate::utils::{parse_fen, to_fen};
+
+// names of the terms in the table, in the order of TERMS
+const LABELS: [&str; 7] = ["Material", "Piece squares", "Pawn structure", "Passed pawns", "King safety", "Mobility", "Pieces"];
+
+// value of a term in the middlegame, in the endgame and blended by the phase of the position
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TermScore {
+  pub mg: i64,
+  pub eg: i64,
+  pub score: i64,
+}
+impl TermScore {
+  fn new(value: Taper, phase: i64) -> TermScore {
+    TermScore { mg: value.mg, eg: value.eg, score: value.blend(phase) }
+  }
+}
+
+// blended scores of values that add up to the blend of their sum: blending each one on its own
+// rounds each of them, so the rounding of the sum so far is carried over to the next one instead
+fn blend_all(values: &[Taper], phase: i64) -> Vec<TermScore> {
+  let mut sum = Taper::default();
+  let mut blended = 0;
+  values
+    .iter()
+    .map(|value| {
+      sum += *value;
+      let score = sum.blend(phase) - blended;
+      blended += score;
+      TermScore { mg: value.mg, eg: value.eg, score }
+    })
+    .collect()
+}
+
+// a term for each side, the total is white minus black
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Term {
+  pub name: String,
+  pub white: TermScore,
+  pub black: TermScore,
+  pub total: TermScore,
+}
+impl Term {
+  fn new(name: &str, white: Taper, black: Taper, phase: i64) -> Term {
+    Term {
+      name: name.to_string(),
+      white: TermScore::new(white, phase),
+      black: TermScore::new(black, phase),
+      total: TermScore::new(white - black, phase),
+    }
+  }
+}
+
+// the terms evaluate adds up, in centipawns from white's point of view
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+  pub fen: String,
+  // from PHASE_MAX in the opening to 0 in pawn endings
+  pub phase: i64,
+  pub terms: Vec<Term>,
+  // sum of the terms
+  pub total: Term,
+  // the evaluation from the point of view of the side to move, as the searches see it
+  pub score: i64,
+  // the same numbers as a text table, in pawns
+  pub table: String,
+}
+
+// breakdown of the classic evaluation of fen with the weights in use
+pub fn explain_evaluation(fen_str: &str) -> Result<Explanation, ChessError> {
+  Ok(explain(&parse_fen(fen_str)?, &eval_params()))
+}
+
+pub fn explain(pos: &Chess, params: &EvalParams) -> Explanation {
+  let phase = game_phase(pos.board());
+  let white = side_terms(pos, Color::White, params);
+  let black = side_terms(pos, Color::Black, params);
+  let difference: Vec<Taper> = white.iter().zip(&black).map(|(white, black)| *white - *black).collect();
+  let (white_scores, black_scores, total_scores) = (blend_all(&white, phase), blend_all(&black, phase), blend_all(&difference, phase));
+  let terms: Vec<Term> = TERMS
+    .iter()
+    .enumerate()
+    .map(|(i, name)| Term { name: name.to_string(), white: white_scores[i], black: black_scores[i], total: total_scores[i] })
+    .collect();
+  let total = Term::new("total", white.iter().fold(Taper::default(), |sum, term| sum + *term), black.iter().fold(Taper::default(), |sum, term| sum + *term), phase);
+  let score = if pos.turn() == Color::White { total.total.score } else { -total.total.score };
+  let mut explanation = Explanation { fen: to_fen(pos), phase, terms, total, score, table: String::new() };
+  explanation.table = table(&explanation);
+  explanation
+}
+
+fn pawns(cp: i64) -> String {
+  format!("{:.2}", cp as f64 / 100.0)
+}
+
+fn row(label: &str, term: &Term) -> String {
+  format!(
+    "{:>15} | {:>6} {:>6} | {:>6} {:>6} | {:>6} {:>6}\n",
+    label, pawns(term.white.mg), pawns(term.white.eg), pawns(term.black.mg), pawns(term.black.eg), pawns(term.total.mg), pawns(term.total.eg)
+  )
+}
+
+fn table(explanation: &Explanation) -> String {
+  let separator = format!("{:-<16}+{:-<15}+{:-<15}+{:-<14}\n", "", "", "", "");
+  let mut table = String::new();
+  table += &format!("{:>15} | {:^13} | {:^13} | {:^13}\n", "Term", "White", "Black", "Total");
+  table += &format!("{:>15} | {:>6} {:>6} | {:>6} {:>6} | {:>6} {:>6}\n", "", "MG", "EG", "MG", "EG", "MG", "EG");
+  table += &separator;
+  for (label, term) in LABELS.iter().zip(&explanation.terms) {
+    table += &row(label, term);
+  }
+  table += &separator;
+  table += &row("Total", &explanation.total);
+  let turn = if explanation.fen.split(' ').nth(1) == Some("w") { "white" } else { "black" };
+  table += &format!("\nPhase: {}/{}\n", explanation.phase, PHASE_MAX);
+  table += &format!("Evaluation: {} for white, {} for the side to move ({})\n", pawns(explanation.total.total.score), pawns(explanation.score), turn);
+  table
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::evaluate::evaluate;
+
+  #[test]
+  fn terms_add_up() {
+    let fens = [
+      "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+      "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 b - - 0 1",
+    ];
+    let params = EvalParams::default();
+    for fen in fens {
+      let pos = parse_fen(fen).unwrap();
+      let explanation = explain(&pos, &params);
+      assert_eq!(explanation.score, evaluate(&pos, &params));
+      assert_eq!(explanation.terms.len(), TERMS.len());
+      let total_mg: i64 = explanation.terms.iter().map(|term| term.total.mg).sum();
+      assert_eq!(total_mg, explanation.total.total.mg);
+      // the blended scores too, for each side and for the total
+      let sum = |side: fn(&Term) -> TermScore| explanation.terms.iter().map(|term| side(term).score).sum::<i64>();
+      assert_eq!(sum(|term| term.white), explanation.total.white.score);
+      assert_eq!(sum(|term| term.black), explanation.total.black.score);
+      assert_eq!(sum(|term| term.total), explanation.total.total.score);
+      for term in &explanation.terms {
+        assert_eq!(term.total.eg, term.white.eg - term.black.eg);
+      }
+    }
+  }
+
+  #[test]
+  fn side_scores() {
+    // white has a knight, black a passed pawn, black to move
+    let explanation = explain_evaluation("4k3/8/8/8/8/p7/8/1N2K3 b - - 0 1").unwrap();
+    let term = |name: &str| explanation.terms.iter().find(|term| term.name == name).unwrap().clone();
+    assert_eq!(term("material").white.score, 300);
+    assert_eq!(term("material").black.score, 100);
+    assert_eq!(term("passed_pawns").white.score, 0);
+    assert!(term("passed_pawns").black.score > 0);
+    assert_eq!(explanation.phase, 1);
+    assert_eq!(explanation.score, -explanation.total.total.score);
+    assert_eq!(explain_evaluation("not a fen").unwrap_err().code(), "INVALID_FEN");
+  }
+
+  #[test]
+  fn readable_table() {
+    let explanation = explain_evaluation("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1").unwrap();
+    let lines: Vec<&str> = explanation.table.lines().collect();
+    assert_eq!(lines[0].trim(), "Term |     White     |     Black     |     Total");
+    assert!(lines.iter().any(|line| line.trim_start().starts_with("Material |  41.00  41.00 |  41.00  41.00 |   0.00   0.00")));
+    assert!(explanation.table.contains("Phase: 24/24"));
+    assert!(explanation.table.contains("Evaluation: 0.00 for white, 0.00 for the side to move (white)"));
+    // every line of the table has the same width
+    assert!(lines[..11].iter().all(|line| line.len() == lines[0].len()));
+  }
+}
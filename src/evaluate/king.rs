@@ -0,0 +1,202 @@
+use serde::{Deserialize, Serialize};
+use shakmaty::*;
+
+use super::{taper, Taper};
+
+// the king only needs a shelter while there are pieces to attack it, so the terms
+// are middlegame only and fade away with the game phase as material comes off
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct KingParams {
+  pub shield_close: Taper,
+  pub shield_far: Taper,
+  pub shield_missing: Taper,
+  // enemy pawns two or three ranks in front of the king, about to open it up
+  pub storm: Taper,
+  pub semi_open_file: Taper,
+  pub open_file: Taper,
+  // multiplied by the danger, that grows with the square of the attack units
+  pub attack: Taper,
+  pub max_danger: i64,
+  // attack units for each square of the king zone a piece hits
+  pub knight_attack: i64,
+  pub bishop_attack: i64,
+  pub rook_attack: i64,
+  pub queen_attack: i64,
+}
+impl Default for KingParams {
+  fn default() -> Self {
+    KingParams {
+      shield_close: taper(12, 0),
+      shield_far: taper(6, 0),
+      shield_missing: taper(-12, 0),
+      storm: taper(-10, 0),
+      semi_open_file: taper(-12, 0),
+      open_file: taper(-20, 0),
+      attack: taper(-1, 0),
+      max_danger: 500,
+      knight_attack: 2,
+      bishop_attack: 2,
+      rook_attack: 3,
+      queen_attack: 5,
+    }
+  }
+}
+impl KingParams {
+  fn attack_weight(&self, role: Role) -> i64 {
+    match role {
+      Role::Knight => self.knight_attack,
+      Role::Bishop => self.bishop_attack,
+      Role::Rook => self.rook_attack,
+      Role::Queen => self.queen_attack,
+      Role::Pawn | Role::King => 0,
+    }
+  }
+}
+
+// king safety terms of a side
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct KingCounts {
+  // files of the shield, the king file and the ones next to it, with a pawn one or two ranks
+  // in front of the king or without a pawn in front
+  pub shield_close: i64,
+  pub shield_far: i64,
+  pub shield_missing: i64,
+  pub storm: i64,
+  // shield files without own pawns, and without any pawn
+  pub semi_open_files: i64,
+  pub open_files: i64,
+  // enemy pieces hitting the king zone and their attack units
+  pub attackers: i64,
+  pub attack_units: i64,
+}
+
+// king safety of the side of color
+pub fn king_safety(board: &Board, color: Color, params: &KingParams) -> Taper {
+  king_score(&king_counts(board, color, params), params)
+}
+
+fn king_score(counts: &KingCounts, params: &KingParams) -> Taper {
+  params.shield_close * counts.shield_close
+    + params.shield_far * counts.shield_far
+    + params.shield_missing * counts.shield_missing
+    + params.storm * counts.storm
+    + params.semi_open_file * counts.semi_open_files
+    + params.open_file * counts.open_files
+    + params.attack * danger(counts, params)
+}
+
+// a single attacker is rarely dangerous, then danger grows faster than the attack units
+fn danger(counts: &KingCounts, params: &KingParams) -> i64 {
+  if counts.attackers < 2 {
+    0
+  } else {
+    (counts.attack_units * counts.attack_units / 4).min(params.max_danger)
+  }
+}
+
+pub fn king_counts(board: &Board, color: Color, params: &KingParams) -> KingCounts {
+  let mut counts = KingCounts::default();
+  let king = match board.king_of(color) {
+    Some(king) => king,
+    None => return counts,
+  };
+  let own_pawns = board.pawns() & board.by_color(color);
+  let enemy_pawns = board.pawns() & board.by_color(!color);
+  let king_rank = color.relative_rank(king.rank()) as i64;
+
+  for file in [king.file().offset(-1), Some(king.file()), king.file().offset(1)].iter().flatten().copied() {
+    let file_bb = Bitboard::from_file(file);
+    // ranks in front of the king of own and enemy pawns on this file
+    let in_front = |pawns: Bitboard| (pawns & file_bb).into_iter().map(|sq| color.relative_rank(sq.rank()) as i64 - king_rank).filter(|ahead| *ahead > 0);
+    match in_front(own_pawns).min() {
+      Some(1) => counts.shield_close += 1,
+      Some(2) => counts.shield_far += 1,
+      _ => counts.shield_missing += 1,
+    }
+    counts.storm += in_front(enemy_pawns).filter(|ahead| *ahead == 2 || *ahead == 3).count() as i64;
+    if (own_pawns & file_bb).is_empty() {
+      if (enemy_pawns & file_bb).is_empty() {
+        counts.open_files += 1;
+      } else {
+        counts.semi_open_files += 1;
+      }
+    }
+  }
+
+  let zone = attacks::king_attacks(king).with(king);
+  for square in board.by_color(!color) & !board.pawns() & !board.kings() {
+    let hits = (board.attacks_from(square) & zone).count() as i64;
+    if hits > 0 {
+      let role = board.role_at(square).expect("piece on square");
+      counts.attackers += 1;
+      counts.attack_units += params.attack_weight(role) * hits;
+    }
+  }
+  counts
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::utils::parse_fen;
+
+  fn counts(fen_str: &str, color: Color) -> KingCounts {
+    king_counts(parse_fen(fen_str).unwrap().board(), color, &KingParams::default())
+  }
+
+  #[test]
+  fn pawn_shield() {
+    // castled king behind f2 g2 h2
+    let intact = counts("6k1/5ppp/8/8/8/8/5PPP/6K1 w - - 0 1", Color::White);
+    assert_eq!((intact.shield_close, intact.shield_far, intact.shield_missing), (3, 0, 0));
+    // after g3 and h4 one pawn is still close, one is far and one has gone too far
+    let pushed = counts("6k1/5ppp/8/8/7P/6P1/5P2/6K1 w - - 0 1", Color::White);
+    assert_eq!((pushed.shield_close, pushed.shield_far, pushed.shield_missing), (1, 1, 1));
+    assert!(king_score(&intact, &KingParams::default()).mg > king_score(&pushed, &KingParams::default()).mg);
+    // same for black
+    let black = counts("6k1/5ppp/8/8/8/8/5PPP/6K1 w - - 0 1", Color::Black);
+    assert_eq!(black.shield_close, 3);
+  }
+
+  #[test]
+  fn pawn_storm() {
+    // the black g and h pawns are coming for the white king
+    let white = counts("6k1/5p2/8/8/6pp/8/5PPP/6K1 w - - 0 1", Color::White);
+    assert_eq!(white.storm, 2);
+    assert_eq!(counts("6k1/5p2/8/8/6pp/8/5PPP/6K1 w - - 0 1", Color::Black).storm, 0);
+  }
+
+  #[test]
+  fn open_files() {
+    // no pawns on the g file and only a black one on the h file
+    let white = counts("6k1/5p1p/8/8/8/8/5P2/6K1 w - - 0 1", Color::White);
+    assert_eq!((white.open_files, white.semi_open_files), (1, 1));
+    // a king on the edge only has two shield files
+    let corner = counts("6k1/8/8/8/8/8/8/7K w - - 0 1", Color::White);
+    assert_eq!(corner.open_files, 2);
+  }
+
+  #[test]
+  fn king_attackers() {
+    // the knight hits f2 and h2, the queen h1, h2 and f2, the rook on a8 doesn't reach the zone
+    let fen = "r5k1/8/8/8/6nq/8/5PP1/6K1 w - - 0 1";
+    let white = counts(fen, Color::White);
+    assert_eq!((white.attackers, white.attack_units), (2, 2 * 2 + 5 * 3));
+    assert!(king_score(&white, &KingParams::default()).mg < 0);
+    assert_eq!(counts(fen, Color::Black).attackers, 0);
+    // a lone attacker isn't enough
+    let lone = counts("r5k1/8/8/8/7q/8/5PP1/6K1 w - - 0 1", Color::White);
+    assert_eq!(lone.attackers, 1);
+    assert_eq!(danger(&lone, &KingParams::default()), 0);
+  }
+
+  #[test]
+  fn fades_with_material() {
+    let board = parse_fen("6k1/5ppp/8/8/8/8/8/6K1 w - - 0 1").unwrap();
+    let params = KingParams::default();
+    let score = king_safety(board.board(), Color::White, &params) - king_safety(board.board(), Color::Black, &params);
+    assert!(score.mg < 0);
+    assert_eq!(score.blend(0), 0);
+  }
+}
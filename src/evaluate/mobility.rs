@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+use shakmaty::*;
+
+use super::{taper, Taper};
+
+// value of each safe square a piece can move to, knights and bishops need to be active
+// from the start, rooks and queens mostly once the board opens up
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct MobilityParams {
+  pub knight: Taper,
+  pub bishop: Taper,
+  pub rook: Taper,
+  pub queen: Taper,
+}
+impl Default for MobilityParams {
+  fn default() -> Self {
+    MobilityParams {
+      knight: taper(4, 4),
+      bishop: taper(5, 5),
+      rook: taper(2, 4),
+      queen: taper(1, 2),
+    }
+  }
+}
+
+// safe squares each type of piece of a side can move to
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MobilityCounts {
+  pub knight: i64,
+  pub bishop: i64,
+  pub rook: i64,
+  pub queen: i64,
+}
+
+// mobility of the pieces of color
+pub fn mobility(board: &Board, color: Color, params: &MobilityParams) -> Taper {
+  mobility_score(&mobility_counts(board, color), params)
+}
+
+fn mobility_score(counts: &MobilityCounts, params: &MobilityParams) -> Taper {
+  params.knight * counts.knight
+    + params.bishop * counts.bishop
+    + params.rook * counts.rook
+    + params.queen * counts.queen
+}
+
+// pseudo-legal moves to squares that are empty or hold an enemy piece,
+// squares attacked by enemy pawns don't count, a piece going there would be lost
+pub fn mobility_counts(board: &Board, color: Color) -> MobilityCounts {
+  let enemy_pawns = board.pawns() & board.by_color(!color);
+  let pawn_attacks = enemy_pawns.into_iter().fold(Bitboard::EMPTY, |attacked, square| attacked | attacks::pawn_attacks(!color, square));
+  let safe = !board.by_color(color) & !pawn_attacks;
+
+  let mut counts = MobilityCounts::default();
+  for square in board.by_color(color) {
+    let moves = (board.attacks_from(square) & safe).count() as i64;
+    match board.role_at(square) {
+      Some(Role::Knight) => counts.knight += moves,
+      Some(Role::Bishop) => counts.bishop += moves,
+      Some(Role::Rook) => counts.rook += moves,
+      Some(Role::Queen) => counts.queen += moves,
+      _ => {},
+    }
+  }
+  counts
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::utils::parse_fen;
+
+  fn counts(fen_str: &str, color: Color) -> MobilityCounts {
+    mobility_counts(parse_fen(fen_str).unwrap().board(), color)
+  }
+
+  #[test]
+  fn piece_mobility() {
+    let start = counts("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", Color::White);
+    assert_eq!(start, MobilityCounts { knight: 4, bishop: 0, rook: 0, queen: 0 });
+    // the knight in the center blocks the bishop, that is also blocked by the queen in the corner
+    let white = counts("4k3/8/8/8/3N4/8/1B6/Q3K2R w - - 0 1", Color::White);
+    assert_eq!(white, MobilityCounts { knight: 8, bishop: 3, rook: 9, queen: 10 });
+  }
+
+  #[test]
+  fn buried_bishop() {
+    // the bishop stuck behind its own pawns moves less than the one outside the chain
+    let buried = counts("4k3/8/8/8/3P4/2P5/1B6/4K3 w - - 0 1", Color::White);
+    let active = counts("4k3/8/8/5B2/3P4/2P5/8/4K3 w - - 0 1", Color::White);
+    assert!(active.bishop > buried.bishop);
+    assert!(mobility_score(&active, &MobilityParams::default()).mg > mobility_score(&buried, &MobilityParams::default()).mg);
+  }
+
+  #[test]
+  fn squares_attacked_by_pawns() {
+    // the black pawns on c6 and e6 cover b5, d5 and f5, only the captures stay safe
+    let covered = counts("4k3/8/2p1p3/8/3N4/8/8/4K3 w - - 0 1", Color::White);
+    let free = counts("4k3/8/8/8/3N4/8/8/4K3 w - - 0 1", Color::White);
+    assert_eq!(free.knight, 8);
+    assert_eq!(covered.knight, 6);
+  }
+}
@@ -0,0 +1,340 @@
+use std::sync::{Arc, Mutex};
+
+use shakmaty::*;
+
+use super::Evaluator;
+use crate::error::ChessError;
+
+// network file: "NNUE", the format version and the size of the hidden layer as u32, then little endian
+// i16 weights: INPUTS x hidden for the inputs (all the hidden neurons of an input are next to each other),
+// the hidden biases, 2 x hidden for the output (side to move first) and last the output bias as i32
+const MAGIC: &[u8; 4] = b"NNUE";
+const FORMAT_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 12;
+// one input for each role of each color on each square
+pub const INPUTS: usize = 2 * 6 * 64;
+pub const MAX_HIDDEN: usize = 4096;
+// the hidden layer is clipped to 0..=QA and the output weights are scaled by QB,
+// SCALE turns the output into centipawns
+const QA: i64 = 255;
+const QB: i64 = 64;
+const SCALE: i64 = 400;
+// past this many changed squares computing the accumulator again is cheaper than updating it
+const MAX_CHANGES: usize = 16;
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+  u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+// index of the accumulator of each perspective
+fn side(color: Color) -> usize {
+  color.fold_wb(0, 1)
+}
+
+// input of piece on square as seen by perspective: the pieces of perspective come first
+// and black sees the board upside down, so both sides share the same weights
+fn feature(perspective: Color, piece: Piece, square: Square) -> usize {
+  let square = if perspective == Color::White { square } else { square.flip_vertical() };
+  let owner = if piece.color == perspective { 0 } else { 1 };
+  owner * 6 * 64 + (piece.role as usize - 1) * 64 + square as usize
+}
+
+// 768 inputs -> hidden -> 1, the hidden layer is computed for both sides and the output sees
+// the one of the side to move first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Network {
+  hidden: usize,
+  input_weights: Vec<i16>,
+  input_biases: Vec<i16>,
+  output_weights: Vec<i16>,
+  output_bias: i32,
+}
+impl Network {
+  pub fn new(hidden: usize, input_weights: Vec<i16>, input_biases: Vec<i16>, output_weights: Vec<i16>, output_bias: i32) -> Result<Network, ChessError> {
+    if hidden == 0 || hidden > MAX_HIDDEN {
+      return Err(ChessError::InvalidNetwork(format!("hidden layer of {} neurons, it has to be between 1 and {}", hidden, MAX_HIDDEN)));
+    }
+    if input_weights.len() != INPUTS * hidden || input_biases.len() != hidden || output_weights.len() != 2 * hidden {
+      return Err(ChessError::InvalidNetwork("wrong number of weights".to_string()));
+    }
+    Ok(Network { hidden, input_weights, input_biases, output_weights, output_bias })
+  }
+
+  pub fn from_bytes(bytes: &[u8]) -> Result<Network, ChessError> {
+    let invalid = |reason: &str| ChessError::InvalidNetwork(reason.to_string());
+    if bytes.len() < HEADER_SIZE || &bytes[0..4] != MAGIC {
+      return Err(invalid("not a network"));
+    }
+    let version = read_u32(bytes, 4);
+    if version != FORMAT_VERSION {
+      return Err(ChessError::InvalidNetwork(format!("unsupported version {}, expected {}", version, FORMAT_VERSION)));
+    }
+    let hidden = read_u32(bytes, 8) as usize;
+    if hidden == 0 || hidden > MAX_HIDDEN {
+      return Err(invalid("invalid header"));
+    }
+    let size = HEADER_SIZE + 2 * (INPUTS * hidden + hidden + 2 * hidden) + 4;
+    if bytes.len() != size {
+      return Err(invalid("wrong length"));
+    }
+
+    let mut weights = bytes[HEADER_SIZE..size - 4].chunks_exact(2).map(|chunk| i16::from_le_bytes([chunk[0], chunk[1]]));
+    let input_weights = weights.by_ref().take(INPUTS * hidden).collect();
+    let input_biases = weights.by_ref().take(hidden).collect();
+    let output_weights = weights.collect();
+    Network::new(hidden, input_weights, input_biases, output_weights, read_u32(bytes, size - 4) as i32)
+  }
+
+  // natively the network can be read from a file
+  pub fn from_file(path: &str) -> Result<Network, ChessError> {
+    let bytes = std::fs::read(path).map_err(|err| ChessError::InvalidNetwork(format!("{}: {}", path, err)))?;
+    Network::from_bytes(&bytes)
+  }
+
+  // the format read by from_bytes, for the tools that train or convert networks
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + 2 * (INPUTS + 3) * self.hidden + 4);
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(self.hidden as u32).to_le_bytes());
+    for weight in self.input_weights.iter().chain(&self.input_biases).chain(&self.output_weights) {
+      bytes.extend_from_slice(&weight.to_le_bytes());
+    }
+    bytes.extend_from_slice(&self.output_bias.to_le_bytes());
+    bytes
+  }
+
+  pub fn hidden(&self) -> usize {
+    self.hidden
+  }
+
+  // accumulator of the empty board
+  fn accumulator(&self) -> Accumulator {
+    let values = self.input_biases.iter().map(|&bias| bias as i32).collect::<Vec<_>>();
+    Accumulator { board: Board::empty(), values: [values.clone(), values] }
+  }
+
+  fn add(&self, acc: &mut Accumulator, piece: Piece, square: Square, sign: i32) {
+    for perspective in Color::ALL {
+      let weights = &self.input_weights[feature(perspective, piece, square) * self.hidden..][..self.hidden];
+      for (value, &weight) in acc.values[side(perspective)].iter_mut().zip(weights) {
+        *value += sign * weight as i32;
+      }
+    }
+  }
+
+  // moves acc to board, removing the inputs of the squares that changed and adding the new ones
+  fn update(&self, acc: &mut Accumulator, board: &Board) {
+    let mut changed = Bitboard::EMPTY;
+    for color in Color::ALL {
+      changed |= acc.board.by_color(color) ^ board.by_color(color);
+    }
+    for role in Role::ALL {
+      changed |= acc.board.by_role(role) ^ board.by_role(role);
+    }
+    if changed.count() > MAX_CHANGES {
+      *acc = self.accumulator();
+      changed = board.occupied();
+    }
+    for square in changed {
+      if let Some(piece) = acc.board.piece_at(square) {
+        self.add(acc, piece, square, -1);
+      }
+      if let Some(piece) = board.piece_at(square) {
+        self.add(acc, piece, square, 1);
+      }
+    }
+    acc.board = board.clone();
+  }
+
+  // score from the point of view of the side to move
+  fn output(&self, acc: &Accumulator, turn: Color) -> i64 {
+    let (us, them) = (&acc.values[side(turn)], &acc.values[side(!turn)]);
+    let (us_weights, them_weights) = self.output_weights.split_at(self.hidden);
+    let mut output = self.output_bias as i64;
+    for (value, &weight) in us.iter().zip(us_weights).chain(them.iter().zip(them_weights)) {
+      output += (*value as i64).clamp(0, QA) * weight as i64;
+    }
+    output * SCALE / (QA * QB)
+  }
+}
+
+// hidden layer before the activation, from the point of view of white and black, for board
+#[derive(Debug, Clone)]
+struct Accumulator {
+  board: Board,
+  values: [Vec<i32>; 2],
+}
+
+// evaluates with a network, the accumulators of the line being searched are kept on a stack,
+// each one is updated from its parent with the few inputs the move changed
+#[derive(Debug, Clone)]
+pub struct Nnue {
+  network: Arc<Network>,
+  // the first len accumulators are the line, the ones after are kept to reuse their memory
+  stack: Vec<Accumulator>,
+  len: usize,
+}
+impl Nnue {
+  pub fn new(network: Arc<Network>) -> Nnue {
+    Nnue { network, stack: vec![], len: 0 }
+  }
+
+  // fills the slot after the top of the stack with the accumulator of board
+  fn prepare(&mut self, board: &Board) {
+    if self.len == self.stack.len() {
+      self.stack.push(self.network.accumulator());
+    }
+    let (line, free) = self.stack.split_at_mut(self.len);
+    let acc = &mut free[0];
+    if let Some(parent) = line.last() {
+      acc.board = parent.board.clone();
+      acc.values[0].copy_from_slice(&parent.values[0]);
+      acc.values[1].copy_from_slice(&parent.values[1]);
+    }
+    self.network.update(acc, board);
+  }
+}
+impl Evaluator for Nnue {
+  fn name(&self) -> &'static str {
+    "nnue"
+  }
+  fn evaluate(&mut self, pos: &Chess) -> i64 {
+    // usually the search has just pushed pos, otherwise it's computed from the top of the stack
+    let index = match self.len.checked_sub(1) {
+      Some(top) if self.stack[top].board == *pos.board() => top,
+      _ => {
+        self.prepare(pos.board());
+        self.len
+      }
+    };
+    self.network.output(&self.stack[index], pos.turn())
+  }
+  fn push(&mut self, pos: &Chess) {
+    self.prepare(pos.board());
+    self.len += 1;
+  }
+  fn pop(&mut self) {
+    self.len = self.len.saturating_sub(1);
+  }
+}
+
+// network used by the nnue evaluators created by name, None until one is loaded
+static NETWORK: Mutex<Option<Arc<Network>>> = Mutex::new(None);
+
+// the nnue evaluators created from now on use the network in bytes
+pub fn load_network(bytes: &[u8]) -> Result<(), ChessError> {
+  let network = Network::from_bytes(bytes)?;
+  *NETWORK.lock().unwrap() = Some(Arc::new(network));
+  Ok(())
+}
+
+pub fn load_network_file(path: &str) -> Result<(), ChessError> {
+  let network = Network::from_file(path)?;
+  *NETWORK.lock().unwrap() = Some(Arc::new(network));
+  Ok(())
+}
+
+// the nnue evaluators created from now on fall back to the classic evaluation
+pub fn unload_network() {
+  *NETWORK.lock().unwrap() = None;
+}
+
+pub fn network() -> Option<Arc<Network>> {
+  NETWORK.lock().unwrap().clone()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use rand::{Rng, SeedableRng};
+  use rand::rngs::SmallRng;
+  use crate::utils::parse_fen;
+
+  fn random_network(hidden: usize, seed: u64) -> Network {
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let mut weights = |count: usize| (0..count).map(|_| rng.gen_range(-64..64)).collect::<Vec<i16>>();
+    Network::new(hidden, weights(INPUTS * hidden), weights(hidden), weights(2 * hidden), 100).unwrap()
+  }
+
+  #[test]
+  fn network_bytes() {
+    let network = random_network(8, 1);
+    let bytes = network.to_bytes();
+    assert_eq!(Network::from_bytes(&bytes).unwrap(), network);
+    assert_eq!(Network::from_bytes(&bytes[..bytes.len() - 1]).unwrap_err(), ChessError::InvalidNetwork("wrong length".to_string()));
+    assert_eq!(Network::from_bytes(b"not a network").unwrap_err().code(), "INVALID_NETWORK");
+    let mut other_version = bytes.clone();
+    other_version[4] = 2;
+    assert_eq!(Network::from_bytes(&other_version).unwrap_err().code(), "INVALID_NETWORK");
+    assert!(Network::from_file("no/such/network.nnue").is_err());
+    assert!(Network::new(0, vec![], vec![], vec![], 0).is_err());
+  }
+
+  #[test]
+  fn integer_inference() {
+    // a single neuron counting the pawns of each side, 40 for each one
+    let mut input_weights = vec![0; INPUTS];
+    for weight in &mut input_weights[..64] {
+      *weight = 40;
+    }
+    let network = Arc::new(Network::new(1, input_weights, vec![0], vec![QB as i16, -QB as i16], 0).unwrap());
+    let mut nnue = Nnue::new(network);
+    // 3 pawns against 1: (120 - 40) * 400 / 255
+    assert_eq!(nnue.evaluate(&parse_fen("4k3/4p3/8/8/8/8/PPP5/4K3 w - - 0 1").unwrap()), 125);
+    assert_eq!(nnue.evaluate(&parse_fen("4k3/4p3/8/8/8/8/PPP5/4K3 b - - 0 1").unwrap()), -125);
+    // the hidden layer is clipped at QA = 255 instead of reaching 320
+    assert_eq!(nnue.evaluate(&parse_fen("4k3/8/8/8/8/8/PPPPPPPP/4K3 w - - 0 1").unwrap()), SCALE);
+  }
+
+  #[test]
+  fn same_for_both_colors() {
+    let mut nnue = Nnue::new(Arc::new(random_network(16, 2)));
+    let white = nnue.evaluate(&parse_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1").unwrap());
+    let black = nnue.evaluate(&parse_fen("r3k2r/pppbbppp/2n2q1P/1P2p3/3pn3/BN2PNP1/P1PPQPB1/R3K2R b KQkq - 0 1").unwrap());
+    assert_eq!(white, black);
+  }
+
+  #[test]
+  fn incremental_updates() {
+    let network = Arc::new(random_network(16, 3));
+    let mut nnue = Nnue::new(network.clone());
+    // castling, en passant, a promotion with capture and captures
+    let mut pos = parse_fen("r3k2r/1P3ppp/8/8/4p3/8/PPPP1PPP/R3K2R w KQkq - 0 1").unwrap();
+    let mut line = vec![pos.clone()];
+    for uci in ["e1g1", "e8g8", "d2d4", "e4d3", "b7a8q", "f8a8", "c2d3"] {
+      let mov = uci.parse::<uci::Uci>().unwrap().to_move(&pos).unwrap();
+      pos.play_unchecked(&mov);
+      nnue.push(&pos);
+      line.push(pos.clone());
+      assert_eq!(nnue.evaluate(&pos), Nnue::new(network.clone()).evaluate(&pos), "after {}", uci);
+    }
+    // going back along the line
+    for pos in line.iter().rev().skip(1) {
+      nnue.pop();
+      assert_eq!(nnue.evaluate(pos), Nnue::new(network.clone()).evaluate(pos));
+    }
+  }
+
+  // computes every accumulator from scratch
+  struct Refresh(Arc<Network>);
+  impl Evaluator for Refresh {
+    fn name(&self) -> &'static str {
+      "refresh"
+    }
+    fn evaluate(&mut self, pos: &Chess) -> i64 {
+      Nnue::new(self.0.clone()).evaluate(pos)
+    }
+  }
+
+  #[test]
+  fn search_with_network() {
+    use crate::algorithms::negamax_a_b_quiescent;
+    let network = Arc::new(random_network(8, 4));
+    let fen = "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3";
+    let incremental = negamax_a_b_quiescent::root(fen, 1, 3, Box::new(Nnue::new(network.clone()))).unwrap();
+    let refresh = negamax_a_b_quiescent::root(fen, 1, 3, Box::new(Refresh(network))).unwrap();
+    assert_eq!(incremental.score, refresh.score);
+    assert_eq!(incremental.pv, refresh.pv);
+  }
+}
@@ -0,0 +1,310 @@
+use serde::{Deserialize, Serialize};
+use shakmaty::*;
+
+use super::{taper, Taper};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct PawnParams {
+  pub doubled: Taper,
+  pub isolated: Taper,
+  pub backward: Taper,
+  pub phalanx: Taper,
+  pub chain: Taper,
+}
+impl Default for PawnParams {
+  fn default() -> Self {
+    PawnParams {
+      doubled: taper(-10, -25),
+      isolated: taper(-10, -15),
+      backward: taper(-8, -12),
+      phalanx: taper(8, 12),
+      chain: taper(10, 8),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct PassedParams {
+  // bonus by relative rank
+  pub rank: [Taper; 8],
+  // the next terms grow with the rank of the pawn, they're multiplied by its relative rank minus one
+  pub blocked: Taper,
+  pub free_path: Taper,
+  // distance in king moves of each king from the square in front of the pawn
+  pub own_king_distance: Taper,
+  pub enemy_king_distance: Taper,
+  // a pawn the enemy king can't catch in a pawn ending is almost a queen
+  pub unstoppable: Taper,
+}
+impl Default for PassedParams {
+  fn default() -> Self {
+    PassedParams {
+      rank: [
+        taper(0, 0), taper(5, 10), taper(10, 15), taper(15, 25),
+        taper(30, 45), taper(50, 75), taper(80, 120), taper(0, 0),
+      ],
+      blocked: taper(-5, -10),
+      free_path: taper(3, 10),
+      own_king_distance: taper(0, -2),
+      enemy_king_distance: taper(0, 5),
+      unstoppable: taper(0, 500),
+    }
+  }
+}
+
+// how many pawns of a side have each weakness or strength
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PawnCounts {
+  // extra pawns on a file, two pawns on the same file count as one doubled
+  pub doubled: i64,
+  // pawns without friendly pawns on the adjacent files
+  pub isolated: i64,
+  // pawns that can't be defended by other pawns and can't advance safely
+  pub backward: i64,
+  // pawns with a friendly pawn beside them on the same rank
+  pub phalanx: i64,
+  // pawns defended by a friendly pawn
+  pub chain: i64,
+}
+
+// pawn structure of the side of color
+pub fn pawn_structure(board: &Board, color: Color, params: &PawnParams) -> Taper {
+  pawn_score(&pawn_counts(board, color), params)
+}
+
+// passed pawns of the side of color, turn is needed by the rule of the square
+pub fn passed_pawns(board: &Board, turn: Color, color: Color, params: &PassedParams) -> Taper {
+  let mut score = Taper::default();
+  for square in passed(board, color) {
+    score += passed_pawn(board, turn, color, square, params);
+  }
+  score
+}
+
+// pawns of color that no enemy pawn can stop or take on their way to promotion,
+// a pawn behind a friendly one on the same file isn't counted
+pub fn passed(board: &Board, color: Color) -> Bitboard {
+  let own = board.pawns() & board.by_color(color);
+  let enemy = board.pawns() & board.by_color(!color);
+  let mut passed = Bitboard::EMPTY;
+  for square in own {
+    let ahead = !ranks_up_to(color, square.rank());
+    let file = Bitboard::from_file(square.file());
+    if (ahead & (file | adjacent_files(square.file())) & enemy).is_empty() && (ahead & file & own).is_empty() {
+      passed.add(square);
+    }
+  }
+  passed
+}
+
+fn passed_pawn(board: &Board, turn: Color, color: Color, square: Square, params: &PassedParams) -> Taper {
+  let rank = color.relative_rank(square.rank()) as i64;
+  let weight = rank - 1;
+  let mut score = params.rank[rank as usize];
+
+  // a passed pawn is never on the last rank, so there's always a square in front of it
+  let stop = Square::from_coords(square.file(), color.relative_rank(Rank::new(rank as u32 + 1)));
+  let path = !ranks_up_to(color, square.rank()) & Bitboard::from_file(square.file());
+  if board.occupied().contains(stop) {
+    score += params.blocked * weight;
+  } else if (path & board.occupied()).is_empty() {
+    score += params.free_path * weight;
+  }
+
+  if let (Some(own_king), Some(enemy_king)) = (board.king_of(color), board.king_of(!color)) {
+    score += params.own_king_distance * (own_king.distance(stop) as i64 * weight);
+    score += params.enemy_king_distance * (enemy_king.distance(stop) as i64 * weight);
+    // rule of the square: with only the king to stop it, the pawn promotes if the king is too far
+    let defenders = board.by_color(!color) & !board.pawns() & !board.kings();
+    if defenders.is_empty() && (path & board.occupied()).is_empty() {
+      let promotion = Square::from_coords(square.file(), color.relative_rank(Rank::Eighth));
+      // from its starting rank the pawn moves two squares at once
+      let pawn_moves = (7 - rank).min(5);
+      let king_moves = enemy_king.distance(promotion) as i64 - if turn == color { 0 } else { 1 };
+      if pawn_moves < king_moves {
+        score += params.unstoppable;
+      }
+    }
+  }
+  score
+}
+
+fn pawn_score(counts: &PawnCounts, params: &PawnParams) -> Taper {
+  params.doubled * counts.doubled
+    + params.isolated * counts.isolated
+    + params.backward * counts.backward
+    + params.phalanx * counts.phalanx
+    + params.chain * counts.chain
+}
+
+pub fn pawn_counts(board: &Board, color: Color) -> PawnCounts {
+  let own = board.pawns() & board.by_color(color);
+  let enemy = board.pawns() & board.by_color(!color);
+  let mut counts = PawnCounts::default();
+
+  for file in File::ALL {
+    let on_file = (own & Bitboard::from_file(file)).count() as i64;
+    counts.doubled += (on_file - 1).max(0);
+  }
+
+  for square in own {
+    let neighbours = own & adjacent_files(square.file());
+    if neighbours.is_empty() {
+      counts.isolated += 1;
+    } else if (neighbours & ranks_up_to(color, square.rank())).is_empty() {
+      // all the pawns that could defend it are ahead, it's backward if it can't move up without being taken
+      let stop = square.offset(color.fold_wb(8, -8));
+      if stop.is_some_and(|stop| (attacks::pawn_attacks(color, stop) & enemy).any()) {
+        counts.backward += 1;
+      }
+    }
+    if (neighbours & Bitboard::from_rank(square.rank())).any() {
+      counts.phalanx += 1;
+    }
+    // the squares a pawn of the other color on square would attack are the ones defending it
+    if (attacks::pawn_attacks(!color, square) & own).any() {
+      counts.chain += 1;
+    }
+  }
+  counts
+}
+
+pub fn adjacent_files(file: File) -> Bitboard {
+  [file.offset(-1), file.offset(1)].iter().flatten().fold(Bitboard::EMPTY, |files, file| files | Bitboard::from_file(*file))
+}
+
+// ranks from the own back rank of color up to rank, included
+pub fn ranks_up_to(color: Color, rank: Rank) -> Bitboard {
+  Rank::ALL
+    .iter()
+    .filter(|other| color.relative_rank(**other) <= color.relative_rank(rank))
+    .fold(Bitboard::EMPTY, |ranks, other| ranks | Bitboard::from_rank(*other))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::utils::parse_fen;
+
+  fn counts(fen_str: &str, color: Color) -> PawnCounts {
+    pawn_counts(parse_fen(fen_str).unwrap().board(), color)
+  }
+
+  #[test]
+  fn doubled_pawns() {
+    // white has doubled c pawns and tripled h pawns
+    let white = counts("4k3/8/7P/2P5/2P4P/8/7P/4K3 w - - 0 1", Color::White);
+    assert_eq!(white.doubled, 3);
+  }
+
+  #[test]
+  fn isolated_pawns() {
+    // the a and e pawns have no neighbours, the g and h pawns cover each other
+    let white = counts("4k3/8/8/8/8/8/P3P1PP/4K3 w - - 0 1", Color::White);
+    assert_eq!(white.isolated, 2);
+    // black mirrors it
+    let black = counts("4k3/p3p1pp/8/8/8/8/8/4K3 w - - 0 1", Color::Black);
+    assert_eq!(black.isolated, 2);
+  }
+
+  #[test]
+  fn backward_pawns() {
+    // the d3 pawn has its neighbours ahead on c4 and e4 and d4 is covered by the black pawn on c5
+    let white = counts("4k3/8/8/2p5/2P1P3/3P4/8/4K3 w - - 0 1", Color::White);
+    assert_eq!(white.backward, 1);
+    // without the black pawn d3 can move up
+    let white = counts("4k3/8/8/8/2P1P3/3P4/8/4K3 w - - 0 1", Color::White);
+    assert_eq!(white.backward, 0);
+    // same for black, d6 with neighbours on c5 and e5 and d5 covered by e4
+    let black = counts("4k3/8/3p4/2p1p3/4P3/8/8/4K3 w - - 0 1", Color::Black);
+    assert_eq!(black.backward, 1);
+  }
+
+  #[test]
+  fn phalanx_pawns() {
+    // d4 and e4 stand side by side, a2 is alone
+    let white = counts("4k3/8/8/8/3PP3/8/P7/4K3 w - - 0 1", Color::White);
+    assert_eq!(white.phalanx, 2);
+  }
+
+  #[test]
+  fn pawn_chains() {
+    // c3 defends d4 that defends e5, the base of the chain isn't defended
+    let white = counts("4k3/8/8/4P3/3P4/2P5/8/4K3 w - - 0 1", Color::White);
+    assert_eq!(white.chain, 2);
+    let black = counts("4k3/8/2p5/3p4/4p3/8/8/4K3 w - - 0 1", Color::Black);
+    assert_eq!(black.chain, 2);
+    assert_eq!(white.doubled + white.isolated + white.backward + white.phalanx, 0);
+  }
+
+  #[test]
+  fn structure_score() {
+    let board = parse_fen("4k3/pp6/8/8/8/8/P1P5/4K3 w - - 0 1").unwrap();
+    // two isolated white pawns against a black phalanx
+    let params = PawnParams::default();
+    assert_eq!(pawn_structure(board.board(), Color::White, &params), params.isolated * 2);
+    assert_eq!(pawn_structure(board.board(), Color::Black, &params), params.phalanx * 2);
+  }
+
+  fn passed_score(fen_str: &str, color: Color, square: Square) -> Taper {
+    let pos = parse_fen(fen_str).unwrap();
+    assert!(passed(pos.board(), color).contains(square));
+    passed_pawn(pos.board(), pos.turn(), color, square, &PassedParams::default())
+  }
+
+  #[test]
+  fn passed_pawn_detection() {
+    // e5 is passed, b4 is stopped by a6 and the rear h pawn is behind its friend
+    let board = parse_fen("4k3/8/p7/4P2P/1P6/7P/8/4K3 w - - 0 1").unwrap();
+    assert_eq!(passed(board.board(), Color::White), Bitboard::from(Square::E5) | Bitboard::from(Square::H5));
+    // a6 and b4 stop each other
+    assert_eq!(passed(board.board(), Color::Black), Bitboard::EMPTY);
+  }
+
+  #[test]
+  fn passed_pawn_rank() {
+    // kings far away in the same spots, only the rank changes
+    let low = passed_score("k7/8/8/8/8/3P4/8/K7 w - - 0 1", Color::White, Square::D3);
+    let high = passed_score("k7/8/3P4/8/8/8/8/K7 w - - 0 1", Color::White, Square::D6);
+    assert!(high.mg > low.mg && high.eg > low.eg);
+  }
+
+  #[test]
+  fn passed_pawn_blocked() {
+    let free = passed_score("k7/8/8/3P4/8/8/8/K6R w - - 0 1", Color::White, Square::D5);
+    let blocked = passed_score("k7/8/3n4/3P4/8/8/8/K6R w - - 0 1", Color::White, Square::D5);
+    let path_taken = passed_score("k2n4/8/8/3P4/8/8/8/K6R w - - 0 1", Color::White, Square::D5);
+    assert!(free.eg > path_taken.eg && path_taken.eg > blocked.eg);
+  }
+
+  #[test]
+  fn passed_pawn_king_distance() {
+    // the pawn is better with its king close and the enemy one far
+    let escorted = passed_score("k7/8/8/3P4/4K3/8/8/7R w - - 0 1", Color::White, Square::D5);
+    let alone = passed_score("k7/8/8/3P4/8/8/8/K6R w - - 0 1", Color::White, Square::D5);
+    let caught = passed_score("8/8/3k4/3P4/8/8/8/K6R w - - 0 1", Color::White, Square::D5);
+    assert!(escorted.eg > alone.eg && alone.eg > caught.eg);
+    assert_eq!(escorted.mg, alone.mg);
+  }
+
+  #[test]
+  fn rule_of_the_square() {
+    let unstoppable = PassedParams::default().unstoppable;
+    // the black king on g5 is outside the square of the a4 pawn whoever moves
+    let outside = passed_score("8/8/8/6k1/P7/8/8/K7 b - - 0 1", Color::White, Square::A4);
+    assert!(outside.eg >= unstoppable.eg);
+    // on f5 it gets into the square only if it moves first
+    let white_moves = passed_score("8/8/8/5k2/P7/8/8/K7 w - - 0 1", Color::White, Square::A4);
+    let black_moves = passed_score("8/8/8/5k2/P7/8/8/K7 b - - 0 1", Color::White, Square::A4);
+    assert!(white_moves.eg >= unstoppable.eg);
+    assert!(black_moves.eg < unstoppable.eg);
+    // from the second rank the pawn jumps a square
+    let start = passed_score("8/8/8/8/6k1/8/P7/K7 w - - 0 1", Color::White, Square::A2);
+    assert!(start.eg >= unstoppable.eg);
+    // a defending knight makes it a normal passed pawn
+    let knight = passed_score("8/8/8/6k1/P7/8/8/K5n1 w - - 0 1", Color::White, Square::A4);
+    assert!(knight.eg < unstoppable.eg);
+  }
+}
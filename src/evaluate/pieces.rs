@@ -0,0 +1,190 @@
+use serde::{Deserialize, Serialize};
+use shakmaty::*;
+
+use super::pawns::{adjacent_files, ranks_up_to};
+use super::{taper, Taper};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct PieceParams {
+  pub bishop_pair: Taper,
+  pub rook_open_file: Taper,
+  pub rook_semi_open_file: Taper,
+  pub rook_on_seventh: Taper,
+  pub doubled_rooks: Taper,
+  pub knight_outpost: Taper,
+  pub bishop_outpost: Taper,
+  // for each own pawn on the squares of the bishop's color
+  pub bad_bishop: Taper,
+}
+impl Default for PieceParams {
+  fn default() -> Self {
+    PieceParams {
+      bishop_pair: taper(30, 50),
+      rook_open_file: taper(25, 10),
+      rook_semi_open_file: taper(12, 6),
+      rook_on_seventh: taper(20, 30),
+      doubled_rooks: taper(15, 10),
+      knight_outpost: taper(25, 15),
+      bishop_outpost: taper(15, 8),
+      bad_bishop: taper(-3, -5),
+    }
+  }
+}
+
+// piece specific terms of a side
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PieceCounts {
+  // 1 with bishops on both colors
+  pub bishop_pair: i64,
+  pub rooks_open_file: i64,
+  pub rooks_semi_open_file: i64,
+  // rooks on the 7th rank that have pawns to eat there or keep the king on the 8th
+  pub rooks_on_seventh: i64,
+  // pairs of rooks defending each other on the same file
+  pub doubled_rooks: i64,
+  // pieces in the enemy half defended by a pawn that no enemy pawn can chase away
+  pub knight_outposts: i64,
+  pub bishop_outposts: i64,
+  // own pawns on the same color as the bishops, summed over the bishops
+  pub bad_bishop_pawns: i64,
+}
+
+// piece terms of the side of color
+pub fn pieces(board: &Board, color: Color, params: &PieceParams) -> Taper {
+  piece_score(&piece_counts(board, color), params)
+}
+
+fn piece_score(counts: &PieceCounts, params: &PieceParams) -> Taper {
+  params.bishop_pair * counts.bishop_pair
+    + params.rook_open_file * counts.rooks_open_file
+    + params.rook_semi_open_file * counts.rooks_semi_open_file
+    + params.rook_on_seventh * counts.rooks_on_seventh
+    + params.doubled_rooks * counts.doubled_rooks
+    + params.knight_outpost * counts.knight_outposts
+    + params.bishop_outpost * counts.bishop_outposts
+    + params.bad_bishop * counts.bad_bishop_pawns
+}
+
+pub fn piece_counts(board: &Board, color: Color) -> PieceCounts {
+  let own = board.by_color(color);
+  let own_pawns = board.pawns() & own;
+  let enemy_pawns = board.pawns() & board.by_color(!color);
+  let mut counts = PieceCounts::default();
+
+  let bishops = board.bishops() & own;
+  if (bishops & Bitboard::LIGHT_SQUARES).any() && (bishops & Bitboard::DARK_SQUARES).any() {
+    counts.bishop_pair = 1;
+  }
+  for bishop in bishops {
+    let same_color = if bishop.is_light() { Bitboard::LIGHT_SQUARES } else { Bitboard::DARK_SQUARES };
+    counts.bad_bishop_pawns += (own_pawns & same_color).count() as i64;
+  }
+
+  let rooks = board.rooks() & own;
+  let seventh = Bitboard::from_rank(color.relative_rank(Rank::Seventh));
+  let eighth = Bitboard::from_rank(color.relative_rank(Rank::Eighth));
+  let enemy_king = board.kings() & board.by_color(!color);
+  for rook in rooks {
+    let file = Bitboard::from_file(rook.file());
+    if (own_pawns & file).is_empty() {
+      if (enemy_pawns & file).is_empty() {
+        counts.rooks_open_file += 1;
+      } else {
+        counts.rooks_semi_open_file += 1;
+      }
+    }
+    if seventh.contains(rook) && ((enemy_pawns & seventh).any() || (enemy_king & eighth).any()) {
+      counts.rooks_on_seventh += 1;
+    }
+    // each rook of the pair sees the other one
+    if (board.attacks_from(rook) & rooks & file).any() {
+      counts.doubled_rooks += 1;
+    }
+  }
+  counts.doubled_rooks /= 2;
+
+  for square in (board.knights() | board.bishops()) & own {
+    if is_outpost(color, square, own_pawns, enemy_pawns) {
+      if board.knights().contains(square) {
+        counts.knight_outposts += 1;
+      } else {
+        counts.bishop_outposts += 1;
+      }
+    }
+  }
+  counts
+}
+
+// relative ranks 4 to 6, defended by an own pawn and out of reach of the enemy pawns
+fn is_outpost(color: Color, square: Square, own_pawns: Bitboard, enemy_pawns: Bitboard) -> bool {
+  let rank = color.relative_rank(square.rank());
+  let defended = (attacks::pawn_attacks(!color, square) & own_pawns).any();
+  let chasers = adjacent_files(square.file()) & !ranks_up_to(color, square.rank()) & enemy_pawns;
+  (Rank::Fourth..=Rank::Sixth).contains(&rank) && defended && chasers.is_empty()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::utils::parse_fen;
+
+  fn counts(fen_str: &str, color: Color) -> PieceCounts {
+    piece_counts(parse_fen(fen_str).unwrap().board(), color)
+  }
+
+  #[test]
+  fn bishop_pair() {
+    assert_eq!(counts("4k3/8/8/8/8/8/8/2B1KB2 w - - 0 1", Color::White).bishop_pair, 1);
+    // two bishops on the same color aren't a pair
+    assert_eq!(counts("4k3/8/8/8/8/8/8/3BKB2 w - - 0 1", Color::White).bishop_pair, 0);
+    assert_eq!(counts("2b1kb2/8/8/8/8/8/8/4K3 w - - 0 1", Color::Black).bishop_pair, 1);
+  }
+
+  #[test]
+  fn rooks_on_files() {
+    // the a file is open, the black pawn makes d semi-open and h has a white pawn
+    let white = counts("4k3/3p4/8/8/8/8/7P/R2RK2R w - - 0 1", Color::White);
+    assert_eq!((white.rooks_open_file, white.rooks_semi_open_file), (1, 1));
+  }
+
+  #[test]
+  fn rook_on_seventh() {
+    // with pawns to attack
+    assert_eq!(counts("8/R4ppk/8/8/8/8/8/4K3 w - - 0 1", Color::White).rooks_on_seventh, 1);
+    // or with the king cut off on the last rank
+    assert_eq!(counts("6k1/R7/8/8/8/8/8/4K3 w - - 0 1", Color::White).rooks_on_seventh, 1);
+    assert_eq!(counts("8/R7/6k1/8/8/8/8/4K3 w - - 0 1", Color::White).rooks_on_seventh, 0);
+    assert_eq!(counts("4k3/8/8/8/8/8/r7/6K1 w - - 0 1", Color::Black).rooks_on_seventh, 1);
+  }
+
+  #[test]
+  fn doubled_rooks() {
+    assert_eq!(counts("4k3/8/8/8/8/8/3R4/3RK3 w - - 0 1", Color::White).doubled_rooks, 1);
+    // a piece between them breaks the connection
+    assert_eq!(counts("4k3/8/8/8/8/3R4/3N4/3RK3 w - - 0 1", Color::White).doubled_rooks, 0);
+  }
+
+  #[test]
+  fn outposts() {
+    // d5 is defended by e4 and no black pawn can attack it
+    let white = counts("4k3/pp6/8/3N4/4P3/8/8/4K3 w - - 0 1", Color::White);
+    assert_eq!(white.knight_outposts, 1);
+    // the c7 pawn can chase the knight with c6
+    assert_eq!(counts("4k3/2p5/8/3N4/4P3/8/8/4K3 w - - 0 1", Color::White).knight_outposts, 0);
+    // without the defending pawn it's just a knight
+    assert_eq!(counts("4k3/pp6/8/3N4/8/8/8/4K3 w - - 0 1", Color::White).knight_outposts, 0);
+    let black = counts("4k3/8/8/4p3/3b4/8/PP6/4K3 w - - 0 1", Color::Black);
+    assert_eq!(black.bishop_outposts, 1);
+  }
+
+  #[test]
+  fn bad_bishop() {
+    // the c1 bishop is on dark squares like the d4 and e3 pawns, the light one has none
+    let bad = counts("4k3/8/8/8/3P4/4P3/8/2B1K3 w - - 0 1", Color::White);
+    assert_eq!(bad.bad_bishop_pawns, 2);
+    let good = counts("4k3/8/8/8/3P4/4P3/8/4KB2 w - - 0 1", Color::White);
+    assert_eq!(good.bad_bishop_pawns, 0);
+    assert!(piece_score(&good, &PieceParams::default()).eg > piece_score(&bad, &PieceParams::default()).eg);
+  }
+}
@@ -0,0 +1,222 @@
+use serde::{Deserialize, Serialize};
+use shakmaty::*;
+
+use super::{taper, Taper};
+
+// piece-square tables from white's point of view, written as seen from white with rank 8 on top,
+// a white piece on rank r uses the row 7 - r and a black one the row r
+#[rustfmt::skip]
+const PAWN_MG: [[i64; 8]; 8] = [
+  [   0,   0,   0,   0,   0,   0,   0,   0],
+  [  50,  50,  50,  50,  50,  50,  50,  50],
+  [  10,  10,  20,  30,  30,  20,  10,  10],
+  [   5,   5,  10,  25,  25,  10,   5,   5],
+  [   0,   0,   0,  20,  20,   0,   0,   0],
+  [   5,  -5, -10,   0,   0, -10,  -5,   5],
+  [   5,  10,  10, -20, -20,  10,  10,   5],
+  [   0,   0,   0,   0,   0,   0,   0,   0],
+];
+#[rustfmt::skip]
+const PAWN_EG: [[i64; 8]; 8] = [
+  [   0,   0,   0,   0,   0,   0,   0,   0],
+  [  80,  80,  80,  80,  80,  80,  80,  80],
+  [  50,  50,  50,  50,  50,  50,  50,  50],
+  [  30,  30,  30,  30,  30,  30,  30,  30],
+  [  15,  15,  15,  15,  15,  15,  15,  15],
+  [   5,   5,   5,   5,   5,   5,   5,   5],
+  [   0,   0,   0,   0,   0,   0,   0,   0],
+  [   0,   0,   0,   0,   0,   0,   0,   0],
+];
+#[rustfmt::skip]
+const KNIGHT_MG: [[i64; 8]; 8] = [
+  [ -50, -40, -30, -30, -30, -30, -40, -50],
+  [ -40, -20,   0,   0,   0,   0, -20, -40],
+  [ -30,   0,  10,  15,  15,  10,   0, -30],
+  [ -30,   5,  15,  20,  20,  15,   5, -30],
+  [ -30,   0,  15,  20,  20,  15,   0, -30],
+  [ -30,   5,  10,  15,  15,  10,   5, -30],
+  [ -40, -20,   0,   5,   5,   0, -20, -40],
+  [ -50, -40, -30, -30, -30, -30, -40, -50],
+];
+#[rustfmt::skip]
+const KNIGHT_EG: [[i64; 8]; 8] = [
+  [ -50, -40, -30, -30, -30, -30, -40, -50],
+  [ -40, -20, -10,  -5,  -5, -10, -20, -40],
+  [ -30, -10,  10,  15,  15,  10, -10, -30],
+  [ -30,  -5,  15,  20,  20,  15,  -5, -30],
+  [ -30,  -5,  15,  20,  20,  15,  -5, -30],
+  [ -30, -10,  10,  15,  15,  10, -10, -30],
+  [ -40, -20, -10,  -5,  -5, -10, -20, -40],
+  [ -50, -40, -30, -30, -30, -30, -40, -50],
+];
+#[rustfmt::skip]
+const BISHOP_MG: [[i64; 8]; 8] = [
+  [ -20, -10, -10, -10, -10, -10, -10, -20],
+  [ -10,   0,   0,   0,   0,   0,   0, -10],
+  [ -10,   0,   5,  10,  10,   5,   0, -10],
+  [ -10,   5,   5,  10,  10,   5,   5, -10],
+  [ -10,   0,  10,  10,  10,  10,   0, -10],
+  [ -10,  10,  10,  10,  10,  10,  10, -10],
+  [ -10,   5,   0,   0,   0,   0,   5, -10],
+  [ -20, -10, -10, -10, -10, -10, -10, -20],
+];
+#[rustfmt::skip]
+const BISHOP_EG: [[i64; 8]; 8] = [
+  [ -20, -10, -10, -10, -10, -10, -10, -20],
+  [ -10,   0,   0,   0,   0,   0,   0, -10],
+  [ -10,   0,  10,  10,  10,  10,   0, -10],
+  [ -10,   0,  10,  15,  15,  10,   0, -10],
+  [ -10,   0,  10,  15,  15,  10,   0, -10],
+  [ -10,   0,  10,  10,  10,  10,   0, -10],
+  [ -10,   0,   0,   0,   0,   0,   0, -10],
+  [ -20, -10, -10, -10, -10, -10, -10, -20],
+];
+#[rustfmt::skip]
+const ROOK_MG: [[i64; 8]; 8] = [
+  [   0,   0,   0,   0,   0,   0,   0,   0],
+  [   5,  10,  10,  10,  10,  10,  10,   5],
+  [  -5,   0,   0,   0,   0,   0,   0,  -5],
+  [  -5,   0,   0,   0,   0,   0,   0,  -5],
+  [  -5,   0,   0,   0,   0,   0,   0,  -5],
+  [  -5,   0,   0,   0,   0,   0,   0,  -5],
+  [  -5,   0,   0,   0,   0,   0,   0,  -5],
+  [   0,   0,   0,   5,   5,   0,   0,   0],
+];
+#[rustfmt::skip]
+const ROOK_EG: [[i64; 8]; 8] = [
+  [   5,   5,   5,   5,   5,   5,   5,   5],
+  [  10,  10,  10,  10,  10,  10,  10,  10],
+  [   0,   0,   0,   0,   0,   0,   0,   0],
+  [   0,   0,   0,   0,   0,   0,   0,   0],
+  [   0,   0,   0,   0,   0,   0,   0,   0],
+  [   0,   0,   0,   0,   0,   0,   0,   0],
+  [   0,   0,   0,   0,   0,   0,   0,   0],
+  [   0,   0,   0,   0,   0,   0,   0,   0],
+];
+#[rustfmt::skip]
+const QUEEN_MG: [[i64; 8]; 8] = [
+  [ -20, -10, -10,  -5,  -5, -10, -10, -20],
+  [ -10,   0,   0,   0,   0,   0,   0, -10],
+  [ -10,   0,   5,   5,   5,   5,   0, -10],
+  [  -5,   0,   5,   5,   5,   5,   0,  -5],
+  [   0,   0,   5,   5,   5,   5,   0,  -5],
+  [ -10,   5,   5,   5,   5,   5,   0, -10],
+  [ -10,   0,   5,   0,   0,   0,   0, -10],
+  [ -20, -10, -10,  -5,  -5, -10, -10, -20],
+];
+#[rustfmt::skip]
+const QUEEN_EG: [[i64; 8]; 8] = [
+  [ -20, -10, -10,  -5,  -5, -10, -10, -20],
+  [ -10,   0,   5,   5,   5,   5,   0, -10],
+  [ -10,   5,  10,  10,  10,  10,   5, -10],
+  [  -5,   5,  10,  15,  15,  10,   5,  -5],
+  [  -5,   5,  10,  15,  15,  10,   5,  -5],
+  [ -10,   5,  10,  10,  10,  10,   5, -10],
+  [ -10,   0,   5,   5,   5,   5,   0, -10],
+  [ -20, -10, -10,  -5,  -5, -10, -10, -20],
+];
+// in the middlegame the king hides behind its pawns, in the endgame it goes to the center
+#[rustfmt::skip]
+const KING_MG: [[i64; 8]; 8] = [
+  [ -30, -40, -40, -50, -50, -40, -40, -30],
+  [ -30, -40, -40, -50, -50, -40, -40, -30],
+  [ -30, -40, -40, -50, -50, -40, -40, -30],
+  [ -30, -40, -40, -50, -50, -40, -40, -30],
+  [ -20, -30, -30, -40, -40, -30, -30, -20],
+  [ -10, -20, -20, -20, -20, -20, -20, -10],
+  [  20,  20,   0,   0,   0,   0,  20,  20],
+  [  20,  30,  10,   0,   0,  10,  30,  20],
+];
+#[rustfmt::skip]
+const KING_EG: [[i64; 8]; 8] = [
+  [ -50, -40, -30, -20, -20, -30, -40, -50],
+  [ -30, -20, -10,   0,   0, -10, -20, -30],
+  [ -30, -10,  20,  30,  30,  20, -10, -30],
+  [ -30, -10,  30,  40,  40,  30, -10, -30],
+  [ -30, -10,  30,  40,  40,  30, -10, -30],
+  [ -30, -10,  20,  30,  30,  20, -10, -30],
+  [ -30, -30,   0,   0,   0,   0, -30, -30],
+  [ -50, -30, -30, -30, -30, -30, -30, -50],
+];
+
+// middlegame and endgame tables of a piece, rank 8 first
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Pst {
+  pub mg: [[i64; 8]; 8],
+  pub eg: [[i64; 8]; 8],
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct PstParams {
+  pub pawn: Pst,
+  pub knight: Pst,
+  pub bishop: Pst,
+  pub rook: Pst,
+  pub queen: Pst,
+  pub king: Pst,
+}
+impl Default for PstParams {
+  fn default() -> Self {
+    PstParams {
+      pawn: Pst { mg: PAWN_MG, eg: PAWN_EG },
+      knight: Pst { mg: KNIGHT_MG, eg: KNIGHT_EG },
+      bishop: Pst { mg: BISHOP_MG, eg: BISHOP_EG },
+      rook: Pst { mg: ROOK_MG, eg: ROOK_EG },
+      queen: Pst { mg: QUEEN_MG, eg: QUEEN_EG },
+      king: Pst { mg: KING_MG, eg: KING_EG },
+    }
+  }
+}
+
+// piece-square table value of a piece, positive for both colors
+pub fn piece_square(params: &PstParams, piece: Piece, square: Square) -> Taper {
+  let pst = match piece.role {
+    Role::Pawn => &params.pawn,
+    Role::Knight => &params.knight,
+    Role::Bishop => &params.bishop,
+    Role::Rook => &params.rook,
+    Role::Queen => &params.queen,
+    Role::King => &params.king,
+  };
+  // the tables are written with rank 8 first, that's where black starts
+  let row = match piece.color {
+    Color::White => 7 - usize::from(square.rank()),
+    Color::Black => usize::from(square.rank()),
+  };
+  let column = usize::from(square.file());
+  taper(pst.mg[row][column], pst.eg[row][column])
+}
+
+// piece-square values of the pieces of color
+pub fn piece_squares(board: &Board, color: Color, params: &PstParams) -> Taper {
+  let mut score = Taper::default();
+  for square in board.by_color(color) {
+    if let Some(piece) = board.piece_at(square) {
+      score += piece_square(params, piece, square);
+    }
+  }
+  score
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn piece_square_tables() {
+    let params = PstParams::default();
+    // a developed knight is better than one in the corner
+    let knight = Piece { color: Color::White, role: Role::Knight };
+    assert!(piece_square(&params, knight, Square::F3).mg > piece_square(&params, knight, Square::A1).mg);
+    // the same square seen by black
+    assert_eq!(piece_square(&params, knight, Square::F3), piece_square(&params, Piece { color: Color::Black, role: Role::Knight }, Square::F6));
+    // the king is safe on g1 in the middlegame, but belongs to the center in the endgame
+    let king = Piece { color: Color::White, role: Role::King };
+    assert!(piece_square(&params, king, Square::G1).mg > piece_square(&params, king, Square::E4).mg);
+    assert!(piece_square(&params, king, Square::G1).eg < piece_square(&params, king, Square::E4).eg);
+    // a pawn about to promote
+    assert_eq!(piece_square(&params, Piece { color: Color::White, role: Role::Pawn }, Square::A7), taper(50, 80));
+    assert_eq!(piece_square(&params, Piece { color: Color::Black, role: Role::Pawn }, Square::A2), taper(50, 80));
+  }
+}
@@ -0,0 +1,72 @@
+use shakmaty::{Chess, Position, Move, san::SanPlus, uci::Uci};
+use shakmaty::zobrist::ZobristHash;
+
+use crate::error::ChessError;
+use crate::utils::{parse_fen, to_fen};
+
+pub const STARTING_FEN: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+// position reached by playing a list of moves from a starting one, it keeps the
+// zobrist keys of the positions that came before it so the search can find repetitions
+#[derive(Debug, Clone)]
+pub struct Game {
+  pub pos: Chess,
+  // keys of the positions before pos, oldest first
+  pub history: Vec<u64>,
+}
+impl Game {
+  // start is a FEN or "startpos", each move can be in UCI or SAN,
+  // the index of the first move that can't be played is returned in the error
+  pub fn from_moves<S: AsRef<str>>(start: &str, moves: &[S]) -> Result<Game, ChessError> {
+    let mut pos = match start.trim() {
+      "startpos" => Chess::default(),
+      fen_str => parse_fen(fen_str)?,
+    };
+    let mut history = Vec::with_capacity(moves.len());
+    for (index, mov) in moves.iter().enumerate() {
+      let mov = mov.as_ref();
+      let legal = parse_move(&pos, mov).ok_or_else(|| ChessError::IllegalMove(index, mov.to_string()))?;
+      history.push(pos.zobrist_hash());
+      pos.play_unchecked(&legal);
+    }
+    Ok(Game { pos, history })
+  }
+
+  pub fn fen(&self) -> String {
+    to_fen(&self.pos)
+  }
+}
+
+// a legal move in pos, written in UCI or SAN
+fn parse_move(pos: &Chess, mov: &str) -> Option<Move> {
+  let from_uci = || mov.parse::<Uci>().ok()?.to_move(pos).ok();
+  let from_san = || mov.parse::<SanPlus>().ok()?.san.to_move(pos).ok();
+  from_uci().or_else(from_san)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn replay_uci_and_san() {
+    let uci = Game::from_moves("startpos", &["e2e4", "e7e5", "g1f3", "b8c6", "f1b5"]).unwrap();
+    let san = Game::from_moves(STARTING_FEN, &["e4", "e5", "Nf3", "Nc6", "Bb5"]).unwrap();
+    assert_eq!(uci.fen(), "r1bqkbnr/pppp1ppp/2n5/1B2p3/4P3/5N2/PPPP1PPP/RNBQK2R b KQkq - 3 3");
+    assert_eq!(uci.fen(), san.fen());
+    assert_eq!(uci.history, san.history);
+    assert_eq!(uci.history.len(), 5);
+    assert_eq!(uci.history[0], Chess::default().zobrist_hash::<u64>());
+    // both notations can be mixed, with check and capture markers
+    let mixed = Game::from_moves("startpos", &["e4", "f7f5", "exf5", "g7g5", "Qh5#"]).unwrap();
+    assert!(mixed.pos.is_checkmate());
+  }
+
+  #[test]
+  fn illegal_move_index() {
+    assert_eq!(Game::from_moves("startpos", &["e4", "e5", "e5"]).unwrap_err(), ChessError::IllegalMove(2, "e5".to_string()));
+    assert_eq!(Game::from_moves("startpos", &["e2e5"]).unwrap_err(), ChessError::IllegalMove(0, "e2e5".to_string()));
+    assert_eq!(Game::from_moves("startpos", &["hello"]).unwrap_err(), ChessError::IllegalMove(0, "hello".to_string()));
+    assert_eq!(Game::from_moves("not a fen", &["e4"]).unwrap_err(), ChessError::InvalidFen("not a fen".to_string()));
+  }
+}
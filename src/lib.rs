@@ -2,10 +2,24 @@ mod utils;
 mod algorithms;
 mod evaluate;
 mod ordering;
+mod engine;
+mod search;
+mod error;
+mod game;
+pub mod perft;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tune;
 
 use wasm_bindgen::prelude::*;
-use crate::algorithms::hashtable::{HashTable};
-use crate::utils::{zobrish, MoveAndTable};
+use crate::utils::to_js;
+
+pub use crate::engine::Engine;
+pub use crate::error::ChessError;
+pub use crate::algorithms::Algorithm;
+pub use crate::evaluate::{evaluator_from_name, load_network_file, network, set_eval_params, EvalParams, Evaluator, Explanation, Network, Nnue, DEFAULT_EVALUATOR, EVALUATORS};
+pub use crate::algorithms::hashtable::{HashTable, MAX_SIZE_MB};
+pub use crate::game::{Game, STARTING_FEN};
+pub use crate::search::{time_for_move, Score, SearchContext, SearchResult, MOVE_OVERHEAD};
 
 // wasm-pack build --target nodejs --out-dir /home/fra/SocketChess/node_modules/wasm-chess-algorithms
 
@@ -26,58 +40,132 @@ pub fn greet(name: &str) {
     alert(&greeting);
 } */
 
+// the evaluator called name, or the default one when it's not given
+fn evaluator(name: Option<String>) -> Result<Box<dyn evaluate::Evaluator>, ChessError> {
+    evaluate::evaluator_from_name(name.as_deref().unwrap_or(evaluate::DEFAULT_EVALUATOR))
+}
+
+// names of the evaluators the searches can use, each search takes one of them as its last argument
+#[wasm_bindgen]
+pub fn evaluators() -> Vec<String> {
+    evaluate::EVALUATORS.iter().map(|name| name.to_string()).collect()
+}
+
+// loads the weights of the evaluation terms from JSON, the evaluators of the following searches use
+// them, terms missing from the JSON keep their default value
+#[wasm_bindgen(js_name = set_eval_params)]
+pub fn set_eval_params_js(json: &str) -> Result<(), JsValue> {
+    evaluate::set_eval_params(Some(EvalParams::from_json(json)?));
+    Ok(())
+}
+
+// goes back to the default weights
+#[wasm_bindgen]
+pub fn reset_eval_params() {
+    evaluate::set_eval_params(None);
+}
+
+// the weights in use as JSON, a starting point to write new ones
+#[wasm_bindgen]
+pub fn eval_params() -> String {
+    evaluate::eval_params().to_json()
+}
+
+// loads the weights of the network used by the nnue evaluator, as written by Network::to_bytes,
+// until one is loaded nnue falls back to the classic evaluation
 #[wasm_bindgen]
-pub fn random_move(fen_str: &str, seed: u64) -> String {
-    return algorithms::random::random_move(fen_str, seed);
+pub fn load_network(bytes: &[u8]) -> Result<(), JsValue> {
+    evaluate::load_network(bytes)?;
+    Ok(())
 }
 
 #[wasm_bindgen]
-pub fn negamax_move(fen_str: &str, seed: u64, depth: u64) -> String {
-    return algorithms::negamax::negamax_root(fen_str, seed, depth)
+pub fn unload_network() {
+    evaluate::unload_network();
 }
 
+// each term of the classic evaluation of fen for both sides, with the weights in use,
+// as { fen, phase, terms, total, score, table } where table is the same breakdown as text
 #[wasm_bindgen]
-pub fn negamax_a_b_move(fen_str: &str, seed: u64, depth: u64) -> String {
-    return algorithms::negamax_a_b::negamax_a_b_root(fen_str, seed, depth)
+pub fn explain_evaluation(fen_str: &str) -> Result<JsValue, JsValue> {
+    to_js(&evaluate::explain_evaluation(fen_str)?)
 }
 
 #[wasm_bindgen]
-pub fn negamax_a_b_table_move(fen_str: &str, seed: u64, depth: u64, lastres: JsValue) -> JsValue {
-    let move_and_table = lastres.into_serde::<MoveAndTable>();
-    // table = if move_and_table is ok, unwrap it otherwise use none
-    let table = match move_and_table {
-        Ok(mt) => Some(mt.table),
-        Err(_) => None,
+pub fn random_move(fen_str: &str, seed: u64, evaluator_name: Option<String>) -> Result<JsValue, JsValue> {
+    to_js(&algorithms::random::random_move(fen_str, seed, evaluator(evaluator_name)?)?)
+}
+
+#[wasm_bindgen]
+pub fn negamax_move(fen_str: &str, seed: u64, depth: u64, evaluator_name: Option<String>) -> Result<JsValue, JsValue> {
+    to_js(&algorithms::negamax::negamax_root(fen_str, seed, depth, evaluator(evaluator_name)?)?)
+}
+
+#[wasm_bindgen]
+pub fn negamax_a_b_move(fen_str: &str, seed: u64, depth: u64, evaluator_name: Option<String>) -> Result<JsValue, JsValue> {
+    to_js(&algorithms::negamax_a_b::negamax_a_b_root(fen_str, seed, depth, evaluator(evaluator_name)?)?)
+}
+
+// table is the one returned by the previous call, as exported by HashTable::to_bytes,
+// returns { result, table } with the updated table as a Uint8Array
+#[wasm_bindgen]
+pub fn negamax_a_b_table_move(fen_str: &str, seed: u64, depth: u64, table: Option<Vec<u8>>, evaluator_name: Option<String>) -> Result<JsValue, JsValue> {
+    // no previous table means we start with an empty one
+    let table = match table {
+        Some(bytes) => Some(HashTable::from_bytes(&bytes)?),
+        None => None,
     };
     /* let string = format!("[Rust-lib] {}", if table.is_none() { "None" } else { "Some" });
     web_sys::console::log_1(&string.into()); */
-    let fun_res = algorithms::negamax_a_b_table::root(fen_str, seed, depth, table);
-    let data = JsValue::from_serde(&fun_res);
-    if data.is_err() {
-        panic!("Error during negamax_a_b_table_move {:?}", data.err().unwrap());
-    }
-    return data.unwrap();
+    let fun_res = algorithms::negamax_a_b_table::root(fen_str, seed, depth, table, evaluator(evaluator_name)?)?;
+    let move_and_table = js_sys::Object::new();
+    js_sys::Reflect::set(&move_and_table, &"result".into(), &to_js(&fun_res.result)?)?;
+    js_sys::Reflect::set(&move_and_table, &"table".into(), &js_sys::Uint8Array::from(&fun_res.table.to_bytes()[..]))?;
+    Ok(move_and_table.into())
+}
+
+#[wasm_bindgen]
+pub fn negamax_a_b_quiescent(fen_str: &str, seed: u64, depth: u64, evaluator_name: Option<String>) -> Result<JsValue, JsValue> {
+    to_js(&algorithms::negamax_a_b_quiescent::root(fen_str, seed, depth, evaluator(evaluator_name)?)?)
+}
+
+#[wasm_bindgen]
+pub fn iterative_deepening(fen_str: &str, seed: u64, max_time:u64, evaluator_name: Option<String>) -> Result<JsValue, JsValue> {
+    to_js(&algorithms::iterative_deepening::root(fen_str, seed, max_time, evaluator(evaluator_name)?)?)
+}
+
+#[wasm_bindgen]
+pub fn iterative_deepening_table(fen_str: &str, seed: u64, max_time:u64, evaluator_name: Option<String>) -> Result<JsValue, JsValue> {
+    let move_table = algorithms::iterative_deepening_table::root(fen_str, seed, max_time, None, evaluator(evaluator_name)?)?;
+    to_js(&move_table.result)
 }
 
 #[wasm_bindgen]
-pub fn negamax_a_b_quiescent(fen_str: &str, seed: u64, depth: u64) -> String {
-    return algorithms::negamax_a_b_quiescent::root(fen_str, seed, depth)
+pub fn iterative_deepening_order(fen_str: &str, seed: u64, max_time:u64, evaluator_name: Option<String>) -> Result<JsValue, JsValue> {
+    to_js(&algorithms::iterative_deepening_order::root(fen_str, seed, max_time, evaluator(evaluator_name)?)?)
 }
 
+// searches the position reached by playing moves (UCI or SAN) from start (a FEN or "startpos"),
+// knowing the game history it scores repetitions as draws, like Engine does
 #[wasm_bindgen]
-pub fn iterative_deepening(fen_str: &str, seed: u64, max_time:u64) -> String {
-    return algorithms::iterative_deepening::root(fen_str, seed, max_time)
+pub fn search_moves(start: &str, moves: Vec<String>, seed: u64, max_time: u64, evaluator_name: Option<String>) -> Result<JsValue, JsValue> {
+    let game = game::Game::from_moves(start, &moves)?;
+    let mut table = HashTable::new();
+    let mut ctx = search::SearchContext::with_history(&game.history);
+    ctx.evaluator = evaluator(evaluator_name)?;
+    to_js(&algorithms::iterative_deepening_table::search(&game.fen(), seed, max_time, &mut table, &mut ctx)?)
 }
 
+// number of positions depth plies from fen_str, to check move generation
 #[wasm_bindgen]
-pub fn iterative_deepening_table(fen_str: &str, seed: u64, max_time:u64) -> String {
-    let move_table = algorithms::iterative_deepening_table::root(fen_str, seed, max_time, None);
-    return move_table.mov
+pub fn perft(fen_str: &str, depth: u64) -> Result<JsValue, JsValue> {
+    to_js(&perft::perft(fen_str, depth)?)
 }
 
+// perft after each legal move, as an object with the moves in UCI as keys
 #[wasm_bindgen]
-pub fn iterative_deepening_order(fen_str: &str, seed: u64, max_time:u64) -> String {
-    return algorithms::iterative_deepening_order::root(fen_str, seed, max_time)
+pub fn divide(fen_str: &str, depth: u64) -> Result<JsValue, JsValue> {
+    to_js(&perft::divide(fen_str, depth)?)
 }
 
 /* #[wasm_bindgen]
@@ -2,29 +2,109 @@ use std::cmp::Ordering;
 
 use shakmaty::*;
 
+use crate::algorithms::hashtable::encode_move;
+
 pub fn move_ordering(m1:&Move,m2:&Move) -> Ordering {
   if m1.is_capture() && !m2.is_capture() {
     // a capture is always better than a non-capture
-    return Ordering::Less;
+    Ordering::Less
   } else if !m1.is_capture() && m2.is_capture() {
     // a non-capture is always worst than a capture
-    return Ordering::Greater;
+    Ordering::Greater
   } else if m1.is_capture() && m2.is_capture() {
     // if both are capture, we compare the capture value and capturer value
-    return capture_ordering(m1,m2);
+    capture_ordering(m1,m2)
   } else {
     // if neither is capture, we prefer promotions 
     if m1.is_promotion() && !m2.is_promotion() {
-      return Ordering::Less;
+      Ordering::Less
     } else if !m1.is_promotion() && m2.is_promotion() {
-      return Ordering::Greater;
+      Ordering::Greater
     } else {
       // if neither is promotion, we return equal
-      return Ordering::Equal;
+      Ordering::Equal
     }
   }
 }
 
+// plies that have their own killer moves, deeper ones share the last slots
+pub const MAX_PLY: usize = 64;
+// history scores are halved when one gets past this, so recent cutoffs count more than old ones
+const MAX_HISTORY: i64 = 1 << 20;
+
+// what the quiet moves that caused beta cutoffs tell about the others, for a single search:
+// the last two that did it at each ply (killer moves) and how often each from-to pair did it
+// for each color (butterfly history), weighted by the depth left
+#[derive(Debug, Clone)]
+pub struct Heuristics {
+  // without them quiet moves are left in the order they come in
+  pub enabled: bool,
+  killers: Vec<[Option<Move>; 2]>,
+  history: Vec<i64>,
+}
+impl Heuristics {
+  pub fn new() -> Heuristics {
+    Heuristics { enabled: true, killers: vec![[None, None]; MAX_PLY], history: vec![0; 2 * 64 * 64] }
+  }
+
+  fn history_index(color: Color, mov: &Move) -> Option<usize> {
+    let from = mov.from()? as usize;
+    Some(color.fold_wb(0, 1) * 64 * 64 + from * 64 + mov.to() as usize)
+  }
+
+  // mov, played by color at ply with depth plies left, scored at least beta,
+  // captures and promotions are already tried first so they are not remembered
+  pub fn cutoff(&mut self, color: Color, mov: &Move, ply: u64, depth: u64) {
+    if !self.enabled || mov.is_capture() || mov.is_promotion() {
+      return;
+    }
+    let killers = &mut self.killers[(ply as usize).min(MAX_PLY - 1)];
+    if killers[0].as_ref() != Some(mov) {
+      killers[1] = killers[0].take();
+      killers[0] = Some(mov.clone());
+    }
+    if let Some(index) = Heuristics::history_index(color, mov) {
+      self.history[index] += (depth * depth) as i64;
+      if self.history[index] > MAX_HISTORY {
+        self.history.iter_mut().for_each(|score| *score /= 2);
+      }
+    }
+  }
+
+  // 0 for the first killer move of ply, 1 for the second, 2 for the other moves
+  fn killer_rank(&self, mov: &Move, ply: u64) -> usize {
+    let killers = &self.killers[(ply as usize).min(MAX_PLY - 1)];
+    killers.iter().position(|killer| killer.as_ref() == Some(mov)).unwrap_or(2)
+  }
+
+  fn history(&self, color: Color, mov: &Move) -> i64 {
+    Heuristics::history_index(color, mov).map_or(0, |index| self.history[index])
+  }
+
+  // same as move_ordering, then quiet moves go by killer moves first and then by history
+  pub fn ordering(&self, m1: &Move, m2: &Move, color: Color, ply: u64) -> Ordering {
+    let ordering = move_ordering(m1, m2);
+    if ordering != Ordering::Equal || m1.is_capture() || !self.enabled {
+      return ordering;
+    }
+    self.killer_rank(m1, ply).cmp(&self.killer_rank(m2, ply)).then_with(|| self.history(color, m2).cmp(&self.history(color, m1)))
+  }
+}
+impl Default for Heuristics {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+// moves the best move found by a previous search (packed by the table) in front of the others,
+// the order of the other moves is kept
+pub fn best_move_first(legals: &mut MoveList, best_move: u16) {
+  let index = legals.iter().position(|mov| encode_move(mov) == best_move);
+  if let Some(index) = index {
+    legals[..=index].rotate_right(1);
+  }
+}
+
 pub fn capture_ordering(m1:&Move,m2:&Move) -> Ordering {
   // both are capture so we sort based on capture value and piece that captured (MVV-LVA)
   let c1 = m1.capture().unwrap();
@@ -32,9 +112,9 @@ pub fn capture_ordering(m1:&Move,m2:&Move) -> Ordering {
   let p1 = m1.role();
   let p2 = m2.role();
   if c1.cmp(&c2) == Ordering::Equal {
-    return p1.cmp(&p2);
+    p1.cmp(&p2)
   } else {
-    return c2.cmp(&c1);
+    c2.cmp(&c1)
   }
 }
 #[cfg(test)]
@@ -59,4 +139,39 @@ use super::*;
     } */
     
   }
+
+  #[test]
+  fn test_best_move_first() {
+    let fen: fen::Fen = "7k/4P3/8/2r5/1Q1P2p1/8/8/2R4K w - - 0 1".parse().unwrap();
+    let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
+    let mut legals = pos.legal_moves();
+    let promotion = legals.iter().find(|mov| mov.promotion() == Some(Role::Queen)).unwrap().clone();
+    best_move_first(&mut legals, encode_move(&promotion));
+    assert_eq!(legals[0].to_uci(CastlingMode::Standard).to_string(), "e7e8q");
+    assert_eq!(legals.len(), pos.legal_moves().len());
+  }
+
+  #[test]
+  fn killers_and_history() {
+    let fen: fen::Fen = "4k3/8/8/3p4/8/8/P6P/R3K2R w KQ - 0 1".parse().unwrap();
+    let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
+    let find = |uci: &str| uci.parse::<uci::Uci>().unwrap().to_move(&pos).unwrap();
+    let (a3, h3, h4, castle) = (find("a2a3"), find("h2h3"), find("h2h4"), find("e1g1"));
+    let mut heuristics = Heuristics::new();
+    // h3 caused more cutoffs deeper in the tree, castling is the last killer move at ply 2
+    heuristics.cutoff(Color::White, &h3, 4, 3);
+    heuristics.cutoff(Color::White, &h4, 2, 1);
+    heuristics.cutoff(Color::White, &castle, 2, 1);
+    heuristics.cutoff(Color::White, &castle, 2, 1);
+    let mut legals = pos.legal_moves();
+    legals.sort_by(|a, b| heuristics.ordering(a, b, Color::White, 2));
+    let order: Vec<String> = legals.iter().take(3).map(|mov| mov.to_uci(CastlingMode::Standard).to_string()).collect();
+    assert_eq!(order, vec!["e1g1", "h2h4", "h2h3"]);
+    // at another ply only history counts
+    assert_eq!(heuristics.ordering(&h3, &h4, Color::White, 3), Ordering::Less);
+    assert_eq!(heuristics.ordering(&a3, &h4, Color::White, 3), Ordering::Greater);
+    assert_eq!(heuristics.ordering(&a3, &h4, Color::Black, 3), Ordering::Equal);
+    heuristics.enabled = false;
+    assert_eq!(heuristics.ordering(&castle, &a3, Color::White, 2), Ordering::Equal);
+  }
 }
\ No newline at end of file
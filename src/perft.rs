@@ -0,0 +1,124 @@
+// perft counts the positions reached after a number of plies, comparing the counts with
+// the known ones checks move generation and the way positions are handled by the search
+// https://www.chessprogramming.org/Perft
+
+use std::collections::BTreeMap;
+
+use shakmaty::{Chess, Position, CastlingMode};
+use shakmaty::zobrist::ZobristHash;
+
+use crate::error::ChessError;
+use crate::utils::{parse_fen, to_fen, zobrist_after};
+
+// number of leaf positions depth plies from fen_str
+pub fn perft(fen_str: &str, depth: u64) -> Result<u64, ChessError> {
+  let pos = parse_fen(fen_str)?;
+  perft_pos(&pos, pos.zobrist_hash(), depth)
+}
+
+// perft of the position after each legal move, by move in UCI
+pub fn divide(fen_str: &str, depth: u64) -> Result<BTreeMap<String, u64>, ChessError> {
+  let pos = parse_fen(fen_str)?;
+  let key = pos.zobrist_hash();
+  let mut counts = BTreeMap::new();
+  if depth == 0 {
+    return Ok(counts);
+  }
+  for legal in pos.legal_moves() {
+    let (new_pos, new_key) = play(&pos, key, &legal)?;
+    counts.insert(legal.to_uci(CastlingMode::Standard).to_string(), perft_pos(&new_pos, new_key, depth - 1)?);
+  }
+  Ok(counts)
+}
+
+fn perft_pos(pos: &Chess, key: u64, depth: u64) -> Result<u64, ChessError> {
+  if depth == 0 {
+    return Ok(1);
+  }
+  let legals = pos.legal_moves();
+  // the leaves are only counted, nothing is played from them
+  if depth == 1 {
+    return Ok(legals.len() as u64);
+  }
+  let mut nodes = 0;
+  for legal in legals {
+    let (new_pos, new_key) = play(pos, key, &legal)?;
+    nodes += perft_pos(&new_pos, new_key, depth - 1)?;
+  }
+  Ok(nodes)
+}
+
+// plays mov like the search does, then goes through the fen of the new position like
+// positions sent back and forth with JS do, the tree goes on from the parsed position
+fn play(pos: &Chess, key: u64, mov: &shakmaty::Move) -> Result<(Chess, u64), ChessError> {
+  let mut new_pos = pos.clone();
+  new_pos.play_unchecked(mov);
+  let new_key = zobrist_after(pos, key, mov, &new_pos);
+  let parsed = parse_fen(&to_fen(&new_pos))?;
+  debug_assert_eq!(parsed.zobrist_hash::<u64>(), new_key, "wrong key after {:?} in {}", mov, to_fen(pos));
+  Ok((parsed, new_key))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  const STARTING_FEN: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+  fn check(fen: &str, counts: &[u64]) {
+    for (depth, count) in counts.iter().enumerate() {
+      assert_eq!(perft(fen, depth as u64 + 1).unwrap(), *count, "perft {} of {}", depth + 1, fen);
+    }
+  }
+
+  #[test]
+  fn reference_positions() {
+    check(STARTING_FEN, &[20, 400, 8902, 197281]);
+    // kiwipete
+    check("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1", &[48, 2039, 97862]);
+    check("8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1", &[14, 191, 2812, 43238]);
+    check("r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1", &[6, 264, 9467]);
+    check("rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8", &[44, 1486, 62379]);
+    check("r4rk1/1pp1qppp/p1np1n2/2b1p1B1/2B1P1b1/P1NP1N2/1PP1QPPP/R4RK1 w - - 0 10", &[46, 2079, 89890]);
+  }
+
+  #[test]
+  fn en_passant_edge_cases() {
+    // en passant capture that would leave the king in check
+    check("3k4/3p4/8/K1P4r/8/8/8/8 b - - 0 1", &[18, 92, 1670, 10138, 185429]);
+    // en passant capture that gives check
+    check("8/8/4k3/8/2p5/8/B2P2K1/8 w - - 0 1", &[13, 102, 1266, 10276, 135655]);
+    // en passant square given by the fen
+    check("8/8/1k6/2b5/2pP4/8/5K2/8 b - d3 0 1", &[15, 126, 1928, 13931, 206379]);
+  }
+
+  #[test]
+  fn castling_edge_cases() {
+    check("5k2/8/8/8/8/8/8/4K2R w K - 0 1", &[15, 66, 1198, 6399, 120330]);
+    check("3k4/8/8/8/8/8/8/R3K3 w Q - 0 1", &[16, 71, 1286, 7418, 141077]);
+    check("r3k2r/1b4bq/8/8/8/8/7B/R3K2R w KQkq - 0 1", &[26, 1141, 27826]);
+    check("r3k2r/8/3Q4/8/8/5q2/8/R3K2R b KQkq - 0 1", &[44, 1494, 50509]);
+  }
+
+  #[test]
+  fn promotion_edge_cases() {
+    // promotion out of check, to give check and underpromotion
+    check("2K2r2/4P3/8/8/8/8/8/3k4 w - - 0 1", &[11, 133, 1442, 19174]);
+    check("4k3/1P6/8/8/8/8/K7/8 w - - 0 1", &[9, 40, 472, 2661, 38983, 217342]);
+    check("8/P1k5/K7/8/8/8/8/8 w - - 0 1", &[6, 27, 273, 1329, 18135, 92683]);
+    // stalemate and checkmate after promotion
+    check("K1k5/8/P7/8/8/8/8/8 w - - 0 1", &[2, 6, 13, 63, 382, 2217]);
+    check("8/k1P5/8/1K6/8/8/8/8 w - - 0 1", &[10, 25, 268, 926, 10857, 43261]);
+  }
+
+  #[test]
+  fn divide_by_move() {
+    let counts = divide(STARTING_FEN, 3).unwrap();
+    assert_eq!(counts.len(), 20);
+    assert_eq!(counts["e2e4"], 600);
+    assert_eq!(counts["g1f3"], 440);
+    assert_eq!(counts.values().sum::<u64>(), 8902);
+    assert!(divide(STARTING_FEN, 0).unwrap().is_empty());
+    assert_eq!(perft(STARTING_FEN, 0).unwrap(), 1);
+  }
+}
@@ -0,0 +1,312 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::{Serialize, Deserialize};
+use shakmaty::*;
+use instant::Instant;
+
+use crate::error::ChessError;
+use crate::evaluate::{eval_params, evaluator_from_name, Classic, Evaluator};
+use crate::ordering::Heuristics;
+
+// score of giving checkmate at the root, a mate found at ply p scores MATE - p,
+// so faster mates score more and slower losses score less
+pub const MATE: i64 = 100_000;
+// any score past this is a mate, no search gets 1000 plies deep
+pub const MATE_BOUND: i64 = MATE - 1_000;
+
+// score of a position without legal moves, ply is the distance from the root
+pub fn terminal_score(pos: &Chess, ply: u64) -> i64 {
+  if pos.is_check() {
+    -(MATE - ply as i64)
+  } else {
+    0
+  }
+}
+
+// score of a leaf of the search, from the point of view of the side to move
+pub fn leaf_score(pos: &Chess, ply: u64, evaluator: &mut dyn Evaluator) -> i64 {
+  match pos.outcome() {
+    // in standard chess only the side to move can be checkmated
+    Some(Outcome::Decisive { .. }) => -(MATE - ply as i64),
+    Some(Outcome::Draw) => 0,
+    None => evaluator.evaluate(pos),
+  }
+}
+
+// score of a search, always from the point of view of the side to move
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Score {
+  // centipawns
+  Cp(i64),
+  // moves until mate, negative if the side to move is the one getting mated
+  Mate(i64),
+}
+impl Score {
+  pub fn new(score: i64) -> Score {
+    if score >= MATE_BOUND {
+      Score::Mate((MATE - score + 1) / 2)
+    } else if score <= -MATE_BOUND {
+      Score::Mate(-(MATE + score + 1) / 2)
+    } else {
+      Score::Cp(score)
+    }
+  }
+}
+// same format used by UCI: "cp 25" or "mate -3"
+impl std::fmt::Display for Score {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Score::Cp(cp) => write!(f, "cp {}", cp),
+      Score::Mate(moves) => write!(f, "mate {}", moves),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchResult {
+  // best move in SAN and UCI
+  pub san: String,
+  pub uci: String,
+  pub score: Score,
+  // principal variation starting with the best move, in UCI and SAN
+  pub pv: Vec<String>,
+  pub pv_san: Vec<String>,
+  // depth of the last completed search
+  pub depth: u64,
+  pub nodes: u64,
+  // elapsed time in milliseconds
+  pub time: u64,
+}
+impl SearchResult {
+  // pv has to start with the best move and be playable from pos
+  pub fn new(pos: &Chess, pv: &[Move], score: i64, depth: u64, ctx: &SearchContext) -> SearchResult {
+    let mut pv_uci = vec![];
+    let mut pv_san = vec![];
+    let mut pos = pos.clone();
+    for mov in pv {
+      if !pos.is_legal(mov) {
+        break;
+      }
+      pv_uci.push(mov.to_uci(CastlingMode::Standard).to_string());
+      pv_san.push(san::San::from_move(&pos, mov).to_string());
+      pos.play_unchecked(mov);
+    }
+    SearchResult {
+      san: pv_san.first().cloned().unwrap_or_default(),
+      uci: pv_uci.first().cloned().unwrap_or_default(),
+      score: Score::new(score),
+      pv: pv_uci,
+      pv_san,
+      depth,
+      nodes: ctx.nodes,
+      time: ctx.elapsed(),
+    }
+  }
+}
+
+// moves the remaining time is divided into when the number of moves to the time control isn't known
+pub const DEFAULT_MOVES_TO_GO: u64 = 30;
+// milliseconds kept aside for the communication with the GUI
+pub const MOVE_OVERHEAD: u64 = 50;
+
+// milliseconds to spend on a move with time left on the clock, inc added after each move
+// and moves_to_go moves to play before the next time control
+pub fn time_for_move(time: u64, inc: u64, moves_to_go: Option<u64>) -> u64 {
+  let budget = time / moves_to_go.unwrap_or(DEFAULT_MOVES_TO_GO).max(1) + inc * 3 / 4;
+  // never more than half of what's left on the clock
+  budget.min(time.saturating_sub(MOVE_OVERHEAD) / 2).max(1)
+}
+
+pub type InfoCallback = Box<dyn FnMut(&SearchResult)>;
+
+// state shared by all the nodes of a single search
+pub struct SearchContext {
+  pub start: Instant,
+  pub nodes: u64,
+  // zobrist keys of the positions before the current node, oldest first:
+  // the game history, then the root and the current line
+  pub keys: Vec<u64>,
+  // index in keys of the root position
+  root: usize,
+  // set from another thread to end the search as if time was over
+  pub stop: Arc<AtomicBool>,
+  // iterative deepening doesn't go past this depth
+  pub max_depth: u64,
+  // called with the result of every completed iteration
+  pub info: Option<InfoCallback>,
+  // scores the leaves of the search
+  pub evaluator: Box<dyn Evaluator>,
+  // killer moves and history, to try first the quiet moves that caused cutoffs
+  pub heuristics: Heuristics,
+  // lines ended by is_draw so far, a node whose subtree ended one has a score that depends on
+  // the path to it, so it's not stored in the table
+  pub draws: u64,
+}
+impl SearchContext {
+  pub fn new() -> SearchContext {
+    SearchContext::with_history(&[])
+  }
+
+  // history holds the keys of the positions played before the root, oldest first
+  pub fn with_history(history: &[u64]) -> SearchContext {
+    SearchContext {
+      start: Instant::now(),
+      nodes: 0,
+      keys: history.to_vec(),
+      root: history.len(),
+      stop: Arc::new(AtomicBool::new(false)),
+      max_depth: u64::MAX,
+      info: None,
+      evaluator: Box::new(Classic::new(eval_params())),
+      heuristics: Heuristics::new(),
+      draws: 0,
+    }
+  }
+
+  pub fn with_evaluator(evaluator: Box<dyn Evaluator>) -> SearchContext {
+    SearchContext { evaluator, ..SearchContext::new() }
+  }
+
+  // replaces the evaluator with the one called name
+  pub fn set_evaluator(&mut self, name: &str) -> Result<(), ChessError> {
+    self.evaluator = evaluator_from_name(name)?;
+    Ok(())
+  }
+
+  // the search has to end, because max_time milliseconds have passed or it was stopped
+  pub fn out_of_time(&self, max_time: u64) -> bool {
+    self.elapsed() > max_time || self.stop.load(Ordering::Relaxed)
+  }
+
+  // passes the result of a completed iteration to info, if there's one
+  pub fn report(&mut self, pos: &Chess, pv: &[Move], score: i64, depth: u64) {
+    if let Some(mut info) = self.info.take() {
+      info(&SearchResult::new(pos, pv, score, depth, self));
+      self.info = Some(info);
+    }
+  }
+
+  // a position repeated inside the search, the root included, is scored as a draw straight away,
+  // since the same moves can be played again, one that only repeats the game history needs to be
+  // a threefold, positions after 50 moves without captures or pawn moves are a draw too, unless it's checkmate
+  pub fn is_draw(&self, pos: &Chess, key: u64) -> bool {
+    if pos.halfmoves() >= 100 && !(pos.is_check() && pos.legal_moves().is_empty()) {
+      return true;
+    }
+    // only positions since the last capture or pawn move can repeat, with the same side to move
+    let reversible = (pos.halfmoves() as usize).min(self.keys.len());
+    let mut repetitions = 0;
+    for distance in (4..=reversible).step_by(2) {
+      let index = self.keys.len() - distance;
+      if self.keys[index] == key {
+        if index >= self.root {
+          return true;
+        }
+        repetitions += 1;
+        if repetitions == 2 {
+          return true;
+        }
+      }
+    }
+    false
+  }
+
+  // milliseconds since the search started
+  pub fn elapsed(&self) -> u64 {
+    self.start.elapsed().as_millis() as u64
+  }
+}
+impl Default for SearchContext {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn result_from_pv() {
+    let fen: fen::Fen = "5k2/8/1Q6/2R5/8/8/8/4K3 w - - 3 3".parse().unwrap();
+    let pos: Chess = fen.into_position(CastlingMode::Standard).unwrap();
+    let pv = vec![
+      Move::Normal { role: Role::Rook, from: Square::C5, capture: None, to: Square::C7, promotion: None },
+      Move::Normal { role: Role::King, from: Square::F8, capture: None, to: Square::G8, promotion: None },
+      Move::Normal { role: Role::Queen, from: Square::B6, capture: None, to: Square::B8, promotion: None },
+    ];
+    let result = SearchResult::new(&pos, &pv, MATE - 3, 3, &SearchContext::new());
+    assert_eq!(result.san, "Rc7");
+    assert_eq!(result.uci, "c5c7");
+    assert_eq!(result.pv, vec!["c5c7", "f8g8", "b6b8"]);
+    assert_eq!(result.pv_san, vec!["Rc7", "Kg8", "Qb8"]);
+    assert_eq!(result.score, Score::Mate(2));
+  }
+
+  #[test]
+  fn score_centipawns() {
+    assert_eq!(Score::new(150), Score::Cp(150));
+    assert_eq!(Score::new(-(MATE - 2)), Score::Mate(-1));
+    assert_eq!(Score::new(MATE - 5), Score::Mate(3));
+    assert_eq!(Score::new(MATE - 5).to_string(), "mate 3");
+    assert_eq!(Score::new(-40).to_string(), "cp -40");
+  }
+
+  #[test]
+  fn leaf_score_checkmate() {
+    // white wins, black to move is mated
+    let pos = crate::utils::parse_fen("1Q2k3/2R5/8/8/8/8/8/4K3 b - - 1 1").unwrap();
+    assert_eq!(leaf_score(&pos, 3, &mut Classic::default()), -(MATE - 3));
+    assert_eq!(terminal_score(&pos, 3), -(MATE - 3));
+    // black wins, white to move is mated
+    let pos = crate::utils::parse_fen("4k3/8/8/8/8/8/1q6/2r1K3 w - - 2 2").unwrap();
+    assert_eq!(leaf_score(&pos, 1, &mut Classic::default()), -(MATE - 1));
+    // stalemate
+    let pos = crate::utils::parse_fen("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1").unwrap();
+    assert_eq!(leaf_score(&pos, 3, &mut Classic::default()), 0);
+    assert_eq!(terminal_score(&pos, 3), 0);
+  }
+
+  #[test]
+  fn draws_by_rule() {
+    use shakmaty::zobrist::ZobristHash;
+    let ctx = SearchContext::new();
+    // fifty-move rule, unless the last move gave checkmate
+    let pos = crate::utils::parse_fen("q3k3/8/8/8/8/8/8/4K3 w - - 100 80").unwrap();
+    assert!(ctx.is_draw(&pos, pos.zobrist_hash()));
+    let pos = crate::utils::parse_fen("1Q2k3/2R5/8/8/8/8/8/4K3 b - - 100 80").unwrap();
+    assert!(!ctx.is_draw(&pos, pos.zobrist_hash()));
+
+    // knights going back and forth, keys has one entry for each position before the current one
+    let mut pos = crate::utils::parse_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1").unwrap();
+    let mut keys = vec![];
+    for uci in ["g1f3", "g8f6", "f3g1", "f6g8", "g1f3", "g8f6", "f3g1", "f6g8"] {
+      keys.push(pos.zobrist_hash::<u64>());
+      let mov = uci.parse::<uci::Uci>().unwrap().to_move(&pos).unwrap();
+      pos.play_unchecked(&mov);
+    }
+    let key = pos.zobrist_hash();
+    // repeated inside the search
+    let mut ctx = SearchContext::with_history(&keys[..2]);
+    ctx.keys.extend(&keys[2..]);
+    assert!(ctx.is_draw(&pos, key));
+    // back to the root
+    let mut ctx = SearchContext::new();
+    ctx.keys.extend(&keys[4..]);
+    assert!(ctx.is_draw(&pos, key));
+    // repeated once in the game history only is not enough, twice is a threefold
+    assert!(!SearchContext::with_history(&keys[4..]).is_draw(&pos, key));
+    assert!(SearchContext::with_history(&keys).is_draw(&pos, key));
+  }
+
+  #[test]
+  fn time_management() {
+    assert_eq!(time_for_move(60000, 1000, None), 60000 / DEFAULT_MOVES_TO_GO + 750);
+    assert_eq!(time_for_move(60000, 0, Some(10)), 6000);
+    // a short clock is never used up in one move
+    assert_eq!(time_for_move(1000, 0, Some(1)), 475);
+    assert_eq!(time_for_move(0, 0, None), 1);
+  }
+}
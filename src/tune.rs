@@ -0,0 +1,258 @@
+// texel tuning of the evaluation weights: the weights are moved one step at a time, keeping the
+// changes that make the evaluation of positions from real games predict their results better
+// https://www.chessprogramming.org/Texel%27s_Tuning_Method
+
+use serde_json::Value;
+use shakmaty::*;
+
+use crate::algorithms::negamax_a_b_quiescent::quiescent_search;
+use crate::evaluate::{evaluate, Classic, EvalParams};
+use crate::search::{SearchContext, MATE};
+use crate::utils::parse_fen;
+
+// a position and the result of the game it was played in, 1 if white won, 0.5 for a draw and 0 if black won
+#[derive(Debug, Clone)]
+pub struct Sample {
+  pub pos: Chess,
+  pub result: f64,
+}
+
+fn parse_result(result: &str) -> Option<f64> {
+  match result.trim_matches('"') {
+    "1-0" => Some(1.0),
+    "1/2-1/2" => Some(0.5),
+    "0-1" => Some(0.0),
+    _ => None,
+  }
+}
+
+// an EPD line, the four fields of the FEN without the move counters followed by the operations,
+// the result is in c9 (or result): 2rq1rk1/pp3ppp/8/8/8/8/PP3PPP/2RQ1RK1 w - - c9 "1/2-1/2";
+pub fn parse_epd(line: &str) -> Result<Sample, String> {
+  let fields: Vec<&str> = line.split_whitespace().collect();
+  if fields.len() < 4 {
+    return Err(format!("not an EPD position: {}", line));
+  }
+  let pos = parse_fen(&format!("{} 0 1", fields[..4].join(" "))).map_err(|err| err.to_string())?;
+  let operations = fields[4..].join(" ");
+  for operation in operations.split(';') {
+    let mut operation = operation.trim().splitn(2, ' ');
+    if let (Some("c9"), Some(result)) | (Some("result"), Some(result)) = (operation.next(), operation.next()) {
+      return match parse_result(result.trim()) {
+        Some(result) => Ok(Sample { pos, result }),
+        None => Err(format!("unknown result {}", result)),
+      };
+    }
+  }
+  Err(format!("no result in {}", line))
+}
+
+// every position of an EPD file, empty lines and the ones starting with # are skipped
+pub fn load_epd(text: &str) -> Result<Vec<Sample>, String> {
+  let mut samples = vec![];
+  for (index, line) in text.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    samples.push(parse_epd(line).map_err(|err| format!("line {}: {}", index + 1, err))?);
+  }
+  Ok(samples)
+}
+
+// replaces each position with the one at the end of its quiescence search, so the evaluation
+// is tuned on positions without captures left to play, as the search uses it
+pub fn quiet_positions(samples: Vec<Sample>, params: &EvalParams) -> Vec<Sample> {
+  let mut ctx = SearchContext::with_evaluator(Box::new(Classic::new(params.clone())));
+  samples
+    .into_iter()
+    .map(|sample| {
+      let (_, pv) = quiescent_search(&sample.pos, 0, -MATE, MATE, &mut ctx);
+      let mut pos = sample.pos;
+      for mov in pv.iter().rev() {
+        pos.play_unchecked(mov);
+      }
+      Sample { pos, result: sample.result }
+    })
+    .collect()
+}
+
+// calls f on every number in value
+fn visit_numbers(value: &mut Value, f: &mut dyn FnMut(&mut Value)) {
+  match value {
+    Value::Number(_) => f(value),
+    Value::Array(values) => values.iter_mut().for_each(|value| visit_numbers(value, f)),
+    Value::Object(map) => map.values_mut().for_each(|value| visit_numbers(value, f)),
+    _ => {},
+  }
+}
+
+// every weight of params, in the order with_weights expects them
+pub fn weights(params: &EvalParams) -> Vec<i64> {
+  let mut value = serde_json::to_value(params).unwrap();
+  let mut weights = vec![];
+  visit_numbers(&mut value, &mut |number| weights.push(number.as_i64().unwrap()));
+  weights
+}
+
+pub fn with_weights(params: &EvalParams, weights: &[i64]) -> EvalParams {
+  let mut value = serde_json::to_value(params).unwrap();
+  let mut weights = weights.iter();
+  visit_numbers(&mut value, &mut |number| *number = Value::from(*weights.next().unwrap()));
+  serde_json::from_value(value).unwrap()
+}
+
+// evaluation of each position from white's point of view, split between the cores
+fn evaluations(samples: &[Sample], params: &EvalParams) -> Vec<i64> {
+  let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+  let chunk_size = samples.len().div_ceil(threads).max(1);
+  std::thread::scope(|scope| {
+    let handles: Vec<_> = samples
+      .chunks(chunk_size)
+      .map(|chunk| {
+        scope.spawn(move || {
+          chunk.iter().map(|sample| evaluate(&sample.pos, params) * if sample.pos.turn() == Color::White { 1 } else { -1 }).collect::<Vec<_>>()
+        })
+      })
+      .collect();
+    handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+  })
+}
+
+// expected result for white of a position evaluated score, k scales centipawns to winning chances
+fn sigmoid(score: i64, k: f64) -> f64 {
+  1.0 / (1.0 + 10f64.powf(-k * score as f64 / 400.0))
+}
+
+fn mean_squared_error(samples: &[Sample], evaluations: &[i64], k: f64) -> f64 {
+  let total: f64 = samples.iter().zip(evaluations).map(|(sample, &score)| (sample.result - sigmoid(score, k)).powi(2)).sum();
+  total / samples.len().max(1) as f64
+}
+
+pub fn error(samples: &[Sample], params: &EvalParams, k: f64) -> f64 {
+  mean_squared_error(samples, &evaluations(samples, params), k)
+}
+
+// the k that fits the current weights best, so that tuning changes the weights and not their scale
+pub fn best_k(samples: &[Sample], params: &EvalParams) -> f64 {
+  let evaluations = evaluations(samples, params);
+  let mut best = 1.0;
+  let mut step = 0.1;
+  for _ in 0..3 {
+    let center = best;
+    for i in -10..=10 {
+      let k = center + i as f64 * step;
+      if k > 0.0 && mean_squared_error(samples, &evaluations, k) < mean_squared_error(samples, &evaluations, best) {
+        best = k;
+      }
+    }
+    step /= 10.0;
+  }
+  best
+}
+
+// local search: each weight is moved up or down by one while that lowers the error, for at most
+// passes passes over all the weights, after each one report gets the pass, the error and the weights
+pub fn tune(samples: &[Sample], params: &EvalParams, k: f64, passes: usize, mut report: impl FnMut(usize, f64, &EvalParams)) -> EvalParams {
+  let mut weights = weights(params);
+  let mut best = error(samples, params, k);
+  for pass in 1..=passes {
+    let mut improved = false;
+    for i in 0..weights.len() {
+      for step in [1, -1].iter() {
+        weights[i] += step;
+        let error = error(samples, &with_weights(params, &weights), k);
+        if error < best {
+          best = error;
+          improved = true;
+          break;
+        }
+        weights[i] -= step;
+      }
+    }
+    report(pass, best, &with_weights(params, &weights));
+    if !improved {
+      break;
+    }
+  }
+  with_weights(params, &weights)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn epd_results() {
+    let sample = parse_epd("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - c9 \"1-0\";").unwrap();
+    assert_eq!(sample.result, 1.0);
+    assert_eq!(sample.pos.turn(), Color::Black);
+    assert_eq!(parse_epd("4k3/8/8/8/8/8/8/4K3 w - - id \"draw\"; c9 \"1/2-1/2\";").unwrap().result, 0.5);
+    assert_eq!(parse_epd("4k3/8/8/8/8/8/8/4K3 w - - result 0-1;").unwrap().result, 0.0);
+    assert!(parse_epd("4k3/8/8/8/8/8/8/4K3 w - -").is_err());
+    assert!(parse_epd("4k3/8/8/8/8/8/8/4K3 w - - c9 \"2-0\";").is_err());
+
+    let text = "# quiet positions\n4k3/8/8/8/8/8/8/4K3 w - - c9 \"1/2-1/2\";\n\n4k3/8/8/8/8/8/8/4K3 x - - c9 \"1-0\";\n";
+    assert_eq!(load_epd(text).unwrap_err(), "line 4: invalid fen: 4k3/8/8/8/8/8/8/4K3 x - - 0 1");
+    let valid: Vec<&str> = text.lines().take(3).collect();
+    assert_eq!(load_epd(&valid.join("\n")).unwrap().len(), 1);
+  }
+
+  #[test]
+  fn weights_round_trip() {
+    let params = EvalParams::default();
+    let weights = weights(&params);
+    // the piece-square tables alone have 768
+    assert!(weights.len() > 800);
+    assert_eq!(with_weights(&params, &weights), params);
+    // each weight is one of the numbers of the params
+    let mut cheaper_pawn = params.clone();
+    cheaper_pawn.material.pawn = 90;
+    let changed: Vec<usize> = (0..weights.len()).filter(|&i| super::weights(&cheaper_pawn)[i] != weights[i]).collect();
+    assert_eq!(changed.len(), 1);
+    assert_eq!(weights[changed[0]], 100);
+  }
+
+  #[test]
+  fn scaling_constant() {
+    // results that follow the evaluation with k = 1.5
+    let params = EvalParams::default();
+    let mut samples = load_epd("4k3/pp6/8/8/8/8/PPP5/4K3 w - - c9 \"1-0\";\n4k3/8/8/8/8/8/8/4K3 w - - c9 \"0-1\";").unwrap();
+    let evaluations = evaluations(&samples, &params);
+    for (sample, &score) in samples.iter_mut().zip(&evaluations) {
+      sample.result = sigmoid(score, 1.5);
+    }
+    assert!((best_k(&samples, &params) - 1.5).abs() < 0.01);
+  }
+
+  #[test]
+  fn quiet_leaves() {
+    // the knight on d5 is taken before the position is evaluated
+    let samples = load_epd("4k3/8/8/3n4/4P3/8/8/4K3 w - - c9 \"1-0\";").unwrap();
+    let quiet = quiet_positions(samples, &EvalParams::default());
+    assert_eq!(quiet[0].pos.board().knights(), Bitboard::EMPTY);
+    assert_eq!(quiet[0].pos.turn(), Color::Black);
+  }
+
+  #[test]
+  fn tuning_lowers_the_error() {
+    // white is a pawn up in each position, but it lost every game
+    let samples = load_epd(
+      "4k3/pp6/8/8/8/8/PPP5/4K3 w - - c9 \"0-1\";\n\
+       4k3/6pp/8/8/8/8/5PPP/4K3 b - - c9 \"0-1\";\n\
+       4k3/p7/8/8/8/8/PP6/4K3 w - - c9 \"0-1\";",
+    )
+    .unwrap();
+    let params = EvalParams::default();
+    let k = 1.0;
+    let before = error(&samples, &params, k);
+    let mut reports = 0;
+    let tuned = tune(&samples, &params, k, 1, |pass, error, _| {
+      assert_eq!(pass, 1);
+      assert!(error < before);
+      reports += 1;
+    });
+    assert_eq!(reports, 1);
+    assert!(error(&samples, &tuned, k) < before);
+  }
+}
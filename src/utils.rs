@@ -9,26 +9,88 @@
     console_error_panic_hook::set_once();
 } */
 
-use serde::{Serialize, Deserialize};
-use shakmaty::{Chess, zobrist::ZobristHash, fen::Fen, CastlingMode};
+use serde::Serialize;
+use shakmaty::{Chess, Position, Move, Piece, Role, Square, Color, CastlingSide, fen::Fen, CastlingMode, EnPassantMode};
+use shakmaty::zobrist::{ZobristHash, ZobristValue};
+use wasm_bindgen::JsValue;
 
 use crate::HashTable;
+use crate::error::ChessError;
+use crate::search::SearchResult;
 
-// zobrish hasher for fen string
-pub fn zobrish(fen_str: &str) -> u64 {
-    let fen: Fen = fen_str.parse().unwrap();
-    let chess: Chess = fen.into_position(CastlingMode::Standard).unwrap();
-    chess.zobrist_hash::<u64>()
+// parses a fen string into a position, checking that the position is legal
+pub fn parse_fen(fen_str: &str) -> Result<Chess, ChessError> {
+  let fen: Fen = fen_str.parse().map_err(|_| ChessError::InvalidFen(fen_str.to_string()))?;
+  fen.into_position(CastlingMode::Standard).map_err(|_| ChessError::IllegalPosition(fen_str.to_string()))
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+// fen of a position, with the en passant square only when the capture is legal,
+// so that the same position always gets the same fen
+pub fn to_fen(pos: &Chess) -> String {
+  Fen::from_position(pos.clone(), EnPassantMode::Legal).to_string()
+}
+
+// zobrist key of new_pos, that is pos after playing mov, updated from the key of pos
+// instead of hashing the whole board again (shakmaty doesn't do it for Chess)
+pub fn zobrist_after(pos: &Chess, key: u64, mov: &Move, new_pos: &Chess) -> u64 {
+  let turn = pos.turn();
+  let piece = |role: Role, color: Color, square: Square| u64::zobrist_for_piece(square, Piece { color, role });
+  let mut key = key ^ u64::zobrist_for_white_turn();
+
+  match *mov {
+    Move::Normal { role, from, capture, to, promotion } => {
+      key ^= piece(role, turn, from);
+      if let Some(captured) = capture {
+        key ^= piece(captured, !turn, to);
+      }
+      key ^= piece(promotion.unwrap_or(role), turn, to);
+    },
+    Move::EnPassant { from, to } => {
+      key ^= piece(Role::Pawn, turn, from);
+      key ^= piece(Role::Pawn, turn, to);
+      key ^= piece(Role::Pawn, !turn, Square::from_coords(to.file(), from.rank()));
+    },
+    Move::Castle { king, rook } => {
+      let side = CastlingSide::from_queen_side(rook < king);
+      key ^= piece(Role::King, turn, king);
+      key ^= piece(Role::Rook, turn, rook);
+      key ^= piece(Role::King, turn, side.king_to(turn));
+      key ^= piece(Role::Rook, turn, side.rook_to(turn));
+    },
+    // drops only exist in crazyhouse
+    Move::Put { .. } => return new_pos.zobrist_hash(),
+  }
+
+  for color in Color::ALL {
+    for side in CastlingSide::ALL {
+      if pos.castles().has(color, side) != new_pos.castles().has(color, side) {
+        key ^= u64::zobrist_for_castling_right(color, side);
+      }
+    }
+  }
+  if let Some(square) = pos.legal_ep_square() {
+    key ^= u64::zobrist_for_en_passant_file(square.file());
+  }
+  if let Some(square) = new_pos.legal_ep_square() {
+    key ^= u64::zobrist_for_en_passant_file(square.file());
+  }
+  key
+}
+
+// converts a rust value to a plain js object going through JSON
+pub fn to_js<T: Serialize>(value: &T) -> Result<JsValue, JsValue> {
+  let json = serde_json::to_string(value).map_err(|err| JsValue::from_str(&err.to_string()))?;
+  js_sys::JSON::parse(&json)
+}
+
+#[derive(Debug)]
 pub struct MoveAndTable {
-  pub mov: String,
+  pub result: SearchResult,
   pub table: HashTable,
 }
 impl MoveAndTable {
-  pub fn new(mov: String, table: HashTable) -> MoveAndTable {
-    MoveAndTable { mov, table }
+  pub fn new(result: SearchResult, table: HashTable) -> MoveAndTable {
+    MoveAndTable { result, table }
   }
 }
 
@@ -42,7 +104,44 @@ mod test {
     // let mut hasher = ChessHash::new_hasher();
     // chess_hash.hash(&mut hasher);
     // let hash = hasher.finish();
-    let hash = zobrish("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
-    assert_eq!(hash, 0x463b96181691fc9c);
+    let pos = parse_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1").unwrap();
+    assert_eq!(pos.zobrist_hash::<u64>(), 0x463b96181691fc9c);
+  }
+
+  // walks every line up to depth plies checking the incremental key against a full hash
+  fn check_incremental(pos: &Chess, key: u64, depth: u64) {
+    assert_eq!(key, pos.zobrist_hash::<u64>());
+    if depth == 0 {
+      return;
+    }
+    for mov in pos.legal_moves() {
+      let mut new_pos = pos.clone();
+      new_pos.play_unchecked(&mov);
+      check_incremental(&new_pos, zobrist_after(pos, key, &mov, &new_pos), depth - 1);
+    }
+  }
+
+  #[test]
+  fn incremental_zobrist() {
+    let fens = [
+      "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+      // castling on both sides, en passant and promotions
+      "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+      "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 3",
+      "n1n5/PPPk4/8/8/8/8/4Kppp/5N1N b - - 0 1",
+    ];
+    for fen in fens {
+      let pos = parse_fen(fen).unwrap();
+      check_incremental(&pos, pos.zobrist_hash(), 3);
+    }
+  }
+
+  #[test]
+  fn parse_fen_errors() {
+    assert_eq!(parse_fen("not a fen").err(), Some(ChessError::InvalidFen("not a fen".to_string())));
+    // both kings missing
+    let fen = "8/8/8/8/8/8/8/8 w - - 0 1";
+    assert_eq!(parse_fen(fen).err(), Some(ChessError::IllegalPosition(fen.to_string())));
+    assert!(parse_fen("4k3/8/8/8/8/8/8/4K3 w - - 0 1").is_ok());
   }
 }
\ No newline at end of file